@@ -1 +1 @@
-use uuid::Uuid;
+
@@ -1,8 +1,32 @@
-use pem::{encode, parse, Pem};
-use rcgen::{Certificate as RcgenCertificate, CertificateParams, Error, KeyPair};
+use rcgen::{
+    BasicConstraints, Certificate as RcgenCertificate, CertificateParams, DistinguishedName,
+    DnType, Error, IsCa, KeyPair, KeyUsagePurpose, SanType,
+};
+use rustls_pki_types::CertificateDer;
+use std::{net::IpAddr, time::SystemTime};
+use time::OffsetDateTime;
+use x509_parser::{certificate::X509Certificate, prelude::FromDer};
+use zeroize::Zeroize;
 
+#[derive(Debug, thiserror::Error)]
+pub enum CertificateError {
+    #[error("A certificate needs at least one subject alternative name")]
+    NoSubjectAltNames,
+    #[error("not_before must be earlier than not_after")]
+    InvalidValidity,
+    #[error("Invalid DNS name: {0}")]
+    InvalidDnsName(String),
+    #[error("Private key does not match the certificate's public key")]
+    KeyMismatch,
+    #[error("Certificate error: {0}")]
+    Rcgen(#[from] Error),
+}
+
+// `der` holds the encoding a certificate was loaded from, since re-signing the parsed
+// params would not reproduce it.
 pub struct Certificate {
     cert: RcgenCertificate,
+    der: Option<CertificateDer<'static>>,
 }
 
 pub struct CertifiedKey {
@@ -10,10 +34,102 @@ pub struct CertifiedKey {
     key_pair: KeyPair,
 }
 
+// Collects SANs, a validity window and the CA flag into `CertificateParams`. Unset bounds
+// keep rcgen's defaults.
+#[derive(Debug, Default, Clone)]
+pub struct CertificateBuilder {
+    dns_names: Vec<String>,
+    ips: Vec<IpAddr>,
+    not_before: Option<SystemTime>,
+    not_after: Option<SystemTime>,
+    is_ca: bool,
+}
+
+impl CertificateBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_dns(mut self, name: &str) -> Self {
+        self.dns_names.push(name.to_string());
+        self
+    }
+
+    pub fn add_ip(mut self, ip: IpAddr) -> Self {
+        self.ips.push(ip);
+        self
+    }
+
+    pub fn not_before(mut self, time: SystemTime) -> Self {
+        self.not_before = Some(time);
+        self
+    }
+
+    pub fn not_after(mut self, time: SystemTime) -> Self {
+        self.not_after = Some(time);
+        self
+    }
+
+    // A CA certificate may sign certificates and CRLs; a leaf gets the same key usages as
+    // `CertifiedKey::new`.
+    pub fn is_ca(mut self, is_ca: bool) -> Self {
+        self.is_ca = is_ca;
+        self
+    }
+
+    pub fn build(self) -> Result<CertificateParams, CertificateError> {
+        if self.dns_names.is_empty() && self.ips.is_empty() {
+            return Err(CertificateError::NoSubjectAltNames);
+        }
+        let mut params = CertificateParams::default();
+        for name in self.dns_names {
+            let dns = name
+                .as_str()
+                .try_into()
+                .map_err(|_| CertificateError::InvalidDnsName(name.clone()))?;
+            params.subject_alt_names.push(SanType::DnsName(dns));
+        }
+        params
+            .subject_alt_names
+            .extend(self.ips.into_iter().map(SanType::IpAddress));
+        if let Some(time) = self.not_before {
+            params.not_before = OffsetDateTime::from(time);
+        }
+        if let Some(time) = self.not_after {
+            params.not_after = OffsetDateTime::from(time);
+        }
+        if params.not_before >= params.not_after {
+            return Err(CertificateError::InvalidValidity);
+        }
+        if self.is_ca {
+            params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            params.key_usages = vec![KeyUsagePurpose::KeyCertSign, KeyUsagePurpose::CrlSign];
+        } else {
+            params.key_usages = vec![
+                KeyUsagePurpose::DigitalSignature,
+                KeyUsagePurpose::KeyEncipherment,
+            ];
+        }
+        Ok(params)
+    }
+}
+
 impl Certificate {
     pub fn new(params: CertificateParams, key_pair: &KeyPair) -> Result<Self, Error> {
         let cert = params.self_signed(key_pair)?;
-        Ok(Self { cert })
+        Ok(Self { cert, der: None })
+    }
+
+    // Issued by `issuer_cert`: the issuer name is taken from its subject and the signature
+    // is made with `issuer_key`, which must be the key `issuer_cert` was created for.
+    pub fn signed_by(
+        params: CertificateParams,
+        subject_key: &KeyPair,
+        issuer_cert: &Certificate,
+        issuer_key: &KeyPair,
+    ) -> Result<Self, Error> {
+        let cert = params.signed_by(subject_key, &issuer_cert.cert, issuer_key)?;
+        Ok(Self { cert, der: None })
     }
 
     pub fn from_pem(params_pem: &str, key_pem: &str) -> Result<Self, Error> {
@@ -22,13 +138,63 @@ impl Certificate {
         Self::new(params, &key_pair)
     }
 
+    pub fn from_der(cert_der: &[u8], key_der: &[u8]) -> Result<Self, CertificateError> {
+        let der = CertificateDer::from(cert_der.to_vec());
+        let params = CertificateParams::from_ca_cert_der(&der)?;
+        let key_pair = KeyPair::try_from(key_der)?;
+        let (_, parsed) =
+            X509Certificate::from_der(cert_der).map_err(|_| Error::CouldNotParseCertificate)?;
+        if parsed.public_key().raw != key_pair.public_key_der() {
+            return Err(CertificateError::KeyMismatch);
+        }
+        let cert = params.self_signed(&key_pair)?;
+        Ok(Self {
+            cert,
+            der: Some(der),
+        })
+    }
+
     pub fn to_pem(&self) -> String {
-        self.cert.pem()
+        match &self.der {
+            Some(der) => pem::encode_config(
+                &pem::Pem::new("CERTIFICATE", der.to_vec()),
+                pem::EncodeConfig::new().set_line_ending(pem::LineEnding::LF),
+            ),
+            None => self.cert.pem(),
+        }
     }
 
     pub fn to_der(&self) -> Vec<u8> {
-        self.cert.der().to_vec()
+        self.der.as_deref().unwrap_or(self.cert.der()).to_vec()
     }
+
+    // The subject distinguished name in RFC 4514 form, e.g. "CN=example.com".
+    pub fn subject(&self) -> String {
+        self.with_parsed(|cert| cert.subject().to_string())
+    }
+
+    pub fn not_after(&self) -> SystemTime {
+        self.with_parsed(|cert| cert.validity().not_after.to_datetime().into())
+    }
+
+    pub fn sha256_fingerprint(&self) -> [u8; 32] {
+        der_fingerprint(&self.to_der())
+    }
+
+    // The DER was either produced by rcgen or already parsed by `from_der`.
+    fn with_parsed<T>(&self, f: impl FnOnce(&X509Certificate<'_>) -> T) -> T {
+        let der = self.to_der();
+        let (_, cert) = X509Certificate::from_der(&der).expect("certificate DER is valid");
+        f(&cert)
+    }
+}
+
+// SHA-256 of a certificate's DER, the form certificate pins and
+// `openssl x509 -fingerprint -sha256` use. This is SHA-2, unlike the crate's `Sha256`,
+// which is SHA3-256.
+pub fn der_fingerprint(der: &[u8]) -> [u8; 32] {
+    let digest = ring::digest::digest(&ring::digest::SHA256, der);
+    digest.as_ref().try_into().unwrap()
 }
 
 impl CertifiedKey {
@@ -43,12 +209,51 @@ impl CertifiedKey {
         Ok(Self { cert, key_pair })
     }
 
+    pub fn new_ed25519(name: &str) -> Result<Self, Error> {
+        let mut params = CertificateParams::new(vec![name.to_string()])?;
+        params.key_usages = vec![rcgen::KeyUsagePurpose::DigitalSignature];
+        let key_pair = KeyPair::generate_for(&rcgen::PKCS_ED25519)?;
+        let cert = Certificate::new(params, &key_pair)?;
+        Ok(Self { cert, key_pair })
+    }
+
+    // A fresh key pair for a certificate to be issued by an external CA: the subject's
+    // common name and `sans` go into the request from `generate_csr`, and the self-signed
+    // certificate held meanwhile carries the same names.
+    pub fn for_request(subject: &str, sans: &[&str]) -> Result<Self, Error> {
+        let sans: Vec<String> = sans.iter().map(|san| san.to_string()).collect();
+        let mut params = CertificateParams::new(sans)?;
+        params.distinguished_name = DistinguishedName::new();
+        params.distinguished_name.push(DnType::CommonName, subject);
+        params.key_usages = vec![
+            rcgen::KeyUsagePurpose::DigitalSignature,
+            rcgen::KeyUsagePurpose::KeyEncipherment,
+        ];
+        let key_pair = KeyPair::generate()?;
+        let cert = Certificate::new(params, &key_pair)?;
+        Ok(Self { cert, key_pair })
+    }
+
+    // PEM-encoded PKCS#10 request for this key, with the certificate's subject and SANs.
+    // rcgen can't put key usages in a request, so those are left for the CA to set.
+    pub fn generate_csr(&self) -> Result<String, Error> {
+        let mut params = self.cert.cert.params().clone();
+        params.key_usages.clear();
+        params.serialize_request(&self.key_pair)?.pem()
+    }
+
     pub fn from_pem(cert_pem: &str, key_pem: &str) -> Result<Self, Error> {
         let cert = Certificate::from_pem(cert_pem, key_pem)?;
         let key_pair = KeyPair::from_pem(key_pem)?;
         Ok(Self { cert, key_pair })
     }
 
+    pub fn from_der(cert_der: &[u8], key_der: &[u8]) -> Result<Self, CertificateError> {
+        let cert = Certificate::from_der(cert_der, key_der)?;
+        let key_pair = KeyPair::try_from(key_der)?;
+        Ok(Self { cert, key_pair })
+    }
+
     pub fn certificate(&self) -> &Certificate {
         &self.cert
     }
@@ -58,6 +263,20 @@ impl CertifiedKey {
     }
 }
 
+// Scrubs the serialized private key held by rcgen; the parsed signing state inside ring is
+// not reachable from here.
+impl Zeroize for CertifiedKey {
+    fn zeroize(&mut self) {
+        self.key_pair.zeroize();
+    }
+}
+
+impl Drop for CertifiedKey {
+    fn drop(&mut self) {
+        self.zeroize();
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -68,4 +287,190 @@ mod tests {
         assert!(!cert_with_key.certificate().to_pem().is_empty());
         assert!(!cert_with_key.key_pair().serialize_pem().is_empty());
     }
+
+    #[test]
+    fn test_certificate_builder() {
+        use std::time::Duration;
+
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let end = start + Duration::from_secs(90 * 24 * 60 * 60);
+        let params = CertificateBuilder::new()
+            .add_dns("a.example.com")
+            .add_dns("b.example.com")
+            .add_ip("192.0.2.1".parse().unwrap())
+            .not_before(start)
+            .not_after(end)
+            .build()
+            .unwrap();
+        assert_eq!(params.subject_alt_names.len(), 3);
+        assert_eq!(
+            params.subject_alt_names[2],
+            SanType::IpAddress("192.0.2.1".parse().unwrap())
+        );
+        assert_eq!(params.not_before, OffsetDateTime::from(start));
+        assert_eq!(params.not_after, OffsetDateTime::from(end));
+        assert_eq!(params.is_ca, IsCa::NoCa);
+        let key_pair = KeyPair::generate().unwrap();
+        assert!(Certificate::new(params, &key_pair).is_ok());
+
+        let ca = CertificateBuilder::new()
+            .add_dns("ca.example.com")
+            .is_ca(true)
+            .build()
+            .unwrap();
+        assert_eq!(ca.is_ca, IsCa::Ca(BasicConstraints::Unconstrained));
+
+        assert!(matches!(
+            CertificateBuilder::new().build(),
+            Err(CertificateError::NoSubjectAltNames)
+        ));
+        assert!(matches!(
+            CertificateBuilder::new()
+                .add_dns("a.example.com")
+                .not_before(end)
+                .not_after(start)
+                .build(),
+            Err(CertificateError::InvalidValidity)
+        ));
+        assert!(matches!(
+            CertificateBuilder::new().add_dns("bücher.example").build(),
+            Err(CertificateError::InvalidDnsName(_))
+        ));
+    }
+
+    #[test]
+    fn test_certificate_metadata() {
+        use std::time::Duration;
+
+        let end = SystemTime::UNIX_EPOCH + Duration::from_secs(1_900_000_000);
+        let mut params = CertificateBuilder::new()
+            .add_dns("meta.example.com")
+            .not_after(end)
+            .build()
+            .unwrap();
+        params.distinguished_name = DistinguishedName::new();
+        params
+            .distinguished_name
+            .push(DnType::CommonName, "meta.example.com");
+        let cert = Certificate::new(params, &KeyPair::generate().unwrap()).unwrap();
+
+        assert_eq!(cert.subject(), "CN=meta.example.com");
+        assert_eq!(cert.not_after(), end);
+        let digest = ring::digest::digest(&ring::digest::SHA256, &cert.to_der());
+        assert_eq!(cert.sha256_fingerprint().as_slice(), digest.as_ref());
+
+        let other = CertifiedKey::new("meta.example.com").unwrap();
+        assert_ne!(
+            other.certificate().sha256_fingerprint(),
+            cert.sha256_fingerprint()
+        );
+    }
+
+    #[test]
+    fn test_signed_by_ca() {
+        use ring::signature::{UnparsedPublicKey, ECDSA_P256_SHA256_ASN1};
+
+        let ca_key = KeyPair::generate().unwrap();
+        let mut ca_params = CertificateParams::new(Vec::new()).unwrap();
+        ca_params.distinguished_name = DistinguishedName::new();
+        ca_params
+            .distinguished_name
+            .push(DnType::CommonName, "Internal CA");
+        ca_params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+        let ca = Certificate::new(ca_params, &ca_key).unwrap();
+
+        let leaf_key = KeyPair::generate().unwrap();
+        let leaf_params = CertificateParams::new(vec!["leaf.example.com".to_string()]).unwrap();
+        let leaf = Certificate::signed_by(leaf_params, &leaf_key, &ca, &ca_key).unwrap();
+
+        let leaf_der = leaf.to_der();
+        let ca_der = ca.to_der();
+        let (_, parsed_leaf) = X509Certificate::from_der(&leaf_der).unwrap();
+        let (_, parsed_ca) = X509Certificate::from_der(&ca_der).unwrap();
+        assert_eq!(parsed_leaf.issuer(), parsed_ca.subject());
+        assert_ne!(parsed_leaf.issuer(), parsed_leaf.subject());
+
+        let signature = parsed_leaf.signature_value.data.as_ref();
+        let tbs = parsed_leaf.tbs_certificate.as_ref();
+        let ca_public = UnparsedPublicKey::new(&ECDSA_P256_SHA256_ASN1, ca_key.public_key_raw());
+        assert!(ca_public.verify(tbs, signature).is_ok());
+        let leaf_public =
+            UnparsedPublicKey::new(&ECDSA_P256_SHA256_ASN1, leaf_key.public_key_raw());
+        assert!(leaf_public.verify(tbs, signature).is_err());
+    }
+
+    #[test]
+    fn test_from_der_preserves_encoding() {
+        let original = CertifiedKey::new("der.example.com").unwrap();
+        let cert_der = original.certificate().to_der();
+        let key_der = original.key_pair().serialized_der().to_vec();
+
+        let loaded = CertifiedKey::from_der(&cert_der, &key_der).unwrap();
+        assert_eq!(loaded.certificate().to_der(), cert_der);
+        assert_eq!(
+            loaded.certificate().to_pem(),
+            original.certificate().to_pem()
+        );
+        assert_eq!(
+            loaded.key_pair().serialized_der(),
+            original.key_pair().serialized_der()
+        );
+        assert_eq!(
+            loaded.certificate().subject(),
+            original.certificate().subject()
+        );
+        assert!(loaded
+            .certificate()
+            .subject()
+            .contains("CN=rcgen self signed cert"));
+
+        assert!(Certificate::from_der(&cert_der[1..], &key_der).is_err());
+        assert!(Certificate::from_der(&cert_der, &key_der[1..]).is_err());
+
+        let other_key = KeyPair::generate().unwrap().serialized_der().to_vec();
+        assert!(matches!(
+            CertifiedKey::from_der(&cert_der, &other_key),
+            Err(CertificateError::KeyMismatch)
+        ));
+    }
+
+    #[test]
+    fn test_generate_csr() {
+        let cert_with_key =
+            CertifiedKey::for_request("service", &["service.example.com", "10.0.0.1"]).unwrap();
+        let csr = cert_with_key.generate_csr().unwrap();
+        assert!(csr.starts_with("-----BEGIN CERTIFICATE REQUEST-----"));
+
+        let parsed = rcgen::CertificateSigningRequestParams::from_pem(&csr).unwrap();
+        assert_eq!(
+            parsed.params.distinguished_name.get(&DnType::CommonName),
+            Some(&rcgen::DnValue::Utf8String("service".to_string()))
+        );
+        assert_eq!(
+            parsed.params.subject_alt_names,
+            vec![
+                rcgen::SanType::DnsName("service.example.com".try_into().unwrap()),
+                rcgen::SanType::IpAddress("10.0.0.1".parse().unwrap()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_certified_key_zeroize() {
+        let mut cert_with_key = CertifiedKey::new("test.example.com").unwrap();
+        assert!(cert_with_key
+            .key_pair()
+            .serialized_der()
+            .iter()
+            .any(|&b| b != 0));
+
+        cert_with_key.zeroize();
+        assert!(cert_with_key
+            .key_pair()
+            .serialized_der()
+            .iter()
+            .all(|&b| b == 0));
+
+        drop(CertifiedKey::new("dropped.example.com").unwrap());
+    }
 }
@@ -1,7 +1,36 @@
+use super::keys::{self, ManageKey};
 use aes_gcm::{
-    aead::{Aead, AeadCore, KeyInit, OsRng},
-    Error, Key, Nonce,
+    aead::{consts::U12, Aead, AeadCore, KeyInit, OsRng, Payload},
+    Aes256Gcm, Error, Key, Nonce,
 };
+use chacha20poly1305::ChaCha20Poly1305;
+use ring::rand::{SecureRandom, SystemRandom};
+use std::sync::atomic::{AtomicU64, Ordering};
+use thiserror::Error;
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum EncryptError {
+    #[error("Invalid key length: expected {expected} bytes, got {actual}")]
+    InvalidKeyLength { expected: usize, actual: usize },
+    #[error("Encryption failed")]
+    EncryptionFailed,
+    // The tag did not verify: wrong key, wrong nonce, different AAD or tampered bytes.
+    #[error("Authentication failed")]
+    AuthenticationFailed,
+    #[error("Nonce sequence exhausted")]
+    NonceExhausted,
+    #[error("Failed to generate random nonce prefix")]
+    RandomGenerationFailed,
+    #[error("Chunk size must be between 1 and {max} bytes, got {actual}")]
+    InvalidChunkSize { max: usize, actual: usize },
+    #[error("Stream ended before its header")]
+    TruncatedStream,
+}
+
+const STREAM_PREFIX_SIZE: usize = 7;
+const STREAM_HEADER_SIZE: usize = STREAM_PREFIX_SIZE + 4;
+const MAX_STREAM_CHUNK_SIZE: usize = 1 << 24;
+const TAG_SIZE: usize = 16;
 
 pub struct AES<T: AeadCore + Aead + KeyInit> {
     cipher: T,
@@ -36,10 +65,356 @@ impl<T: AeadCore + Aead + KeyInit> AES<T> {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AeadAlgorithm {
+    #[default]
+    Aes256Gcm,
+    // Faster than AES-GCM in software, on targets without AES instructions.
+    ChaCha20Poly1305,
+}
+
+// Both algorithms take a 32-byte key and a 12-byte nonce and return ciphertext followed
+// by a 16-byte tag; the nonce is not included, and must never be reused with the same key.
+pub fn aead_encrypt(
+    algorithm: AeadAlgorithm,
+    key: &keys::Key,
+    nonce: &[u8; 12],
+    plaintext: &[u8],
+    aad: &[u8],
+) -> Result<Vec<u8>, EncryptError> {
+    match algorithm {
+        AeadAlgorithm::Aes256Gcm => seal::<Aes256Gcm>(key, nonce, plaintext, aad),
+        AeadAlgorithm::ChaCha20Poly1305 => seal::<ChaCha20Poly1305>(key, nonce, plaintext, aad),
+    }
+}
+
+pub fn aead_decrypt(
+    algorithm: AeadAlgorithm,
+    key: &keys::Key,
+    nonce: &[u8; 12],
+    ciphertext: &[u8],
+    aad: &[u8],
+) -> Result<Vec<u8>, EncryptError> {
+    match algorithm {
+        AeadAlgorithm::Aes256Gcm => open::<Aes256Gcm>(key, nonce, ciphertext, aad),
+        AeadAlgorithm::ChaCha20Poly1305 => open::<ChaCha20Poly1305>(key, nonce, ciphertext, aad),
+    }
+}
+
+pub fn aes256_gcm_encrypt(
+    key: &keys::Key,
+    nonce: &[u8; 12],
+    plaintext: &[u8],
+    aad: &[u8],
+) -> Result<Vec<u8>, EncryptError> {
+    aead_encrypt(AeadAlgorithm::Aes256Gcm, key, nonce, plaintext, aad)
+}
+
+pub fn aes256_gcm_decrypt(
+    key: &keys::Key,
+    nonce: &[u8; 12],
+    ciphertext: &[u8],
+    aad: &[u8],
+) -> Result<Vec<u8>, EncryptError> {
+    aead_decrypt(AeadAlgorithm::Aes256Gcm, key, nonce, ciphertext, aad)
+}
+
+pub fn chacha20poly1305_encrypt(
+    key: &keys::Key,
+    nonce: &[u8; 12],
+    plaintext: &[u8],
+    aad: &[u8],
+) -> Result<Vec<u8>, EncryptError> {
+    aead_encrypt(AeadAlgorithm::ChaCha20Poly1305, key, nonce, plaintext, aad)
+}
+
+pub fn chacha20poly1305_decrypt(
+    key: &keys::Key,
+    nonce: &[u8; 12],
+    ciphertext: &[u8],
+    aad: &[u8],
+) -> Result<Vec<u8>, EncryptError> {
+    aead_decrypt(AeadAlgorithm::ChaCha20Poly1305, key, nonce, ciphertext, aad)
+}
+
+// Hands out 96-bit nonces as a random 32-bit prefix followed by a big-endian 64-bit
+// counter, so nonces never repeat within one sequence. After 2^64 - 1 nonces the
+// sequence fails with `NonceExhausted` rather than wrapping; start a new sequence with a
+// new key. Use one sequence per key: two sequences only differ by their random prefix.
+pub struct NonceSequence {
+    algorithm: AeadAlgorithm,
+    prefix: [u8; 4],
+    counter: AtomicU64,
+}
+
+impl NonceSequence {
+    pub fn new(algorithm: AeadAlgorithm) -> Result<Self, EncryptError> {
+        let mut prefix = [0u8; 4];
+        SystemRandom::new()
+            .fill(&mut prefix)
+            .map_err(|_| EncryptError::RandomGenerationFailed)?;
+        Ok(Self {
+            algorithm,
+            prefix,
+            counter: AtomicU64::new(0),
+        })
+    }
+
+    pub fn next_nonce(&self) -> Result<[u8; 12], EncryptError> {
+        let count = self
+            .counter
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |count| {
+                count.checked_add(1)
+            })
+            .map_err(|_| EncryptError::NonceExhausted)?;
+        let mut nonce = [0u8; 12];
+        nonce[..4].copy_from_slice(&self.prefix);
+        nonce[4..].copy_from_slice(&count.to_be_bytes());
+        Ok(nonce)
+    }
+
+    pub fn encrypt_next(
+        &self,
+        key: &keys::Key,
+        plaintext: &[u8],
+        aad: &[u8],
+    ) -> Result<([u8; 12], Vec<u8>), EncryptError> {
+        let nonce = self.next_nonce()?;
+        let ciphertext = aead_encrypt(self.algorithm, key, &nonce, plaintext, aad)?;
+        Ok((nonce, ciphertext))
+    }
+}
+
+// Chunked AEAD in the STREAM construction. The stream opens with a header of a random
+// 7-byte nonce prefix and the big-endian u32 chunk size; each chunk's nonce is that prefix,
+// the big-endian u32 chunk index and a byte that is 1 only on the final chunk, and the
+// header is every chunk's AAD. Reordered, dropped or truncated chunks therefore fail to
+// authenticate. Streams are limited to 2^32 chunks.
+pub struct StreamEncryptor {
+    algorithm: AeadAlgorithm,
+    key: keys::Key,
+    header: [u8; STREAM_HEADER_SIZE],
+    header_written: bool,
+    chunk_size: usize,
+    counter: u32,
+    exhausted: bool,
+    pending: Vec<u8>,
+}
+
+impl StreamEncryptor {
+    pub fn new(
+        algorithm: AeadAlgorithm,
+        key: &keys::Key,
+        chunk_size: usize,
+    ) -> Result<Self, EncryptError> {
+        check_chunk_size(chunk_size)?;
+        cipher_for(algorithm, key)?;
+        let mut header = [0u8; STREAM_HEADER_SIZE];
+        SystemRandom::new()
+            .fill(&mut header[..STREAM_PREFIX_SIZE])
+            .map_err(|_| EncryptError::RandomGenerationFailed)?;
+        header[STREAM_PREFIX_SIZE..].copy_from_slice(&(chunk_size as u32).to_be_bytes());
+        Ok(Self {
+            algorithm,
+            key: key.clone(),
+            header,
+            header_written: false,
+            chunk_size,
+            counter: 0,
+            exhausted: false,
+            pending: Vec::new(),
+        })
+    }
+
+    // Returns the ciphertext ready so far, which may be empty; the first non-empty output
+    // starts with the header. One chunk is always held back for `finalize`.
+    pub fn write_chunk(&mut self, data: &[u8]) -> Result<Vec<u8>, EncryptError> {
+        self.pending.extend_from_slice(data);
+        let mut output = self.take_header();
+        while self.pending.len() > self.chunk_size {
+            let chunk: Vec<u8> = self.pending.drain(..self.chunk_size).collect();
+            output.extend(self.seal_chunk(&chunk, false)?);
+        }
+        Ok(output)
+    }
+
+    pub fn finalize(mut self) -> Result<Vec<u8>, EncryptError> {
+        let mut output = self.take_header();
+        let last = std::mem::take(&mut self.pending);
+        output.extend(self.seal_chunk(&last, true)?);
+        Ok(output)
+    }
+
+    fn take_header(&mut self) -> Vec<u8> {
+        if std::mem::replace(&mut self.header_written, true) {
+            Vec::new()
+        } else {
+            self.header.to_vec()
+        }
+    }
+
+    fn seal_chunk(&mut self, chunk: &[u8], last: bool) -> Result<Vec<u8>, EncryptError> {
+        if self.exhausted {
+            return Err(EncryptError::NonceExhausted);
+        }
+        let nonce = chunk_nonce(&self.header, self.counter, last);
+        let sealed = aead_encrypt(self.algorithm, &self.key, &nonce, chunk, &self.header)?;
+        match self.counter.checked_add(1) {
+            Some(counter) => self.counter = counter,
+            None => self.exhausted = true,
+        }
+        Ok(sealed)
+    }
+}
+
+// The inverse of `StreamEncryptor`; ciphertext may be fed in pieces of any size. Plaintext
+// returned before `finalize` has authenticated, but only `finalize` proves it is complete.
+pub struct StreamDecryptor {
+    algorithm: AeadAlgorithm,
+    key: keys::Key,
+    header: Option<[u8; STREAM_HEADER_SIZE]>,
+    chunk_size: usize,
+    counter: u32,
+    pending: Vec<u8>,
+}
+
+impl StreamDecryptor {
+    pub fn new(algorithm: AeadAlgorithm, key: &keys::Key) -> Result<Self, EncryptError> {
+        cipher_for(algorithm, key)?;
+        Ok(Self {
+            algorithm,
+            key: key.clone(),
+            header: None,
+            chunk_size: 0,
+            counter: 0,
+            pending: Vec::new(),
+        })
+    }
+
+    pub fn write_chunk(&mut self, data: &[u8]) -> Result<Vec<u8>, EncryptError> {
+        self.pending.extend_from_slice(data);
+        let mut output = Vec::new();
+        if !self.read_header()? {
+            return Ok(output);
+        }
+        let sealed_size = self.chunk_size + TAG_SIZE;
+        while self.pending.len() > sealed_size {
+            let chunk: Vec<u8> = self.pending.drain(..sealed_size).collect();
+            output.extend(self.open_chunk(&chunk, false)?);
+        }
+        Ok(output)
+    }
+
+    pub fn finalize(mut self) -> Result<Vec<u8>, EncryptError> {
+        if !self.read_header()? {
+            return Err(EncryptError::TruncatedStream);
+        }
+        let last = std::mem::take(&mut self.pending);
+        self.open_chunk(&last, true)
+    }
+
+    fn read_header(&mut self) -> Result<bool, EncryptError> {
+        if self.header.is_some() {
+            return Ok(true);
+        }
+        if self.pending.len() < STREAM_HEADER_SIZE {
+            return Ok(false);
+        }
+        let header: [u8; STREAM_HEADER_SIZE] =
+            self.pending[..STREAM_HEADER_SIZE].try_into().unwrap();
+        let chunk_size =
+            u32::from_be_bytes(header[STREAM_PREFIX_SIZE..].try_into().unwrap()) as usize;
+        check_chunk_size(chunk_size)?;
+        self.pending.drain(..STREAM_HEADER_SIZE);
+        self.header = Some(header);
+        self.chunk_size = chunk_size;
+        Ok(true)
+    }
+
+    fn open_chunk(&mut self, chunk: &[u8], last: bool) -> Result<Vec<u8>, EncryptError> {
+        let header = self.header.ok_or(EncryptError::TruncatedStream)?;
+        let nonce = chunk_nonce(&header, self.counter, last);
+        let plaintext = aead_decrypt(self.algorithm, &self.key, &nonce, chunk, &header)?;
+        // A stream can't hold more chunks than the encryptor could seal.
+        self.counter = self
+            .counter
+            .checked_add(1)
+            .ok_or(EncryptError::AuthenticationFailed)?;
+        Ok(plaintext)
+    }
+}
+
+fn check_chunk_size(chunk_size: usize) -> Result<(), EncryptError> {
+    if chunk_size == 0 || chunk_size > MAX_STREAM_CHUNK_SIZE {
+        return Err(EncryptError::InvalidChunkSize {
+            max: MAX_STREAM_CHUNK_SIZE,
+            actual: chunk_size,
+        });
+    }
+    Ok(())
+}
+
+fn chunk_nonce(header: &[u8; STREAM_HEADER_SIZE], counter: u32, last: bool) -> [u8; 12] {
+    let mut nonce = [0u8; 12];
+    nonce[..STREAM_PREFIX_SIZE].copy_from_slice(&header[..STREAM_PREFIX_SIZE]);
+    nonce[STREAM_PREFIX_SIZE..11].copy_from_slice(&counter.to_be_bytes());
+    nonce[11] = last as u8;
+    nonce
+}
+
+// Validates the key length up front so streams fail at construction, not mid-way.
+fn cipher_for(algorithm: AeadAlgorithm, key: &keys::Key) -> Result<(), EncryptError> {
+    match algorithm {
+        AeadAlgorithm::Aes256Gcm => cipher::<Aes256Gcm>(key).map(drop),
+        AeadAlgorithm::ChaCha20Poly1305 => cipher::<ChaCha20Poly1305>(key).map(drop),
+    }
+}
+
+fn seal<C: Aead + KeyInit + AeadCore<NonceSize = U12>>(
+    key: &keys::Key,
+    nonce: &[u8; 12],
+    plaintext: &[u8],
+    aad: &[u8],
+) -> Result<Vec<u8>, EncryptError> {
+    cipher::<C>(key)?
+        .encrypt(
+            Nonce::from_slice(nonce),
+            Payload {
+                msg: plaintext,
+                aad,
+            },
+        )
+        .map_err(|_| EncryptError::EncryptionFailed)
+}
+
+fn open<C: Aead + KeyInit + AeadCore<NonceSize = U12>>(
+    key: &keys::Key,
+    nonce: &[u8; 12],
+    ciphertext: &[u8],
+    aad: &[u8],
+) -> Result<Vec<u8>, EncryptError> {
+    cipher::<C>(key)?
+        .decrypt(
+            Nonce::from_slice(nonce),
+            Payload {
+                msg: ciphertext,
+                aad,
+            },
+        )
+        .map_err(|_| EncryptError::AuthenticationFailed)
+}
+
+fn cipher<C: KeyInit>(key: &keys::Key) -> Result<C, EncryptError> {
+    C::new_from_slice(key.as_bytes()).map_err(|_| EncryptError::InvalidKeyLength {
+        expected: C::key_size(),
+        actual: key.len(),
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use aes_gcm::{Aes128Gcm, Aes256Gcm};
+    use aes_gcm::Aes128Gcm;
     use hkdf::Hkdf;
     use ring::rand::{SecureRandom, SystemRandom};
     use zeroize::Zeroizing;
@@ -120,4 +495,166 @@ mod tests {
         let aes = AES::<Aes256Gcm>::new(&key);
         assert!(aes.decrypt(b"Too short").is_err());
     }
+
+    #[test]
+    fn test_aead_algorithms() {
+        let key = keys::Key::generate(32).unwrap();
+        let nonce = [3u8; 12];
+        let aes = aead_encrypt(AeadAlgorithm::Aes256Gcm, &key, &nonce, b"body", b"id").unwrap();
+        let chacha = chacha20poly1305_encrypt(&key, &nonce, b"body", b"id").unwrap();
+        assert_ne!(aes, chacha);
+        assert_eq!(
+            aes,
+            aes256_gcm_encrypt(&key, &nonce, b"body", b"id").unwrap()
+        );
+        assert_eq!(
+            aead_decrypt(
+                AeadAlgorithm::ChaCha20Poly1305,
+                &key,
+                &nonce,
+                &chacha,
+                b"id"
+            )
+            .unwrap(),
+            b"body"
+        );
+        assert_eq!(
+            chacha20poly1305_decrypt(&key, &nonce, &chacha, b"other id"),
+            Err(EncryptError::AuthenticationFailed)
+        );
+        assert_eq!(
+            chacha20poly1305_decrypt(&key, &nonce, &aes, b"id"),
+            Err(EncryptError::AuthenticationFailed)
+        );
+        assert_eq!(
+            chacha20poly1305_encrypt(&keys::Key::generate(31).unwrap(), &nonce, b"", b""),
+            Err(EncryptError::InvalidKeyLength {
+                expected: 32,
+                actual: 31
+            })
+        );
+    }
+
+    #[test]
+    fn test_nonce_sequence() {
+        let key = keys::Key::generate(32).unwrap();
+        let sequence = NonceSequence::new(AeadAlgorithm::ChaCha20Poly1305).unwrap();
+        let (first, ciphertext) = sequence.encrypt_next(&key, b"body", b"id").unwrap();
+        let (second, _) = sequence.encrypt_next(&key, b"body", b"id").unwrap();
+        assert_ne!(first, second);
+        assert_eq!(first[..4], second[..4]);
+        assert_eq!(
+            chacha20poly1305_decrypt(&key, &first, &ciphertext, b"id").unwrap(),
+            b"body"
+        );
+
+        let nonces: std::collections::HashSet<[u8; 12]> =
+            (0..1000).map(|_| sequence.next_nonce().unwrap()).collect();
+        assert_eq!(nonces.len(), 1000);
+
+        sequence.counter.store(u64::MAX, Ordering::Relaxed);
+        assert_eq!(
+            sequence.encrypt_next(&key, b"body", b"id"),
+            Err(EncryptError::NonceExhausted)
+        );
+    }
+
+    fn stream_encrypt(key: &keys::Key, pieces: &[&[u8]]) -> Vec<u8> {
+        let mut encryptor = StreamEncryptor::new(AeadAlgorithm::Aes256Gcm, key, 8).unwrap();
+        let mut ciphertext = Vec::new();
+        for piece in pieces {
+            ciphertext.extend(encryptor.write_chunk(piece).unwrap());
+        }
+        ciphertext.extend(encryptor.finalize().unwrap());
+        ciphertext
+    }
+
+    fn stream_decrypt(key: &keys::Key, ciphertext: &[u8]) -> Result<Vec<u8>, EncryptError> {
+        let mut decryptor = StreamDecryptor::new(AeadAlgorithm::Aes256Gcm, key)?;
+        let mut plaintext = Vec::new();
+        for piece in ciphertext.chunks(5) {
+            plaintext.extend(decryptor.write_chunk(piece)?);
+        }
+        plaintext.extend(decryptor.finalize()?);
+        Ok(plaintext)
+    }
+
+    #[test]
+    fn test_stream_roundtrip_and_tampering() {
+        let key = keys::Key::generate(32).unwrap();
+        let pieces: [&[u8]; 3] = [b"a stream of ", b"more than one chunk", b", unevenly split"];
+        let plaintext = pieces.concat();
+        let ciphertext = stream_encrypt(&key, &pieces);
+        let chunks = plaintext.len().div_ceil(8);
+        assert_eq!(
+            ciphertext.len(),
+            STREAM_HEADER_SIZE + plaintext.len() + chunks * TAG_SIZE
+        );
+        assert_eq!(stream_decrypt(&key, &ciphertext).unwrap(), plaintext);
+        assert_eq!(
+            stream_decrypt(&key, &stream_encrypt(&key, &[])).unwrap(),
+            b""
+        );
+
+        let sealed = 8 + TAG_SIZE;
+        let body = &ciphertext[STREAM_HEADER_SIZE..];
+        let mut reordered = ciphertext[..STREAM_HEADER_SIZE].to_vec();
+        reordered.extend_from_slice(&body[sealed..2 * sealed]);
+        reordered.extend_from_slice(&body[..sealed]);
+        reordered.extend_from_slice(&body[2 * sealed..]);
+        assert_eq!(
+            stream_decrypt(&key, &reordered),
+            Err(EncryptError::AuthenticationFailed)
+        );
+        // Cut at a chunk boundary, so every remaining chunk is intact.
+        let truncated = &ciphertext[..STREAM_HEADER_SIZE + 2 * sealed];
+        assert_eq!(
+            stream_decrypt(&key, truncated),
+            Err(EncryptError::AuthenticationFailed)
+        );
+        let mut resized = ciphertext.clone();
+        resized[STREAM_HEADER_SIZE - 1] = 9;
+        assert_eq!(
+            stream_decrypt(&key, &resized),
+            Err(EncryptError::AuthenticationFailed)
+        );
+        assert_eq!(
+            stream_decrypt(&key, &ciphertext[..4]),
+            Err(EncryptError::TruncatedStream)
+        );
+        assert!(matches!(
+            StreamEncryptor::new(AeadAlgorithm::Aes256Gcm, &key, 0),
+            Err(EncryptError::InvalidChunkSize { actual: 0, .. })
+        ));
+    }
+
+    #[test]
+    fn test_aes256_gcm_with_aad() {
+        let key = keys::Key::generate(32).unwrap();
+        let nonce = [7u8; 12];
+        let ciphertext = aes256_gcm_encrypt(&key, &nonce, b"record body", b"record:1").unwrap();
+        assert_eq!(ciphertext.len(), b"record body".len() + 16);
+        assert_eq!(
+            aes256_gcm_decrypt(&key, &nonce, &ciphertext, b"record:1").unwrap(),
+            b"record body"
+        );
+
+        assert_eq!(
+            aes256_gcm_decrypt(&key, &nonce, &ciphertext, b"record:2"),
+            Err(EncryptError::AuthenticationFailed)
+        );
+        let mut tampered = ciphertext.clone();
+        tampered[0] ^= 1;
+        assert_eq!(
+            aes256_gcm_decrypt(&key, &nonce, &tampered, b"record:1"),
+            Err(EncryptError::AuthenticationFailed)
+        );
+        assert_eq!(
+            aes256_gcm_encrypt(&keys::Key::generate(16).unwrap(), &nonce, b"", b""),
+            Err(EncryptError::InvalidKeyLength {
+                expected: 32,
+                actual: 16
+            })
+        );
+    }
 }
@@ -1,21 +1,29 @@
 pub use hex::{FromHex, ToHex};
-use serde::{Deserialize, Serialize};
+use ring::hmac;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
 use sha3::{Digest, Sha3_256};
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Ord, PartialOrd, Serialize, Deserialize)]
+use std::fmt;
+use subtle::ConstantTimeEq;
+
+// Serializes as a hex string in human-readable formats (JSON) and as raw bytes otherwise (bincode).
+// The derived `PartialEq` and `Ord` short-circuit; compare MACs and secrets with `ct_eq`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Ord, PartialOrd)]
 pub struct Sha256(pub [u8; 32]);
 
 impl Sha256 {
     pub fn new(data: &[u8]) -> Self {
-        let mut hasher = Sha3_256::new();
+        let mut hasher = Sha256Hasher::new();
         hasher.update(data);
-        let result = hasher.finalize();
-
-        Self(result.into())
+        hasher.finalize()
     }
 
     pub fn as_bytes(&self) -> &[u8; 32] {
         &self.0
     }
+
+    pub fn ct_eq(&self, other: &Self) -> bool {
+        self.0.ct_eq(&other.0).into()
+    }
 }
 
 impl ToHex for Sha256 {
@@ -40,33 +48,158 @@ impl FromHex for Sha256 {
     }
 }
 
-pub struct Blake3(blake3::Hash);
+impl Serialize for Sha256 {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.serialize_str(&self.encode_hex::<String>())
+        } else {
+            self.0.serialize(serializer)
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Sha256 {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        if deserializer.is_human_readable() {
+            deserializer.deserialize_any(Sha256Visitor)
+        } else {
+            <[u8; 32]>::deserialize(deserializer).map(Self)
+        }
+    }
+}
+
+struct Sha256Visitor;
+
+impl<'de> de::Visitor<'de> for Sha256Visitor {
+    type Value = Sha256;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a hex string or an array of 32 bytes")
+    }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<Self::Value, E> {
+        Sha256::from_hex(value).map_err(E::custom)
+    }
+
+    fn visit_seq<A: de::SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        let mut bytes = [0u8; 32];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = seq
+                .next_element()?
+                .ok_or_else(|| de::Error::invalid_length(i, &self))?;
+        }
+        Ok(Sha256(bytes))
+    }
+}
+
+// Incremental form of `Sha256::new` for data that arrives in pieces.
+#[derive(Clone, Default)]
+pub struct Sha256Hasher(Sha3_256);
+
+impl Sha256Hasher {
+    pub fn new() -> Self {
+        Self(Sha3_256::new())
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.0.update(data);
+    }
+
+    pub fn finalize(self) -> Sha256 {
+        Sha256(self.0.finalize().into())
+    }
+}
+
+// Standard HMAC-SHA-256 (SHA-2, RFC 2104), not built on the SHA3-based `Sha256` above.
+pub fn hmac_sha256(key: &[u8], message: &[u8]) -> [u8; 32] {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, key);
+    hmac::sign(&key, message)
+        .as_ref()
+        .try_into()
+        .expect("HMAC-SHA-256 tags are 32 bytes")
+}
+
+// Compares in constant time.
+pub fn hmac_sha256_verify(key: &[u8], message: &[u8], tag: &[u8]) -> bool {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, key);
+    hmac::verify(&key, message, tag).is_ok()
+}
+
+// Mirrors `Sha256`, including its serde formats, for callers that want BLAKE3's speed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Ord, PartialOrd)]
+pub struct Blake3(pub [u8; 32]);
 
 impl Blake3 {
     pub fn new(data: &[u8]) -> Self {
-        Self(blake3::hash(data))
+        let mut hasher = Blake3Hasher::new();
+        hasher.update(data);
+        hasher.finalize()
     }
+
     pub fn as_bytes(&self) -> &[u8] {
-        self.0.as_bytes()
+        &self.0
     }
+
     pub fn from_bytes(bytes: [u8; 32]) -> Self {
-        Self(blake3::Hash::from_bytes(bytes))
+        Self(bytes)
     }
+
     pub fn to_hex(&self) -> String {
-        self.0.to_hex().to_string()
+        self.encode_hex()
     }
+
+    // Panics on malformed input; `FromHex::from_hex` reports it instead.
     pub fn from_hex(hex: &str) -> Self {
-        Self(blake3::Hash::from_hex(hex).unwrap())
+        <Self as FromHex>::from_hex(hex).unwrap()
     }
 }
+
 impl ToHex for Blake3 {
     fn encode_hex<T: std::iter::FromIterator<char>>(&self) -> T {
-        self.0.as_bytes().encode_hex()
+        self.0.encode_hex()
     }
     fn encode_hex_upper<T: std::iter::FromIterator<char>>(&self) -> T {
-        self.0.as_bytes().encode_hex_upper()
+        self.0.encode_hex_upper()
     }
 }
+
+impl FromHex for Blake3 {
+    type Error = hex::FromHexError;
+
+    fn from_hex<T: AsRef<[u8]>>(hex: T) -> Result<Self, Self::Error> {
+        Sha256::from_hex(hex).map(|digest| Self(digest.0))
+    }
+}
+
+impl Serialize for Blake3 {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        Sha256(self.0).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Blake3 {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Sha256::deserialize(deserializer).map(|digest| Self(digest.0))
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct Blake3Hasher(blake3::Hasher);
+
+impl Blake3Hasher {
+    pub fn new() -> Self {
+        Self(blake3::Hasher::new())
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.0.update(data);
+    }
+
+    pub fn finalize(self) -> Blake3 {
+        Blake3(*self.0.finalize().as_bytes())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -78,6 +211,44 @@ mod tests {
         assert_eq!(sha256.0.len(), 32);
     }
 
+    #[test]
+    fn test_sha256_hasher_matches_new() {
+        let mut hasher = Sha256Hasher::new();
+        hasher.update(b"hello ");
+        hasher.update(b"world");
+        assert_eq!(hasher.finalize(), Sha256::new(b"hello world"));
+
+        let mut hasher = Blake3Hasher::new();
+        hasher.update(b"hello ");
+        hasher.update(b"world");
+        assert_eq!(
+            hasher.finalize(),
+            Blake3(*blake3::hash(b"hello world").as_bytes())
+        );
+    }
+
+    #[test]
+    fn test_sha256_ct_eq() {
+        let digest = Sha256::new(b"tag");
+        assert!(digest.ct_eq(&Sha256::new(b"tag")));
+        assert!(!digest.ct_eq(&Sha256::new(b"gat")));
+    }
+
+    #[test]
+    fn test_hmac_sha256_rfc4231() {
+        // RFC 4231, test case 1.
+        let key = [0x0b; 20];
+        let expected = <[u8; 32]>::from_hex(
+            "b0344c61d8db38535ca8afceaf0bf12b881dc200c9833da726e9376c2e32cff7",
+        )
+        .unwrap();
+        let tag = hmac_sha256(&key, b"Hi There");
+        assert_eq!(tag, expected);
+        assert!(hmac_sha256_verify(&key, b"Hi There", &tag));
+        assert!(!hmac_sha256_verify(&key, b"Hi there", &tag));
+        assert!(!hmac_sha256_verify(&key, b"Hi There", &tag[..31]));
+    }
+
     #[test]
     fn test_sha256_to_hex() {
         let data = b"test data";
@@ -118,4 +289,47 @@ mod tests {
 
         assert_eq!(sha256, deserialized);
     }
+
+    #[test]
+    fn test_sha256_serde_hex_vs_bytes() {
+        let sha256 = Sha256::new(b"format test");
+        let hex: String = sha256.encode_hex();
+
+        let json = serde_json::to_string(&sha256).unwrap();
+        assert_eq!(json, format!("\"{}\"", hex));
+
+        let encoded = bincode::serialize(&sha256).unwrap();
+        assert_eq!(encoded.len(), 32);
+        assert_eq!(bincode::deserialize::<Sha256>(&encoded).unwrap(), sha256);
+
+        let legacy_json = serde_json::to_string(&sha256.0).unwrap();
+        assert_eq!(
+            serde_json::from_str::<Sha256>(&legacy_json).unwrap(),
+            sha256
+        );
+    }
+
+    #[test]
+    fn test_blake3_mirrors_sha256() {
+        let blake3 = Blake3::new(b"blake3 test");
+        assert_eq!(blake3.as_bytes(), blake3::hash(b"blake3 test").as_bytes());
+        assert_ne!(blake3.0, Sha256::new(b"blake3 test").0);
+        assert_eq!(Blake3::from_hex(&blake3.to_hex()), blake3);
+        assert_eq!(
+            <Blake3 as FromHex>::from_hex(blake3.to_hex()).unwrap(),
+            blake3
+        );
+        assert!(<Blake3 as FromHex>::from_hex("not hex").is_err());
+
+        let json = serde_json::to_string(&blake3).unwrap();
+        assert_eq!(json, format!("\"{}\"", blake3.to_hex()));
+        assert_eq!(serde_json::from_str::<Blake3>(&json).unwrap(), blake3);
+        let bytes = bincode::serialize(&blake3).unwrap();
+        assert_eq!(bytes.len(), 32);
+        assert_eq!(bincode::deserialize::<Blake3>(&bytes).unwrap(), blake3);
+
+        let mut sorted = [Blake3::new(b"b"), Blake3::new(b"a"), Blake3::new(b"c")];
+        sorted.sort();
+        assert!(sorted.windows(2).all(|pair| pair[0] < pair[1]));
+    }
 }
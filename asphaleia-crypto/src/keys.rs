@@ -1,12 +1,19 @@
-use super::hash::Sha256;
+use super::{ed25519::Ed25519, encrypt::AES, hash::Sha256, x25519};
+use aes_gcm::Aes256Gcm;
+use argon2::{Argon2, PasswordHash, PasswordVerifier};
+use ed25519_dalek::{Signature, SignatureError, Verifier, VerifyingKey};
 use hex::{FromHex, ToHex};
 use hkdf::Hkdf;
-use ring::rand::SecureRandom;
+use ring::{pbkdf2, rand::SecureRandom};
+use serde::{Deserialize, Serialize};
 use sha3::Sha3_256;
 use std::{
-    collections::{BTreeMap, HashMap},
-    sync::{Arc, RwLock},
+    collections::{BTreeMap, HashMap, VecDeque},
+    num::NonZeroU32,
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
+use subtle::ConstantTimeEq;
 use thiserror::Error;
 use x25519_dalek::{EphemeralSecret, PublicKey};
 use zeroize::{Zeroize, Zeroizing};
@@ -17,21 +24,128 @@ pub enum KeyManagementError {
     LockPoisoned,
     #[error("Key not found")]
     KeyNotFound,
+    #[error("Key expired")]
+    KeyExpired,
     #[error("Invalid key length")]
     InvalidKeyLength,
     #[error("Failed to generate random key")]
     RandomGenerationFailed,
+    #[error("Key derivation failed: {0}")]
+    KeyDerivationFailed(String),
+    #[error("Encryption failed")]
+    EncryptionFailed,
+    #[error("Decryption failed")]
+    DecryptionFailed,
+    #[error("Malformed key export")]
+    MalformedExport,
+    #[error("Invalid password hash: {0}")]
+    InvalidPasswordHash(String),
+    #[error("Invalid threshold {threshold} for {shares} shares")]
+    InvalidThreshold { threshold: u8, shares: u8 },
+    #[error("Not enough shares: need {needed}, got {got}")]
+    NotEnoughShares { needed: usize, got: usize },
+    #[error("Shares are malformed, duplicated or from different splits")]
+    InvalidShares,
+}
+
+// Version byte at the start of an export's plaintext. Format 1 predates key expiry and
+// lacks each entry's 13-byte expiry field; it still imports, with no expiry.
+const EXPORT_FORMAT: u8 = 2;
+const EXPORT_FORMAT_WITHOUT_EXPIRY: u8 = 1;
+const EXPORT_KEY_INFO: &[u8] = b"asphaleia/kms-export";
+const ENVELOPE_KEY_INFO: &[u8] = b"asphaleia/kms-envelope";
+const DATA_KEY_SIZE: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AuditOperation {
+    AddKey,
+    GetKey,
+    RemoveKey,
+    RotateKeys,
+}
+
+// Identifies the slot touched, never the key material in it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub operation: AuditOperation,
+    pub name: Sha256,
+    pub version: Sha256,
+    pub timestamp: SystemTime,
+}
+
+pub type AuditSink = Arc<dyn Fn(&AuditEntry) + Send + Sync>;
+
+#[derive(Default)]
+struct AuditTrail {
+    entries: VecDeque<AuditEntry>,
+    capacity: usize,
+    sink: Option<AuditSink>,
 }
 
 #[derive(Clone)]
 pub struct KeyManagementSystem {
     keys: Arc<RwLock<HashMap<Sha256, BTreeMap<Sha256, KeyAndDerived>>>>,
+    audit: Arc<Mutex<AuditTrail>>,
+}
+
+impl Default for KeyManagementSystem {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl KeyManagementSystem {
     pub fn new() -> Self {
         Self {
             keys: Arc::new(RwLock::new(HashMap::new())),
+            audit: Arc::default(),
+        }
+    }
+
+    // Keeps the last `capacity` audit entries in memory; zero turns the buffer off and
+    // clears it. Clones of this system share one trail.
+    pub fn set_audit_capacity(&self, capacity: usize) {
+        let mut audit = self.audit_trail();
+        audit.capacity = capacity;
+        while audit.entries.len() > capacity {
+            audit.entries.pop_front();
+        }
+    }
+
+    // Receives every entry as it is recorded, whether or not the buffer is on. Sinks run
+    // with no lock held, so they may call back into the system.
+    pub fn set_audit_sink(&self, sink: Option<AuditSink>) {
+        self.audit_trail().sink = sink;
+    }
+
+    pub fn audit_log(&self) -> Vec<AuditEntry> {
+        self.audit_trail().entries.iter().cloned().collect()
+    }
+
+    // The trail is append-only, so a panic mid-record leaves nothing to repair.
+    fn audit_trail(&self) -> std::sync::MutexGuard<'_, AuditTrail> {
+        self.audit.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn record(&self, operation: AuditOperation, name: Sha256, version: Sha256) {
+        let entry = AuditEntry {
+            operation,
+            name,
+            version,
+            timestamp: SystemTime::now(),
+        };
+        let sink = {
+            let mut audit = self.audit_trail();
+            if audit.capacity > 0 {
+                if audit.entries.len() == audit.capacity {
+                    audit.entries.pop_front();
+                }
+                audit.entries.push_back(entry.clone());
+            }
+            audit.sink.clone()
+        };
+        if let Some(sink) = sink {
+            sink(&entry);
         }
     }
 
@@ -47,34 +161,127 @@ impl KeyManagementSystem {
             .entry(name)
             .or_insert_with(BTreeMap::new)
             .insert(version, key_and_derived);
+        self.record(AuditOperation::AddKey, name, version);
         Ok(())
     }
 
-    pub fn rotate_keys(&self) -> Result<(), KeyManagementError> {
+    // After `expires_at`, `get_key` refuses the entry and `evict_expired` drops it.
+    pub fn add_key_with_expiry(
+        &self,
+        name: Sha256,
+        version: Sha256,
+        mut key_and_derived: KeyAndDerived,
+        expires_at: SystemTime,
+    ) -> Result<(), KeyManagementError> {
+        key_and_derived.expires_at = Some(expires_at);
+        self.add_key(name, version, key_and_derived)
+    }
+
+    // Removes every expired version, and names left without versions; dropping an entry
+    // zeroizes its key material. Returns how many versions were removed.
+    pub fn evict_expired(&self) -> Result<usize, KeyManagementError> {
+        let now = SystemTime::now();
         let mut keys = self
             .keys
             .write()
             .map_err(|_| KeyManagementError::LockPoisoned)?;
-        for versions in keys.values_mut() {
-            for key_and_derived in versions.values_mut() {
-                key_and_derived.rotate();
+        let mut evicted = 0;
+        keys.retain(|_, versions| {
+            let before = versions.len();
+            versions.retain(|_, entry| !entry.is_expired_at(now));
+            evicted += before - versions.len();
+            !versions.is_empty()
+        });
+        Ok(evicted)
+    }
+
+    // Promotes a derived key to a managed root key under `name`/`version`, stamped with
+    // the time of adoption. An existing entry at that slot is replaced.
+    pub fn adopt_derived(
+        &self,
+        name: Sha256,
+        version: Sha256,
+        derived: &DerivedKey,
+    ) -> Result<(), KeyManagementError> {
+        self.add_key(name, version, derived.to_key_and_derived())
+    }
+
+    pub fn rotate_keys(&self) -> Result<(), KeyManagementError> {
+        let mut rotated = Vec::new();
+        {
+            let mut keys = self
+                .keys
+                .write()
+                .map_err(|_| KeyManagementError::LockPoisoned)?;
+            for (name, versions) in keys.iter_mut() {
+                for (version, key_and_derived) in versions.iter_mut() {
+                    key_and_derived.rotate();
+                    rotated.push((*name, *version));
+                }
             }
         }
+        for (name, version) in rotated {
+            self.record(AuditOperation::RotateKeys, name, version);
+        }
         Ok(())
     }
 
+    // Adds fresh material, shaped like the newest version, under a new random version id
+    // and returns that id. Earlier versions stay available for decrypting old data.
+    pub fn rotate_key_versioned(&self, name: &Sha256) -> Result<Sha256, KeyManagementError> {
+        let mut keys = self
+            .keys
+            .write()
+            .map_err(|_| KeyManagementError::LockPoisoned)?;
+        let versions = keys.get_mut(name).ok_or(KeyManagementError::KeyNotFound)?;
+        let newest = newest_version(versions).ok_or(KeyManagementError::KeyNotFound)?;
+        let mut rotated = versions[&newest].clone();
+        rotated.rotate();
+        rotated.expires_at = None;
+        // Keep `latest_version` unambiguous even when the clock hasn't advanced.
+        let floor = versions[&newest].created_at + Duration::from_nanos(1);
+        rotated.created_at = rotated.created_at.max(floor);
+
+        let mut id = [0u8; 32];
+        ring::rand::SystemRandom::new()
+            .fill(&mut id)
+            .map_err(|_| KeyManagementError::RandomGenerationFailed)?;
+        let version = Sha256::new(&id);
+        versions.insert(version, rotated);
+        drop(keys);
+        self.record(AuditOperation::RotateKeys, *name, version);
+        Ok(version)
+    }
+
+    // The version with the latest `created_at`, ties broken by version id.
+    pub fn latest_version(&self, name: &Sha256) -> Result<Option<Sha256>, KeyManagementError> {
+        self.keys
+            .read()
+            .map_err(|_| KeyManagementError::LockPoisoned)
+            .map(|keys| keys.get(name).and_then(newest_version))
+    }
+
     pub fn get_key(
         &self,
         name: &Sha256,
         version: &Sha256,
     ) -> Result<Option<KeyAndDerived>, KeyManagementError> {
-        self.keys
+        let found = self
+            .keys
             .read()
-            .map_err(|_| KeyManagementError::LockPoisoned)
-            .map(|keys| {
-                keys.get(name)
-                    .and_then(|versions| versions.get(version).cloned())
+            .map_err(|_| KeyManagementError::LockPoisoned)?
+            .get(name)
+            .and_then(|versions| versions.get(version))
+            .map(|entry| {
+                if entry.is_expired_at(SystemTime::now()) {
+                    Err(KeyManagementError::KeyExpired)
+                } else {
+                    Ok(entry.clone())
+                }
             })
+            .transpose();
+        self.record(AuditOperation::GetKey, *name, *version);
+        found
     }
 
     pub fn remove_key(
@@ -82,13 +289,14 @@ impl KeyManagementSystem {
         name: &Sha256,
         version: &Sha256,
     ) -> Result<Option<KeyAndDerived>, KeyManagementError> {
-        self.keys
+        let removed = self
+            .keys
             .write()
-            .map_err(|_| KeyManagementError::LockPoisoned)
-            .map(|mut keys| {
-                keys.get_mut(name)
-                    .and_then(|versions| versions.remove(version))
-            })
+            .map_err(|_| KeyManagementError::LockPoisoned)?
+            .get_mut(name)
+            .and_then(|versions| versions.remove(version));
+        self.record(AuditOperation::RemoveKey, *name, *version);
+        Ok(removed)
     }
 
     pub fn list_keys(&self) -> Result<Vec<(Sha256, Vec<Sha256>)>, KeyManagementError> {
@@ -97,16 +305,337 @@ impl KeyManagementSystem {
             .map_err(|_| KeyManagementError::LockPoisoned)
             .map(|keys| {
                 keys.iter()
-                    .map(|(name, versions)| (name.clone(), versions.keys().cloned().collect()))
+                    .map(|(name, versions)| (*name, versions.keys().cloned().collect()))
                     .collect()
             })
     }
+
+    // Every name, version, key and derived key, sealed with AES-256-GCM under a key
+    // derived from `master_key`. The plaintext only ever lives in zeroizing buffers.
+    pub fn export_encrypted(&self, master_key: &Key) -> Result<Vec<u8>, KeyManagementError> {
+        let plaintext = {
+            let keys = self
+                .keys
+                .read()
+                .map_err(|_| KeyManagementError::LockPoisoned)?;
+            encode_export(&keys)
+        };
+        sealing_cipher(master_key, EXPORT_KEY_INFO)
+            .encrypt(&plaintext, None)
+            .map_err(|_| KeyManagementError::EncryptionFailed)
+    }
+
+    pub fn import_encrypted(bytes: &[u8], master_key: &Key) -> Result<Self, KeyManagementError> {
+        let plaintext = Zeroizing::new(
+            sealing_cipher(master_key, EXPORT_KEY_INFO)
+                .decrypt(bytes)
+                .map_err(|_| KeyManagementError::DecryptionFailed)?,
+        );
+        Ok(Self {
+            keys: Arc::new(RwLock::new(decode_export(&plaintext)?)),
+            audit: Arc::default(),
+        })
+    }
+
+    // Seals `plaintext` under a fresh random data key, which is itself sealed under a key
+    // derived from the named master. Both layers are AES-256-GCM.
+    pub fn encrypt_envelope(
+        &self,
+        master_name: &Sha256,
+        master_version: &Sha256,
+        plaintext: &[u8],
+    ) -> Result<EnvelopeCiphertext, KeyManagementError> {
+        let master = self.master_key(master_name, master_version)?;
+        let data_key = Key::generate(DATA_KEY_SIZE)?;
+        let wrapped_key = sealing_cipher(master.key(), ENVELOPE_KEY_INFO)
+            .encrypt(data_key.as_bytes(), None)
+            .map_err(|_| KeyManagementError::EncryptionFailed)?;
+        let ciphertext = AES::<Aes256Gcm>::new(data_key.as_bytes())
+            .encrypt(plaintext, None)
+            .map_err(|_| KeyManagementError::EncryptionFailed)?;
+        Ok(EnvelopeCiphertext {
+            master_name: *master_name,
+            master_version: *master_version,
+            wrapped_key,
+            ciphertext,
+        })
+    }
+
+    pub fn decrypt_envelope(
+        &self,
+        envelope: &EnvelopeCiphertext,
+    ) -> Result<Vec<u8>, KeyManagementError> {
+        let master = self.master_key(&envelope.master_name, &envelope.master_version)?;
+        let data_key = Zeroizing::new(
+            sealing_cipher(master.key(), ENVELOPE_KEY_INFO)
+                .decrypt(&envelope.wrapped_key)
+                .map_err(|_| KeyManagementError::DecryptionFailed)?,
+        );
+        if data_key.len() != DATA_KEY_SIZE {
+            return Err(KeyManagementError::DecryptionFailed);
+        }
+        AES::<Aes256Gcm>::new(&*data_key)
+            .decrypt(&envelope.ciphertext)
+            .map_err(|_| KeyManagementError::DecryptionFailed)
+    }
+
+    fn master_key(
+        &self,
+        name: &Sha256,
+        version: &Sha256,
+    ) -> Result<KeyAndDerived, KeyManagementError> {
+        self.get_key(name, version)?
+            .ok_or(KeyManagementError::KeyNotFound)
+    }
+
+    pub fn signed_manifest(&self, signer: &Ed25519) -> Result<SignedManifest, KeyManagementError> {
+        let mut entries: Vec<ManifestEntry> = self
+            .list_keys()?
+            .into_iter()
+            .map(|(name, versions)| ManifestEntry { name, versions })
+            .collect();
+        entries.sort_by_key(|entry| entry.name);
+        let key_count = entries.len();
+        let version_count = entries.iter().map(|entry| entry.versions.len()).sum();
+        let signature = signer.sign(&manifest_bytes(&entries, key_count, version_count));
+        Ok(SignedManifest {
+            entries,
+            key_count,
+            version_count,
+            signature,
+        })
+    }
+}
+
+// Names the master that wrapped the data key; rotating that master in place with
+// `rotate_keys` makes existing envelopes undecryptable, `rotate_key_versioned` does not.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EnvelopeCiphertext {
+    pub master_name: Sha256,
+    pub master_version: Sha256,
+    pub wrapped_key: Vec<u8>,
+    pub ciphertext: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    pub name: Sha256,
+    pub versions: Vec<Sha256>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SignedManifest {
+    pub entries: Vec<ManifestEntry>,
+    pub key_count: usize,
+    pub version_count: usize,
+    pub signature: Signature,
+}
+
+impl SignedManifest {
+    pub fn verify_manifest(&self, verifying_key: &VerifyingKey) -> Result<(), SignatureError> {
+        let message = manifest_bytes(&self.entries, self.key_count, self.version_count);
+        verifying_key.verify(&message, &self.signature)
+    }
+}
+
+fn manifest_bytes(entries: &[ManifestEntry], key_count: usize, version_count: usize) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    bytes.extend_from_slice(&(key_count as u64).to_be_bytes());
+    bytes.extend_from_slice(&(version_count as u64).to_be_bytes());
+    for entry in entries {
+        bytes.extend_from_slice(entry.name.as_bytes());
+        bytes.extend_from_slice(&(entry.versions.len() as u64).to_be_bytes());
+        for version in &entry.versions {
+            bytes.extend_from_slice(version.as_bytes());
+        }
+    }
+    bytes
+}
+
+fn newest_version(versions: &BTreeMap<Sha256, KeyAndDerived>) -> Option<Sha256> {
+    versions
+        .iter()
+        .max_by_key(|(version, entry)| (entry.created_at, **version))
+        .map(|(version, _)| *version)
+}
+
+fn sealing_cipher(key: &Key, info: &[u8]) -> AES<Aes256Gcm> {
+    AES::new(key.derive(None, info, 32).as_bytes())
+}
+
+type KeyStore = HashMap<Sha256, BTreeMap<Sha256, KeyAndDerived>>;
+
+// Sized up front so the buffer never reallocates and strands unzeroized copies.
+fn encode_export(keys: &KeyStore) -> Zeroizing<Vec<u8>> {
+    let entry_size = |entry: &KeyAndDerived| {
+        32 + 12
+            + 13
+            + 4
+            + entry.key.len()
+            + 8
+            + entry
+                .derived_keys
+                .values()
+                .map(|derived| 32 + 4 + derived.len())
+                .sum::<usize>()
+    };
+    let size = 1
+        + 8
+        + keys
+            .values()
+            .map(|versions| 32 + 8 + versions.values().map(entry_size).sum::<usize>())
+            .sum::<usize>();
+
+    let mut bytes = Zeroizing::new(Vec::with_capacity(size));
+    bytes.push(EXPORT_FORMAT);
+    bytes.extend_from_slice(&(keys.len() as u64).to_be_bytes());
+    for (name, versions) in keys {
+        bytes.extend_from_slice(name.as_bytes());
+        bytes.extend_from_slice(&(versions.len() as u64).to_be_bytes());
+        for (version, entry) in versions {
+            let created = entry
+                .created_at
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default();
+            bytes.extend_from_slice(version.as_bytes());
+            bytes.extend_from_slice(&created.as_secs().to_be_bytes());
+            bytes.extend_from_slice(&created.subsec_nanos().to_be_bytes());
+            let expires = entry
+                .expires_at
+                .map(|at| at.duration_since(UNIX_EPOCH).unwrap_or_default());
+            bytes.push(expires.is_some() as u8);
+            let expires = expires.unwrap_or_default();
+            bytes.extend_from_slice(&expires.as_secs().to_be_bytes());
+            bytes.extend_from_slice(&expires.subsec_nanos().to_be_bytes());
+            bytes.extend_from_slice(&(entry.key.len() as u32).to_be_bytes());
+            bytes.extend_from_slice(entry.key.as_bytes());
+            bytes.extend_from_slice(&(entry.derived_keys.len() as u64).to_be_bytes());
+            for (derived_name, derived) in &entry.derived_keys {
+                bytes.extend_from_slice(derived_name.as_bytes());
+                bytes.extend_from_slice(&(derived.len() as u32).to_be_bytes());
+                bytes.extend_from_slice(derived.as_bytes());
+            }
+        }
+    }
+    debug_assert_eq!(bytes.len(), size);
+    bytes
+}
+
+fn decode_export(bytes: &[u8]) -> Result<KeyStore, KeyManagementError> {
+    let mut reader = ExportReader(bytes);
+    let format = reader.take(1)?[0];
+    if format != EXPORT_FORMAT && format != EXPORT_FORMAT_WITHOUT_EXPIRY {
+        return Err(KeyManagementError::MalformedExport);
+    }
+    let mut keys = HashMap::new();
+    for _ in 0..reader.u64()? {
+        let name = reader.sha256()?;
+        let mut versions = BTreeMap::new();
+        for _ in 0..reader.u64()? {
+            let version = reader.sha256()?;
+            let created_at = reader.time()?;
+            let expires_at = if format == EXPORT_FORMAT {
+                let has_expiry = reader.take(1)? == [1];
+                let expires_at = reader.time()?;
+                has_expiry.then_some(expires_at)
+            } else {
+                None
+            };
+            let key = Key(reader.secret()?);
+            let mut derived_keys = BTreeMap::new();
+            for _ in 0..reader.u64()? {
+                let derived_name = reader.sha256()?;
+                derived_keys.insert(derived_name, DerivedKey(reader.secret()?));
+            }
+            versions.insert(
+                version,
+                KeyAndDerived {
+                    key,
+                    derived_keys,
+                    created_at,
+                    expires_at,
+                },
+            );
+        }
+        keys.insert(name, versions);
+    }
+    if !reader.0.is_empty() {
+        return Err(KeyManagementError::MalformedExport);
+    }
+    Ok(keys)
+}
+
+struct ExportReader<'a>(&'a [u8]);
+
+impl<'a> ExportReader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], KeyManagementError> {
+        if self.0.len() < len {
+            return Err(KeyManagementError::MalformedExport);
+        }
+        let (head, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(head)
+    }
+
+    fn u32(&mut self) -> Result<u32, KeyManagementError> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, KeyManagementError> {
+        Ok(u64::from_be_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn time(&mut self) -> Result<SystemTime, KeyManagementError> {
+        let (secs, nanos) = (self.u64()?, self.u32()?);
+        (nanos < 1_000_000_000)
+            .then(|| UNIX_EPOCH.checked_add(Duration::new(secs, nanos)))
+            .flatten()
+            .ok_or(KeyManagementError::MalformedExport)
+    }
+
+    fn sha256(&mut self) -> Result<Sha256, KeyManagementError> {
+        Ok(Sha256(self.take(32)?.try_into().unwrap()))
+    }
+
+    fn secret(&mut self) -> Result<Zeroizing<Vec<u8>>, KeyManagementError> {
+        let len = self.u32()? as usize;
+        Ok(Zeroizing::new(self.take(len)?.to_vec()))
+    }
+}
+
+// The standard key schedule. Each purpose is derived from the parent with its label
+// as HKDF info and stored under `Sha256(label)`, so re-deriving is deterministic.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Purpose {
+    Encryption,
+    Mac,
+    Signing,
+    KeyWrapping,
+}
+
+impl Purpose {
+    pub fn label(&self) -> &'static str {
+        match self {
+            Purpose::Encryption => "asphaleia/encryption",
+            Purpose::Mac => "asphaleia/mac",
+            Purpose::Signing => "asphaleia/signing",
+            Purpose::KeyWrapping => "asphaleia/key-wrapping",
+        }
+    }
+
+    pub fn name(&self) -> Sha256 {
+        Sha256::new(self.label().as_bytes())
+    }
 }
 
+const PURPOSE_KEY_LENGTH: usize = 32;
+
 #[derive(Clone)]
 pub struct KeyAndDerived {
     key: Key,
     derived_keys: BTreeMap<Sha256, DerivedKey>,
+    created_at: SystemTime,
+    expires_at: Option<SystemTime>,
 }
 
 impl KeyAndDerived {
@@ -114,9 +643,24 @@ impl KeyAndDerived {
         Self {
             key,
             derived_keys: BTreeMap::new(),
+            created_at: SystemTime::now(),
+            expires_at: None,
         }
     }
 
+    pub fn expires_at(&self) -> Option<SystemTime> {
+        self.expires_at
+    }
+
+    fn is_expired_at(&self, now: SystemTime) -> bool {
+        self.expires_at.is_some_and(|expires_at| now >= expires_at)
+    }
+
+    // When this key material was created or last rotated.
+    pub fn created_at(&self) -> SystemTime {
+        self.created_at
+    }
+
     pub fn add_derived_key(&mut self, name: Sha256, derived_key: DerivedKey) {
         self.derived_keys.insert(name, derived_key);
     }
@@ -125,6 +669,29 @@ impl KeyAndDerived {
         self.derived_keys.get(name)
     }
 
+    // Derives with the label's bytes as HKDF info and stores the result under
+    // `Sha256(label)`, replacing any key already there.
+    pub fn derive_labeled(&mut self, label: &str, output_length: usize) -> &DerivedKey {
+        let name = Sha256::new(label.as_bytes());
+        let derived_key = self.key.derive(None, label.as_bytes(), output_length);
+        self.derived_keys.insert(name, derived_key);
+        &self.derived_keys[&name]
+    }
+
+    pub fn get_derived_by_label(&self, label: &str) -> Option<&DerivedKey> {
+        self.derived_keys.get(&Sha256::new(label.as_bytes()))
+    }
+
+    pub fn rederive_standard(&mut self, purposes: &[Purpose]) {
+        for purpose in purposes {
+            self.derive_labeled(purpose.label(), PURPOSE_KEY_LENGTH);
+        }
+    }
+
+    pub fn get_purpose_key(&self, purpose: Purpose) -> Option<&DerivedKey> {
+        self.derived_keys.get(&purpose.name())
+    }
+
     pub fn key(&self) -> &Key {
         &self.key
     }
@@ -132,6 +699,7 @@ impl KeyAndDerived {
     pub fn rotate(&mut self) {
         let new_key = Key::generate(self.key.len()).expect("Failed to generate new key");
         self.key = new_key;
+        self.created_at = SystemTime::now();
         for derived_key in self.derived_keys.values_mut() {
             *derived_key = derived_key.rotate_key();
         }
@@ -147,6 +715,7 @@ impl Drop for KeyAndDerived {
     }
 }
 
+// As with `Key`, the derived `PartialEq` is not constant-time; use `ct_eq` for secrets.
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct DerivedKey(Zeroizing<Vec<u8>>);
 
@@ -155,6 +724,10 @@ impl DerivedKey {
         Self(Zeroizing::new(bytes))
     }
 
+    pub fn ct_eq(&self, other: &Self) -> bool {
+        self.0.as_slice().ct_eq(other.0.as_slice()).into()
+    }
+
     pub fn to_key(&self) -> Key {
         Key(self.0.clone())
     }
@@ -166,6 +739,21 @@ impl DerivedKey {
             .map_err(|_| KeyManagementError::RandomGenerationFailed)?;
         Ok(Self(key))
     }
+
+    // Runs HKDF-SHA3-256 over a key-agreement output. Only feed it secrets from
+    // ephemeral-ephemeral or authenticated exchanges; use a distinct `info` per key.
+    pub fn from_shared_secret(
+        secret: &[u8],
+        salt: Option<&[u8]>,
+        info: &[u8],
+        output_length: usize,
+    ) -> DerivedKey {
+        let hk = Hkdf::<Sha3_256>::new(salt, secret);
+        let mut okm = Zeroizing::new(vec![0u8; output_length]);
+        hk.expand(info, &mut okm)
+            .expect("HKDF-SHA3-256 should never fail");
+        DerivedKey(okm)
+    }
 }
 
 impl Zeroize for DerivedKey {
@@ -217,10 +805,130 @@ impl ManageKey for DerivedKey {
     }
 }
 
+// Password stretching algorithms and their cost parameters. Memory is in KiB for both
+// memory-hard choices; scrypt's N is given as log2.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PasswordKdf {
+    Argon2id {
+        memory_kib: u32,
+        iterations: u32,
+        parallelism: u32,
+    },
+    Scrypt {
+        log_n: u8,
+        r: u32,
+        p: u32,
+    },
+    Pbkdf2HmacSha256 {
+        iterations: u32,
+    },
+}
+
+impl Default for PasswordKdf {
+    fn default() -> Self {
+        PasswordKdf::Argon2id {
+            memory_kib: argon2::Params::DEFAULT_M_COST,
+            iterations: argon2::Params::DEFAULT_T_COST,
+            parallelism: argon2::Params::DEFAULT_P_COST,
+        }
+    }
+}
+
+// Everything needed to re-derive a password key; store it next to whatever the key
+// protects. None of it is secret.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PasswordKeyParams {
+    pub kdf: PasswordKdf,
+    pub salt: Vec<u8>,
+    pub key_size: usize,
+}
+
+impl PasswordKeyParams {
+    pub fn generate(kdf: PasswordKdf, key_size: usize) -> Result<Self, KeyManagementError> {
+        let mut salt = vec![0u8; 16];
+        ring::rand::SystemRandom::new()
+            .fill(&mut salt)
+            .map_err(|_| KeyManagementError::RandomGenerationFailed)?;
+        Ok(Self {
+            kdf,
+            salt,
+            key_size,
+        })
+    }
+}
+
+// The derived `PartialEq` stops at the first differing byte. `ct_eq` takes time
+// independent of the contents, though keys of different lengths compare unequal at once.
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Key(Zeroizing<Vec<u8>>);
 
 impl Key {
+    pub fn from_password(
+        password: &[u8],
+        params: &PasswordKeyParams,
+    ) -> Result<Self, KeyManagementError> {
+        let mut key = Zeroizing::new(vec![0u8; params.key_size]);
+        match params.kdf {
+            PasswordKdf::Argon2id {
+                memory_kib,
+                iterations,
+                parallelism,
+            } => {
+                let argon_params =
+                    argon2::Params::new(memory_kib, iterations, parallelism, Some(params.key_size))
+                        .map_err(|e| KeyManagementError::KeyDerivationFailed(e.to_string()))?;
+                Argon2::new(
+                    argon2::Algorithm::Argon2id,
+                    argon2::Version::V0x13,
+                    argon_params,
+                )
+                .hash_password_into(password, &params.salt, &mut key)
+                .map_err(|e| KeyManagementError::KeyDerivationFailed(e.to_string()))?;
+            }
+            PasswordKdf::Scrypt { log_n, r, p } => {
+                let scrypt_params =
+                    scrypt::Params::new(log_n, r, p, scrypt::Params::RECOMMENDED_LEN)
+                        .map_err(|e| KeyManagementError::KeyDerivationFailed(e.to_string()))?;
+                scrypt::scrypt(password, &params.salt, &scrypt_params, &mut key)
+                    .map_err(|e| KeyManagementError::KeyDerivationFailed(e.to_string()))?;
+            }
+            PasswordKdf::Pbkdf2HmacSha256 { iterations } => {
+                let iterations = NonZeroU32::new(iterations).ok_or_else(|| {
+                    KeyManagementError::KeyDerivationFailed(
+                        "PBKDF2 needs at least one iteration".to_string(),
+                    )
+                })?;
+                if key.is_empty() {
+                    return Err(KeyManagementError::InvalidKeyLength);
+                }
+                pbkdf2::derive(
+                    pbkdf2::PBKDF2_HMAC_SHA256,
+                    iterations,
+                    &params.salt,
+                    password,
+                    &mut key,
+                );
+            }
+        }
+        Ok(Self(key))
+    }
+
+    // Checks a password against a stored Argon2 PHC string, which carries its own
+    // variant, cost parameters and salt. Only an unparseable hash is an error.
+    pub fn derive_and_verify(password: &[u8], phc: &str) -> Result<bool, KeyManagementError> {
+        let hash = PasswordHash::new(phc)
+            .map_err(|e| KeyManagementError::InvalidPasswordHash(e.to_string()))?;
+        match Argon2::default().verify_password(password, &hash) {
+            Ok(()) => Ok(true),
+            Err(argon2::password_hash::Error::Password) => Ok(false),
+            Err(e) => Err(KeyManagementError::InvalidPasswordHash(e.to_string())),
+        }
+    }
+
+    pub fn ct_eq(&self, other: &Self) -> bool {
+        self.0.as_slice().ct_eq(other.0.as_slice()).into()
+    }
+
     pub fn generate(key_size: usize) -> Result<Self, KeyManagementError> {
         let mut key = Zeroizing::new(vec![0u8; key_size]);
         ring::rand::SystemRandom::new()
@@ -303,19 +1011,24 @@ impl FromHex for Key {
     }
 }
 
+// Wraps `x25519::EphemeralSecret` and hands back the raw curve output; new code should use
+// `x25519` directly and run the `SharedSecret` through `derive`.
 pub struct DiffieHellman {
-    ephemeral_secret: EphemeralSecret,
+    secret: x25519::EphemeralSecret,
     public_key: PublicKey,
 }
 
+impl Default for DiffieHellman {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl DiffieHellman {
     pub fn new() -> Self {
-        let ephemeral_secret = EphemeralSecret::random();
-        let public_key = PublicKey::from(&ephemeral_secret);
-        Self {
-            ephemeral_secret,
-            public_key,
-        }
+        let secret = x25519::EphemeralSecret::generate();
+        let public_key = secret.public_key();
+        Self { secret, public_key }
     }
 
     pub fn public_key(&self) -> &PublicKey {
@@ -323,10 +1036,7 @@ impl DiffieHellman {
     }
 
     pub fn exchange(self, their_public: &PublicKey) -> [u8; 32] {
-        *self
-            .ephemeral_secret
-            .diffie_hellman(their_public)
-            .as_bytes()
+        *self.secret.diffie_hellman(their_public).as_bytes()
     }
 }
 
@@ -343,3 +1053,424 @@ pub trait ManageKey: Sized + Zeroize {
     fn xor(&self, other: &Self) -> Result<Self, KeyManagementError>;
     fn to_key_and_derived(&self) -> KeyAndDerived;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kms_with_keys() -> KeyManagementSystem {
+        let kms = KeyManagementSystem::new();
+        for (name, version) in [(b"alpha", b"v1"), (b"alpha", b"v2"), (b"omega", b"v1")] {
+            kms.add_key(
+                Sha256::new(name),
+                Sha256::new(version),
+                KeyAndDerived::new(Key::generate(32).unwrap()),
+            )
+            .unwrap();
+        }
+        kms
+    }
+
+    #[test]
+    fn test_signed_manifest() {
+        let kms = kms_with_keys();
+        let signer = Ed25519::new();
+        let manifest = kms.signed_manifest(&signer).unwrap();
+
+        assert_eq!(manifest.key_count, 2);
+        assert_eq!(manifest.version_count, 3);
+        assert!(manifest.verify_manifest(&signer.verifying_key()).is_ok());
+        assert!(manifest
+            .verify_manifest(&Ed25519::new().verifying_key())
+            .is_err());
+
+        let mut tampered = manifest.clone();
+        tampered.entries[0].versions.pop();
+        assert!(tampered.verify_manifest(&signer.verifying_key()).is_err());
+    }
+
+    #[test]
+    fn test_from_shared_secret_labels() {
+        let alice = DiffieHellman::new();
+        let bob = DiffieHellman::new();
+        let alice_public = *alice.public_key();
+        let shared = alice.exchange(bob.public_key());
+        assert_eq!(shared, bob.exchange(&alice_public));
+
+        let salt = b"handshake-salt";
+        let enc = DerivedKey::from_shared_secret(&shared, Some(salt), b"enc", 32);
+        let mac = DerivedKey::from_shared_secret(&shared, Some(salt), b"mac", 32);
+        assert_eq!(enc.len(), 32);
+        assert_ne!(enc, mac);
+        assert_eq!(
+            enc,
+            DerivedKey::from_shared_secret(&shared, Some(salt), b"enc", 32)
+        );
+    }
+
+    #[test]
+    fn test_encrypted_export_roundtrip() {
+        let kms = kms_with_keys();
+        let (name, version) = (Sha256::new(b"alpha"), Sha256::new(b"v1"));
+        let mut entry = kms.get_key(&name, &version).unwrap().unwrap();
+        entry.rederive_standard(&[Purpose::Encryption, Purpose::Mac]);
+        kms.add_key(name, version, entry.clone()).unwrap();
+
+        let master = Key::generate(32).unwrap();
+        let exported = kms.export_encrypted(&master).unwrap();
+        assert!(!exported
+            .windows(32)
+            .any(|window| window == entry.key().as_bytes()));
+
+        let imported = KeyManagementSystem::import_encrypted(&exported, &master).unwrap();
+        let mut expected = kms.list_keys().unwrap();
+        let mut restored = imported.list_keys().unwrap();
+        expected.sort();
+        restored.sort();
+        assert_eq!(restored, expected);
+        let roundtripped = imported.get_key(&name, &version).unwrap().unwrap();
+        assert!(roundtripped.key().ct_eq(entry.key()));
+        assert_eq!(roundtripped.created_at(), entry.created_at());
+        assert_eq!(
+            roundtripped.get_purpose_key(Purpose::Mac),
+            entry.get_purpose_key(Purpose::Mac)
+        );
+
+        assert!(matches!(
+            KeyManagementSystem::import_encrypted(&exported, &Key::generate(32).unwrap()),
+            Err(KeyManagementError::DecryptionFailed)
+        ));
+        assert!(matches!(
+            KeyManagementSystem::import_encrypted(&exported[..exported.len() - 1], &master),
+            Err(KeyManagementError::DecryptionFailed)
+        ));
+        let corrupt = sealing_cipher(&master, EXPORT_KEY_INFO)
+            .encrypt(&[EXPORT_FORMAT, 0], None)
+            .unwrap();
+        assert!(matches!(
+            KeyManagementSystem::import_encrypted(&corrupt, &master),
+            Err(KeyManagementError::MalformedExport)
+        ));
+
+        let key = Key::generate(32).unwrap();
+        let mut format_1 = vec![EXPORT_FORMAT_WITHOUT_EXPIRY];
+        format_1.extend_from_slice(&1u64.to_be_bytes());
+        format_1.extend_from_slice(name.as_bytes());
+        format_1.extend_from_slice(&1u64.to_be_bytes());
+        format_1.extend_from_slice(version.as_bytes());
+        format_1.extend_from_slice(&1_700_000_000u64.to_be_bytes());
+        format_1.extend_from_slice(&0u32.to_be_bytes());
+        format_1.extend_from_slice(&32u32.to_be_bytes());
+        format_1.extend_from_slice(key.as_bytes());
+        format_1.extend_from_slice(&0u64.to_be_bytes());
+        let sealed = sealing_cipher(&master, EXPORT_KEY_INFO)
+            .encrypt(&format_1, None)
+            .unwrap();
+        let imported = KeyManagementSystem::import_encrypted(&sealed, &master).unwrap();
+        let legacy = imported.get_key(&name, &version).unwrap().unwrap();
+        assert!(legacy.key().ct_eq(&key));
+        assert_eq!(legacy.expires_at(), None);
+        assert_eq!(
+            legacy.created_at(),
+            UNIX_EPOCH + Duration::from_secs(1_700_000_000)
+        );
+    }
+
+    #[test]
+    fn test_envelope_encryption() {
+        let kms = kms_with_keys();
+        let (name, version) = (Sha256::new(b"alpha"), Sha256::new(b"v1"));
+        let envelope = kms
+            .encrypt_envelope(&name, &version, b"payload for the envelope")
+            .unwrap();
+        assert!(!envelope
+            .ciphertext
+            .windows(7)
+            .any(|window| window == b"payload"));
+        let restored: EnvelopeCiphertext =
+            serde_json::from_str(&serde_json::to_string(&envelope).unwrap()).unwrap();
+        assert_eq!(
+            kms.decrypt_envelope(&restored).unwrap(),
+            b"payload for the envelope"
+        );
+
+        let other = EnvelopeCiphertext {
+            master_version: Sha256::new(b"v2"),
+            ..envelope.clone()
+        };
+        assert!(matches!(
+            kms.decrypt_envelope(&other),
+            Err(KeyManagementError::DecryptionFailed)
+        ));
+        assert!(matches!(
+            kms.encrypt_envelope(&name, &Sha256::new(b"v9"), b"payload"),
+            Err(KeyManagementError::KeyNotFound)
+        ));
+        kms.rotate_key_versioned(&name).unwrap();
+        assert!(kms.decrypt_envelope(&envelope).is_ok());
+    }
+
+    #[test]
+    fn test_audit_log() {
+        let kms = KeyManagementSystem::new();
+        kms.set_audit_capacity(3);
+        let sunk = Arc::new(Mutex::new(Vec::new()));
+        let sink = Arc::clone(&sunk);
+        kms.set_audit_sink(Some(Arc::new(move |entry: &AuditEntry| {
+            sink.lock().unwrap().push(entry.clone())
+        })));
+
+        let (name, version) = (Sha256::new(b"alpha"), Sha256::new(b"v1"));
+        let key = Key::generate(32).unwrap();
+        kms.add_key(name, version, KeyAndDerived::new(key.clone()))
+            .unwrap();
+        kms.get_key(&name, &version).unwrap();
+        kms.rotate_keys().unwrap();
+        kms.remove_key(&name, &version).unwrap();
+
+        let operations: Vec<AuditOperation> =
+            sunk.lock().unwrap().iter().map(|e| e.operation).collect();
+        assert_eq!(
+            operations,
+            [
+                AuditOperation::AddKey,
+                AuditOperation::GetKey,
+                AuditOperation::RotateKeys,
+                AuditOperation::RemoveKey
+            ]
+        );
+        let log = kms.audit_log();
+        assert_eq!(log.as_slice(), &sunk.lock().unwrap()[1..]);
+        assert!(log.iter().all(|e| e.name == name && e.version == version));
+        assert!(log.windows(2).all(|w| w[0].timestamp <= w[1].timestamp));
+        let serialized = serde_json::to_string(&log).unwrap();
+        assert!(!serialized.contains(&key.encode_hex::<String>()));
+
+        kms.set_audit_capacity(0);
+        assert!(kms.audit_log().is_empty());
+    }
+
+    #[test]
+    fn test_key_expiry() {
+        let kms = kms_with_keys();
+        let name = Sha256::new(b"alpha");
+        let (expired, live) = (Sha256::new(b"expired"), Sha256::new(b"live"));
+        let past = SystemTime::now() - Duration::from_secs(1);
+        let future = SystemTime::now() + Duration::from_secs(3600);
+        for (version, expires_at) in [(expired, past), (live, future)] {
+            let entry = KeyAndDerived::new(Key::generate(32).unwrap());
+            kms.add_key_with_expiry(name, version, entry, expires_at)
+                .unwrap();
+        }
+        let doomed = Sha256::new(b"doomed");
+        let entry = KeyAndDerived::new(Key::generate(32).unwrap());
+        kms.add_key_with_expiry(doomed, expired, entry, past)
+            .unwrap();
+
+        assert!(matches!(
+            kms.get_key(&name, &expired),
+            Err(KeyManagementError::KeyExpired)
+        ));
+        let live_entry = kms.get_key(&name, &live).unwrap().unwrap();
+        assert_eq!(live_entry.expires_at(), Some(future));
+
+        let master = Key::generate(32).unwrap();
+        let imported =
+            KeyManagementSystem::import_encrypted(&kms.export_encrypted(&master).unwrap(), &master)
+                .unwrap();
+        assert!(matches!(
+            imported.get_key(&name, &expired),
+            Err(KeyManagementError::KeyExpired)
+        ));
+
+        assert_eq!(kms.evict_expired().unwrap(), 2);
+        assert!(kms.get_key(&name, &expired).unwrap().is_none());
+        assert!(kms.get_key(&name, &live).unwrap().is_some());
+        assert!(kms
+            .list_keys()
+            .unwrap()
+            .iter()
+            .all(|(key, _)| *key != doomed));
+        assert_eq!(kms.evict_expired().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_rotate_key_versioned() {
+        let kms = kms_with_keys();
+        let name = Sha256::new(b"omega");
+        let original = Sha256::new(b"v1");
+        let old_key = kms.get_key(&name, &original).unwrap().unwrap();
+        assert_eq!(kms.latest_version(&name).unwrap(), Some(original));
+
+        let first = kms.rotate_key_versioned(&name).unwrap();
+        let second = kms.rotate_key_versioned(&name).unwrap();
+        assert_ne!(first, second);
+        assert_eq!(kms.latest_version(&name).unwrap(), Some(second));
+
+        let retained = kms.get_key(&name, &original).unwrap().unwrap();
+        assert!(retained.key().ct_eq(old_key.key()));
+        let newest = kms.get_key(&name, &second).unwrap().unwrap();
+        assert!(!newest.key().ct_eq(old_key.key()));
+        assert_eq!(newest.key().len(), old_key.key().len());
+        assert!(newest.created_at() > retained.created_at());
+
+        assert!(matches!(
+            kms.rotate_key_versioned(&Sha256::new(b"missing")),
+            Err(KeyManagementError::KeyNotFound)
+        ));
+        assert_eq!(kms.latest_version(&Sha256::new(b"missing")).unwrap(), None);
+    }
+
+    #[test]
+    fn test_adopt_derived() {
+        let kms = kms_with_keys();
+        let parent = kms
+            .get_key(&Sha256::new(b"alpha"), &Sha256::new(b"v1"))
+            .unwrap()
+            .unwrap();
+        let derived = parent.key().derive(None, b"asphaleia/tenant", 32);
+        let before = SystemTime::now();
+
+        let (name, version) = (Sha256::new(b"tenant"), Sha256::new(b"v1"));
+        kms.adopt_derived(name, version, &derived).unwrap();
+        let adopted = kms.get_key(&name, &version).unwrap().unwrap();
+        assert_eq!(adopted.key().as_bytes(), derived.as_bytes());
+        assert!(adopted.created_at() >= before);
+        assert!(kms.list_keys().unwrap().contains(&(name, vec![version])));
+    }
+
+    #[test]
+    fn test_password_kdfs() {
+        let kdfs = [
+            PasswordKdf::Argon2id {
+                memory_kib: 64,
+                iterations: 1,
+                parallelism: 1,
+            },
+            PasswordKdf::Scrypt {
+                log_n: 4,
+                r: 8,
+                p: 1,
+            },
+            PasswordKdf::Pbkdf2HmacSha256 { iterations: 1000 },
+        ];
+        let derived: Vec<Key> = kdfs
+            .iter()
+            .map(|&kdf| {
+                let params = PasswordKeyParams {
+                    kdf,
+                    salt: b"fixed salt value".to_vec(),
+                    key_size: 32,
+                };
+                let key = Key::from_password(b"correct horse", &params).unwrap();
+                let recorded: PasswordKeyParams =
+                    serde_json::from_str(&serde_json::to_string(&params).unwrap()).unwrap();
+                assert_eq!(
+                    Key::from_password(b"correct horse", &recorded).unwrap(),
+                    key
+                );
+                assert_ne!(Key::from_password(b"battery staple", &params).unwrap(), key);
+                assert_eq!(key.len(), 32);
+                key
+            })
+            .collect();
+        assert_ne!(derived[0], derived[1]);
+        assert_ne!(derived[1], derived[2]);
+        assert_ne!(derived[0], derived[2]);
+
+        let pbkdf2 = Key::from_password(
+            b"password",
+            &PasswordKeyParams {
+                kdf: PasswordKdf::Pbkdf2HmacSha256 { iterations: 0 },
+                salt: b"salt".to_vec(),
+                key_size: 32,
+            },
+        );
+        assert!(matches!(
+            pbkdf2,
+            Err(KeyManagementError::KeyDerivationFailed(_))
+        ));
+        let generated = PasswordKeyParams::generate(PasswordKdf::default(), 32).unwrap();
+        assert_eq!(generated.salt.len(), 16);
+    }
+
+    #[test]
+    fn test_derive_and_verify() {
+        use argon2::password_hash::{PasswordHasher, SaltString};
+
+        let salt = SaltString::encode_b64(b"fixed salt value").unwrap();
+        let params = argon2::Params::new(64, 1, 1, None).unwrap();
+        let phc = Argon2::new(argon2::Algorithm::Argon2id, argon2::Version::V0x13, params)
+            .hash_password(b"correct horse", &salt)
+            .unwrap()
+            .to_string();
+
+        assert!(Key::derive_and_verify(b"correct horse", &phc).unwrap());
+        assert!(!Key::derive_and_verify(b"battery staple", &phc).unwrap());
+        assert!(matches!(
+            Key::derive_and_verify(b"correct horse", "not a phc string"),
+            Err(KeyManagementError::InvalidPasswordHash(_))
+        ));
+    }
+
+    #[test]
+    fn test_derive_labeled() {
+        let key = Key::generate(32).unwrap();
+        let mut entry = KeyAndDerived::new(key.clone());
+        let encryption = entry.derive_labeled("encryption", 32).clone();
+        let auth = entry.derive_labeled("auth", 16).clone();
+
+        assert_eq!(encryption, key.derive(None, b"encryption", 32));
+        assert_eq!(auth.len(), 16);
+        assert_eq!(entry.get_derived_by_label("encryption"), Some(&encryption));
+        assert_eq!(entry.get_derived_key(&Sha256::new(b"auth")), Some(&auth));
+        assert!(entry.get_derived_by_label("signing").is_none());
+
+        assert_eq!(entry.derive_labeled("encryption", 32), &encryption);
+        entry.rederive_standard(&[Purpose::Mac]);
+        assert_eq!(
+            entry.get_purpose_key(Purpose::Mac),
+            entry.get_derived_by_label(Purpose::Mac.label())
+        );
+    }
+
+    #[test]
+    fn test_rederive_standard_after_import() {
+        let purposes = [Purpose::Encryption, Purpose::Mac];
+        let mut original = KeyAndDerived::new(Key::generate(32).unwrap());
+        original.rederive_standard(&purposes);
+
+        let exported: String = original.key().encode_hex();
+        let mut imported = KeyAndDerived::new(Key::from_hex(exported).unwrap());
+        assert!(imported.get_purpose_key(Purpose::Encryption).is_none());
+        imported.rederive_standard(&purposes);
+
+        for purpose in purposes {
+            assert_eq!(
+                imported.get_purpose_key(purpose),
+                original.get_purpose_key(purpose)
+            );
+            assert_eq!(
+                imported.get_derived_key(&purpose.name()).unwrap(),
+                &original.key().derive(None, purpose.label().as_bytes(), 32)
+            );
+        }
+        assert_ne!(
+            imported.get_purpose_key(Purpose::Encryption),
+            imported.get_purpose_key(Purpose::Mac)
+        );
+        assert!(imported.get_purpose_key(Purpose::Signing).is_none());
+    }
+
+    #[test]
+    fn test_key_ct_eq() {
+        let key = Key::generate(32).unwrap();
+        assert!(key.ct_eq(&key.clone()));
+        assert!(!key.ct_eq(&key.rotate_key()));
+        assert!(!key.ct_eq(&Key::generate(16).unwrap()));
+
+        let derived = key.derive(None, b"info", 32);
+        assert!(derived.ct_eq(&key.derive(None, b"info", 32)));
+        assert!(!derived.ct_eq(&key.derive(None, b"other", 32)));
+    }
+}
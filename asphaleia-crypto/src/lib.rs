@@ -3,15 +3,25 @@ pub mod ed25519;
 pub mod encrypt;
 pub mod hash;
 pub mod keys;
+pub mod secret_sharing;
+pub mod tls;
+#[cfg(feature = "tokio")]
+pub mod tls_tokio;
+pub mod x25519;
 pub mod zksnarks;
 
+pub use aes_gcm;
 pub use argon2;
 pub use certificate::*;
 pub use ed25519::*;
+pub use ed25519_dalek;
 pub use encrypt::*;
 pub use hash::*;
 pub use keys::*;
 pub use ring;
+pub use rustls;
+pub use tls::*;
+pub use x25519::*;
 
 #[cfg(feature = "kyber")]
 pub use pqc_kyber;
@@ -0,0 +1,222 @@
+use super::keys::{DerivedKey, Key, KeyManagementError, ManageKey};
+use ring::rand::{SecureRandom, SystemRandom};
+use zeroize::{Zeroize, Zeroizing};
+
+// One point of every byte's polynomial. `index` is the x coordinate and is never zero;
+// `threshold` travels with the share so `combine` can tell when too few were supplied.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Share {
+    index: u8,
+    threshold: u8,
+    value: Vec<u8>,
+}
+
+impl Share {
+    pub fn index(&self) -> u8 {
+        self.index
+    }
+
+    pub fn threshold(&self) -> u8 {
+        self.threshold
+    }
+
+    pub fn value(&self) -> &[u8] {
+        &self.value
+    }
+
+    // Layout: index, threshold, then the share bytes.
+    pub fn to_bytes(&self) -> Zeroizing<Vec<u8>> {
+        let mut bytes = Zeroizing::new(Vec::with_capacity(2 + self.value.len()));
+        bytes.push(self.index);
+        bytes.push(self.threshold);
+        bytes.extend_from_slice(&self.value);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, KeyManagementError> {
+        match bytes {
+            [index, threshold, value @ ..]
+                if *index != 0 && *threshold != 0 && !value.is_empty() =>
+            {
+                Ok(Self {
+                    index: *index,
+                    threshold: *threshold,
+                    value: value.to_vec(),
+                })
+            }
+            _ => Err(KeyManagementError::InvalidShares),
+        }
+    }
+}
+
+impl Zeroize for Share {
+    fn zeroize(&mut self) {
+        self.index.zeroize();
+        self.threshold.zeroize();
+        self.value.zeroize();
+    }
+}
+
+impl Drop for Share {
+    fn drop(&mut self) {
+        self.zeroize();
+    }
+}
+
+// Shamir's scheme over GF(256), byte by byte: any `threshold` of the `shares` returned
+// rebuild the key, fewer reveal nothing about it.
+pub fn split(key: &Key, threshold: u8, shares: u8) -> Result<Vec<Share>, KeyManagementError> {
+    if threshold == 0 || threshold > shares {
+        return Err(KeyManagementError::InvalidThreshold { threshold, shares });
+    }
+    if key.is_empty() {
+        return Err(KeyManagementError::InvalidKeyLength);
+    }
+    // Coefficients for x^1..x^(threshold - 1); the constant term is the key byte itself.
+    let degree = threshold as usize - 1;
+    let mut coefficients = Zeroizing::new(vec![0u8; degree * key.len()]);
+    SystemRandom::new()
+        .fill(&mut coefficients)
+        .map_err(|_| KeyManagementError::RandomGenerationFailed)?;
+
+    Ok((1..=shares)
+        .map(|x| {
+            let value = key
+                .as_bytes()
+                .iter()
+                .enumerate()
+                .map(|(i, &secret)| {
+                    // Horner's rule, highest coefficient first.
+                    let high = coefficients[i * degree..(i + 1) * degree]
+                        .iter()
+                        .rev()
+                        .fold(0, |acc, &c| gf_mul(acc, x) ^ c);
+                    gf_mul(high, x) ^ secret
+                })
+                .collect();
+            Share {
+                index: x,
+                threshold,
+                value,
+            }
+        })
+        .collect())
+}
+
+// Interpolates at x = 0 from the first `threshold` shares; any extra shares are ignored.
+pub fn combine(shares: &[Share]) -> Result<Key, KeyManagementError> {
+    let first = shares
+        .first()
+        .ok_or(KeyManagementError::NotEnoughShares { needed: 1, got: 0 })?;
+    let needed = first.threshold as usize;
+    if shares.len() < needed {
+        return Err(KeyManagementError::NotEnoughShares {
+            needed,
+            got: shares.len(),
+        });
+    }
+    let used = &shares[..needed];
+    for (i, share) in used.iter().enumerate() {
+        if share.index == 0
+            || share.threshold != first.threshold
+            || share.value.len() != first.value.len()
+            || used[..i].iter().any(|other| other.index == share.index)
+        {
+            return Err(KeyManagementError::InvalidShares);
+        }
+    }
+
+    let mut secret = Zeroizing::new(vec![0u8; first.value.len()]);
+    for share in used {
+        // Lagrange basis at zero: the product of x_j / (x_j - x_i), where - is xor.
+        let basis = used
+            .iter()
+            .filter(|other| other.index != share.index)
+            .fold(1, |acc, other| {
+                gf_mul(acc, gf_mul(other.index, gf_inv(other.index ^ share.index)))
+            });
+        for (byte, &y) in secret.iter_mut().zip(&share.value) {
+            *byte ^= gf_mul(y, basis);
+        }
+    }
+    Ok(DerivedKey::new(secret.to_vec()).to_key())
+}
+
+// Multiplication modulo x^8 + x^4 + x^3 + x + 1 without tables or data-dependent
+// branches, since the operands are secret.
+fn gf_mul(mut a: u8, mut b: u8) -> u8 {
+    let mut product = 0;
+    for _ in 0..8 {
+        product ^= a & (b & 1).wrapping_neg();
+        let carry = (a >> 7).wrapping_neg();
+        a = (a << 1) ^ (carry & 0x1b);
+        b >>= 1;
+    }
+    product
+}
+
+// a^254 = a^-1 in GF(256).
+fn gf_inv(a: u8) -> u8 {
+    let mut result = 1;
+    let mut base = a;
+    let mut exponent = 254u8;
+    while exponent > 0 {
+        if exponent & 1 == 1 {
+            result = gf_mul(result, base);
+        }
+        base = gf_mul(base, base);
+        exponent >>= 1;
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_and_combine() {
+        let key = Key::generate(32).unwrap();
+        let shares = split(&key, 3, 5).unwrap();
+        assert_eq!(shares.len(), 5);
+        assert!(shares.iter().all(|share| share.value() != key.as_bytes()));
+
+        for subset in [[0, 1, 2], [4, 2, 0], [1, 3, 4]] {
+            let picked: Vec<Share> = subset.iter().map(|&i| shares[i].clone()).collect();
+            assert_eq!(combine(&picked).unwrap(), key);
+        }
+        assert_eq!(combine(&shares).unwrap(), key);
+
+        let restored: Vec<Share> = shares[2..]
+            .iter()
+            .map(|share| Share::from_bytes(&share.to_bytes()).unwrap())
+            .collect();
+        assert_eq!(combine(&restored).unwrap(), key);
+
+        assert!(matches!(
+            combine(&shares[..2]),
+            Err(KeyManagementError::NotEnoughShares { needed: 3, got: 2 })
+        ));
+        let duplicated = [shares[0].clone(), shares[0].clone(), shares[1].clone()];
+        assert!(matches!(
+            combine(&duplicated),
+            Err(KeyManagementError::InvalidShares)
+        ));
+        assert!(matches!(
+            split(&key, 4, 3),
+            Err(KeyManagementError::InvalidThreshold {
+                threshold: 4,
+                shares: 3
+            })
+        ));
+        assert!(matches!(
+            Share::from_bytes(&[0, 3, 1]),
+            Err(KeyManagementError::InvalidShares)
+        ));
+    }
+
+    #[test]
+    fn test_gf_inverse() {
+        assert!((1..=255u8).all(|a| gf_mul(a, gf_inv(a)) == 1));
+    }
+}
@@ -0,0 +1,686 @@
+use super::certificate::{der_fingerprint, CertifiedKey};
+use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+use rustls::{
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::{
+        ring::{default_provider, sign::any_supported_type},
+        verify_tls12_signature, verify_tls13_signature, WebPkiSupportedAlgorithms,
+    },
+    pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, ServerName, UnixTime},
+    server::{
+        danger::{ClientCertVerified, ClientCertVerifier},
+        ClientHello, ResolvesServerCert, ResolvesServerCertUsingSni,
+    },
+    sign, CertificateError, ClientConfig, DigitallySignedStruct, DistinguishedName, Error,
+    RootCertStore, ServerConfig, SignatureScheme,
+};
+use std::{sync::Arc, time::SystemTime};
+use subtle::ConstantTimeEq;
+use webpki::{EndEntityCert, KeyUsage, ALL_VERIFICATION_ALGS};
+use x509_parser::{certificate::X509Certificate, oid_registry::OID_SIG_ED25519, prelude::FromDer};
+
+// Trusts a peer iff the Ed25519 key in its end-entity certificate is allowlisted.
+// There is no CA: chains, names and validity periods are deliberately not checked.
+#[derive(Debug, Clone)]
+pub struct Ed25519AllowlistVerifier {
+    allowed: Vec<VerifyingKey>,
+}
+
+impl Ed25519AllowlistVerifier {
+    pub fn new(allowed: Vec<VerifyingKey>) -> Self {
+        Self { allowed }
+    }
+
+    pub fn allow(&mut self, key: VerifyingKey) {
+        if !self.allowed.contains(&key) {
+            self.allowed.push(key);
+        }
+    }
+
+    pub fn is_allowed(&self, key: &VerifyingKey) -> bool {
+        self.allowed.contains(key)
+    }
+
+    fn verify_peer(&self, end_entity: &CertificateDer<'_>) -> Result<(), Error> {
+        let key = certificate_ed25519_key(end_entity)?;
+        if self.is_allowed(&key) {
+            Ok(())
+        } else {
+            Err(Error::InvalidCertificate(
+                CertificateError::ApplicationVerificationFailure,
+            ))
+        }
+    }
+
+    fn verify_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        if dss.scheme != SignatureScheme::ED25519 {
+            return Err(Error::InvalidCertificate(CertificateError::BadSignature));
+        }
+        let key = certificate_ed25519_key(cert)?;
+        let signature = Signature::from_slice(dss.signature())
+            .map_err(|_| Error::InvalidCertificate(CertificateError::BadSignature))?;
+        key.verify(message, &signature)
+            .map_err(|_| Error::InvalidCertificate(CertificateError::BadSignature))?;
+        Ok(HandshakeSignatureValid::assertion())
+    }
+}
+
+// Trusts a server iff the SHA-256 fingerprint of its end-entity certificate (see
+// `Certificate::sha256_fingerprint`) is the pin. Roots, chains, names and validity periods
+// are ignored; handshake signatures are still checked against the pinned certificate.
+#[derive(Debug, Clone)]
+pub struct PinnedCertVerifier {
+    fingerprint: [u8; 32],
+    algorithms: WebPkiSupportedAlgorithms,
+}
+
+impl PinnedCertVerifier {
+    pub fn new(fingerprint: [u8; 32]) -> Self {
+        Self {
+            fingerprint,
+            algorithms: default_provider().signature_verification_algorithms,
+        }
+    }
+
+    pub fn fingerprint(&self) -> &[u8; 32] {
+        &self.fingerprint
+    }
+}
+
+pub fn certificate_ed25519_key(cert: &CertificateDer<'_>) -> Result<VerifyingKey, Error> {
+    let (_, parsed) = X509Certificate::from_der(cert.as_ref())
+        .map_err(|_| Error::InvalidCertificate(CertificateError::BadEncoding))?;
+    let public_key = parsed.public_key();
+    if public_key.algorithm.algorithm != OID_SIG_ED25519 {
+        return Err(Error::InvalidCertificate(CertificateError::BadEncoding));
+    }
+    let bytes: &[u8; 32] = public_key
+        .subject_public_key
+        .data
+        .as_ref()
+        .try_into()
+        .map_err(|_| Error::InvalidCertificate(CertificateError::BadEncoding))?;
+    VerifyingKey::from_bytes(bytes)
+        .map_err(|_| Error::InvalidCertificate(CertificateError::BadEncoding))
+}
+
+// Converts a generated certificate and its key into the form rustls serves.
+pub fn rustls_certified_key(key: &CertifiedKey) -> Result<sign::CertifiedKey, Error> {
+    let private_key =
+        PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(key.key_pair().serialize_der()));
+    Ok(sign::CertifiedKey::new(
+        vec![CertificateDer::from(key.certificate().to_der())],
+        any_supported_type(&private_key)?,
+    ))
+}
+
+// Selects a certificate by SNI like `ResolvesServerCertUsingSni`, but answers clients
+// that send no SNI, or one with no certificate, with the default instead of failing.
+#[derive(Debug)]
+pub struct TLSCertResolver {
+    by_name: ResolvesServerCertUsingSni,
+    // `ResolvesServerCertUsingSni` can't list its names.
+    names: Vec<String>,
+    default: Option<Arc<sign::CertifiedKey>>,
+}
+
+impl Default for TLSCertResolver {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TLSCertResolver {
+    pub fn new() -> Self {
+        Self {
+            by_name: ResolvesServerCertUsingSni::new(),
+            names: Vec::new(),
+            default: None,
+        }
+    }
+
+    // Fails if the certificate is not valid for `name`.
+    pub fn add(&mut self, name: &str, key: sign::CertifiedKey) -> Result<(), Error> {
+        self.by_name.add(name, key)?;
+        if !self.names.iter().any(|known| known == name) {
+            self.names.push(name.to_string());
+        }
+        Ok(())
+    }
+
+    pub fn add_certified_key(&mut self, name: &str, key: &CertifiedKey) -> Result<(), Error> {
+        self.add(name, rustls_certified_key(key)?)
+    }
+
+    // The SNI names with a certificate, in the order they were first added.
+    pub fn names(&self) -> Vec<String> {
+        self.names.clone()
+    }
+
+    pub fn set_default(&mut self, key: Option<Arc<sign::CertifiedKey>>) {
+        self.default = key;
+    }
+}
+
+// The client and server halves of one TLS setup, kept together so settings that both
+// sides must agree on are applied to both.
+#[derive(Debug, Clone)]
+pub struct TLSConfig {
+    client: ClientConfig,
+    server: ServerConfig,
+}
+
+impl TLSConfig {
+    pub fn new(client: ClientConfig, server: ServerConfig) -> Self {
+        Self { client, server }
+    }
+
+    // In order of preference. The server picks the first of its protocols the client also
+    // offers and fails the handshake if there is none; an empty list disables ALPN.
+    pub fn with_alpn_protocols(&mut self, protocols: Vec<Vec<u8>>) -> &mut Self {
+        self.server.alpn_protocols = protocols.clone();
+        self.client.alpn_protocols = protocols;
+        self
+    }
+
+    // Replaces the client's certificate verifier with a `PinnedCertVerifier`.
+    pub fn with_pinned_server_cert(&mut self, fingerprint: [u8; 32]) -> &mut Self {
+        self.client
+            .dangerous()
+            .set_certificate_verifier(Arc::new(PinnedCertVerifier::new(fingerprint)));
+        self
+    }
+
+    pub fn client_config(&self) -> Arc<ClientConfig> {
+        Arc::new(self.client.clone())
+    }
+
+    pub fn server_config(&self) -> Arc<ServerConfig> {
+        Arc::new(self.server.clone())
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum VerifyError {
+    #[error("Malformed certificate: {0}")]
+    Malformed(webpki::Error),
+    #[error("Certificate chain rejected: {0}")]
+    Rejected(webpki::Error),
+    #[error("Verification time is before the Unix epoch")]
+    InvalidTime,
+}
+
+// Trust anchors for `verify_chain`, also usable as the root store of a rustls config.
+#[derive(Debug, Clone)]
+pub struct TLSCertStore {
+    roots: RootCertStore,
+}
+
+impl Default for TLSCertStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TLSCertStore {
+    pub fn new() -> Self {
+        Self {
+            roots: RootCertStore::empty(),
+        }
+    }
+
+    pub fn add(&mut self, root: CertificateDer<'_>) -> Result<(), VerifyError> {
+        let anchor = webpki::anchor_from_trusted_cert(&root).map_err(VerifyError::Malformed)?;
+        self.roots.roots.push(anchor.to_owned());
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.roots.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.roots.is_empty()
+    }
+
+    pub fn root_store(&self) -> &RootCertStore {
+        &self.roots
+    }
+}
+
+// Checks that `leaf` chains to one of `roots` through `intermediates` and that every
+// certificate on the path is valid at `now`, for use as a TLS server certificate. The
+// leaf's names are not checked against anything.
+pub fn verify_chain(
+    leaf: &CertificateDer<'_>,
+    intermediates: &[CertificateDer<'_>],
+    roots: &TLSCertStore,
+    now: SystemTime,
+) -> Result<(), VerifyError> {
+    let since_epoch = now
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_err(|_| VerifyError::InvalidTime)?;
+    let cert = EndEntityCert::try_from(leaf).map_err(VerifyError::Malformed)?;
+    cert.verify_for_usage(
+        ALL_VERIFICATION_ALGS,
+        &roots.roots.roots,
+        intermediates,
+        UnixTime::since_unix_epoch(since_epoch),
+        KeyUsage::server_auth(),
+        None,
+        None,
+    )
+    .map_err(VerifyError::Rejected)?;
+    Ok(())
+}
+
+impl ResolvesServerCert for TLSCertResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<sign::CertifiedKey>> {
+        self.by_name
+            .resolve(client_hello)
+            .or_else(|| self.default.clone())
+    }
+}
+
+impl ServerCertVerifier for Ed25519AllowlistVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, Error> {
+        self.verify_peer(end_entity)?;
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        self.verify_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        self.verify_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        vec![SignatureScheme::ED25519]
+    }
+}
+
+impl ServerCertVerifier for PinnedCertVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, Error> {
+        let presented = der_fingerprint(end_entity.as_ref());
+        if bool::from(presented.ct_eq(&self.fingerprint)) {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(Error::InvalidCertificate(
+                CertificateError::ApplicationVerificationFailure,
+            ))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
+}
+
+impl ClientCertVerifier for Ed25519AllowlistVerifier {
+    fn root_hint_subjects(&self) -> &[DistinguishedName] {
+        &[]
+    }
+
+    fn verify_client_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _now: UnixTime,
+    ) -> Result<ClientCertVerified, Error> {
+        self.verify_peer(end_entity)?;
+        Ok(ClientCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        self.verify_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        self.verify_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        vec![SignatureScheme::ED25519]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::certificate::{Certificate, CertificateBuilder};
+    use rcgen::KeyPair;
+    use rustls::{ClientConnection, ServerConnection};
+    use std::time::Duration;
+
+    fn peer(name: &str) -> (CertificateDer<'static>, VerifyingKey) {
+        let peer = CertifiedKey::new_ed25519(name).unwrap();
+        let der = CertificateDer::from(peer.certificate().to_der());
+        let key = certificate_ed25519_key(&der).unwrap();
+        assert_eq!(key.as_bytes().as_slice(), peer.key_pair().public_key_raw());
+        (der, key)
+    }
+
+    #[test]
+    fn test_allowlist_verifier() {
+        let (trusted, trusted_key) = peer("trusted.peer");
+        let (stranger, _) = peer("stranger.peer");
+        let verifier = Ed25519AllowlistVerifier::new(vec![trusted_key]);
+        let name = ServerName::try_from("trusted.peer").unwrap();
+        let now = UnixTime::now();
+
+        assert!(verifier
+            .verify_server_cert(&trusted, &[], &name, &[], now)
+            .is_ok());
+        assert!(verifier.verify_client_cert(&trusted, &[], now).is_ok());
+        assert!(matches!(
+            verifier.verify_server_cert(&stranger, &[], &name, &[], now),
+            Err(Error::InvalidCertificate(
+                CertificateError::ApplicationVerificationFailure
+            ))
+        ));
+        assert!(verifier.verify_client_cert(&stranger, &[], now).is_err());
+
+        let ecdsa = CertifiedKey::new("ecdsa.peer").unwrap();
+        let ecdsa = CertificateDer::from(ecdsa.certificate().to_der());
+        assert!(verifier.verify_client_cert(&ecdsa, &[], now).is_err());
+    }
+
+    // A certificate from `builder` with its own common name, so that no two certificates in
+    // a chain share a subject.
+    fn issue(
+        name: &str,
+        builder: CertificateBuilder,
+        issuer: Option<(&Certificate, &KeyPair)>,
+    ) -> (Certificate, KeyPair) {
+        let mut params = builder.build().unwrap();
+        params.distinguished_name = rcgen::DistinguishedName::new();
+        params
+            .distinguished_name
+            .push(rcgen::DnType::CommonName, name);
+        let key = KeyPair::generate().unwrap();
+        let cert = match issuer {
+            Some((cert, issuer_key)) => Certificate::signed_by(params, &key, cert, issuer_key),
+            None => Certificate::new(params, &key),
+        };
+        (cert.unwrap(), key)
+    }
+
+    #[test]
+    fn test_verify_chain() {
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let end = start + Duration::from_secs(365 * 24 * 60 * 60);
+        let window = || {
+            CertificateBuilder::new()
+                .add_dns("chain.example.com")
+                .not_before(start)
+                .not_after(end)
+        };
+        let (root, root_key) = issue("Root", window().is_ca(true), None);
+        let (intermediate, intermediate_key) = issue(
+            "Intermediate",
+            window().is_ca(true),
+            Some((&root, &root_key)),
+        );
+        let (leaf, _) = issue("Leaf", window(), Some((&intermediate, &intermediate_key)));
+        let leaf = CertificateDer::from(leaf.to_der());
+        let intermediates = [CertificateDer::from(intermediate.to_der())];
+
+        let mut store = TLSCertStore::new();
+        store.add(CertificateDer::from(root.to_der())).unwrap();
+        assert_eq!(store.len(), 1);
+        let now = start + Duration::from_secs(60);
+        assert!(verify_chain(&leaf, &intermediates, &store, now).is_ok());
+
+        assert!(matches!(
+            verify_chain(&leaf, &[], &store, now),
+            Err(VerifyError::Rejected(webpki::Error::UnknownIssuer))
+        ));
+        assert!(matches!(
+            verify_chain(&leaf, &intermediates, &TLSCertStore::new(), now),
+            Err(VerifyError::Rejected(webpki::Error::UnknownIssuer))
+        ));
+        assert!(matches!(
+            verify_chain(&leaf, &intermediates, &store, end + Duration::from_secs(1)),
+            Err(VerifyError::Rejected(webpki::Error::CertExpired))
+        ));
+        assert!(matches!(
+            verify_chain(&CertificateDer::from(vec![0u8; 8]), &[], &store, now),
+            Err(VerifyError::Malformed(_))
+        ));
+    }
+
+    fn test_config(
+        resolver: Arc<TLSCertResolver>,
+        verifier: Arc<Ed25519AllowlistVerifier>,
+    ) -> Result<TLSConfig, Error> {
+        let provider = Arc::new(default_provider());
+        let server = ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()?
+            .with_no_client_auth()
+            .with_cert_resolver(resolver);
+        let client = ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()?
+            .dangerous()
+            .with_custom_certificate_verifier(verifier)
+            .with_no_client_auth();
+        Ok(TLSConfig::new(client, server))
+    }
+
+    // Runs an in-memory handshake and returns the client's end of it.
+    fn handshake(config: &TLSConfig, server_name: &str) -> Result<ClientConnection, Error> {
+        let name = ServerName::try_from(server_name.to_string()).unwrap();
+        let mut client = ClientConnection::new(config.client_config(), name)?;
+        let mut server = ServerConnection::new(config.server_config())?;
+
+        while client.is_handshaking() || server.is_handshaking() {
+            let mut buffer = Vec::new();
+            client.write_tls(&mut buffer).unwrap();
+            server.read_tls(&mut buffer.as_slice()).unwrap();
+            server.process_new_packets()?;
+            buffer.clear();
+            server.write_tls(&mut buffer).unwrap();
+            client.read_tls(&mut buffer.as_slice()).unwrap();
+            client.process_new_packets()?;
+        }
+        assert_eq!(client.alpn_protocol(), server.alpn_protocol());
+        Ok(client)
+    }
+
+    // The certificate the server presented.
+    fn served_certificate(
+        resolver: Arc<TLSCertResolver>,
+        verifier: Arc<Ed25519AllowlistVerifier>,
+        server_name: &str,
+    ) -> Result<CertificateDer<'static>, Error> {
+        let client = handshake(&test_config(resolver, verifier)?, server_name)?;
+        Ok(client.peer_certificates().unwrap()[0].clone().into_owned())
+    }
+
+    #[test]
+    fn test_pinned_server_cert() {
+        let pinned = CertifiedKey::new("backend.example.com").unwrap();
+        let impostor = CertifiedKey::new("backend.example.com").unwrap();
+        let fingerprint = pinned.certificate().sha256_fingerprint();
+        // Only used until the pin replaces it.
+        let allow_nobody = Arc::new(Ed25519AllowlistVerifier::new(Vec::new()));
+
+        let config_serving = |key: &CertifiedKey| {
+            let mut resolver = TLSCertResolver::new();
+            resolver.set_default(Some(Arc::new(rustls_certified_key(key).unwrap())));
+            let mut config = test_config(Arc::new(resolver), allow_nobody.clone()).unwrap();
+            config.with_pinned_server_cert(fingerprint);
+            config
+        };
+
+        let client = handshake(&config_serving(&pinned), "backend.example.com").unwrap();
+        assert_eq!(
+            der_fingerprint(client.peer_certificates().unwrap()[0].as_ref()),
+            fingerprint
+        );
+        // The name is not checked, only the pin.
+        assert!(handshake(&config_serving(&pinned), "other.example.com").is_ok());
+        assert!(matches!(
+            handshake(&config_serving(&impostor), "backend.example.com"),
+            Err(Error::InvalidCertificate(
+                CertificateError::ApplicationVerificationFailure
+            ))
+        ));
+    }
+
+    #[test]
+    fn test_alpn_negotiation() {
+        let server = CertifiedKey::new_ed25519("alpn.example.com").unwrap();
+        let server_der = CertificateDer::from(server.certificate().to_der());
+        let verifier = Arc::new(Ed25519AllowlistVerifier::new(vec![
+            certificate_ed25519_key(&server_der).unwrap(),
+        ]));
+        let mut resolver = TLSCertResolver::new();
+        resolver.set_default(Some(Arc::new(rustls_certified_key(&server).unwrap())));
+        let mut config = test_config(Arc::new(resolver), verifier).unwrap();
+
+        let client = handshake(&config, "alpn.example.com").unwrap();
+        assert_eq!(client.alpn_protocol(), None);
+
+        config.with_alpn_protocols(vec![b"h2".to_vec(), b"http/1.1".to_vec()]);
+        let client = handshake(&config, "alpn.example.com").unwrap();
+        assert_eq!(client.alpn_protocol(), Some(b"h2".as_slice()));
+
+        let mut mismatched = config.clone();
+        mismatched.client.alpn_protocols = vec![b"asphaleia/1".to_vec()];
+        assert!(matches!(
+            handshake(&mismatched, "alpn.example.com"),
+            Err(Error::NoApplicationProtocol)
+        ));
+    }
+
+    #[test]
+    fn test_sni_resolver_falls_back_to_default() {
+        let specific = CertifiedKey::new_ed25519("api.example.com").unwrap();
+        let fallback = CertifiedKey::new_ed25519("default.example.com").unwrap();
+        let (specific_der, fallback_der) = (
+            CertificateDer::from(specific.certificate().to_der()),
+            CertificateDer::from(fallback.certificate().to_der()),
+        );
+        let verifier = Arc::new(Ed25519AllowlistVerifier::new(vec![
+            certificate_ed25519_key(&specific_der).unwrap(),
+            certificate_ed25519_key(&fallback_der).unwrap(),
+        ]));
+
+        let mut resolver = TLSCertResolver::new();
+        resolver
+            .add("api.example.com", rustls_certified_key(&specific).unwrap())
+            .unwrap();
+        resolver.set_default(Some(Arc::new(rustls_certified_key(&fallback).unwrap())));
+        let resolver = Arc::new(resolver);
+
+        assert_eq!(
+            served_certificate(resolver.clone(), verifier.clone(), "api.example.com").unwrap(),
+            specific_der
+        );
+        assert_eq!(
+            served_certificate(resolver, verifier.clone(), "legacy.example.com").unwrap(),
+            fallback_der
+        );
+        assert!(served_certificate(
+            Arc::new(TLSCertResolver::new()),
+            verifier,
+            "api.example.com"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_resolver_routes_certified_keys() {
+        let hosts = ["a.example.com", "b.example.com"];
+        let keys: Vec<CertifiedKey> = hosts
+            .iter()
+            .map(|host| CertifiedKey::new_ed25519(host).unwrap())
+            .collect();
+        let ders: Vec<CertificateDer<'static>> = keys
+            .iter()
+            .map(|key| CertificateDer::from(key.certificate().to_der()))
+            .collect();
+        let verifier = Arc::new(Ed25519AllowlistVerifier::new(
+            ders.iter()
+                .map(|der| certificate_ed25519_key(der).unwrap())
+                .collect(),
+        ));
+
+        let mut resolver = TLSCertResolver::new();
+        for (host, key) in hosts.iter().zip(&keys) {
+            resolver.add_certified_key(host, key).unwrap();
+        }
+        resolver.add_certified_key(hosts[0], &keys[0]).unwrap();
+        assert!(resolver
+            .add_certified_key("c.example.com", &keys[0])
+            .is_err());
+        assert_eq!(resolver.names(), hosts);
+
+        let resolver = Arc::new(resolver);
+        for (host, der) in hosts.iter().zip(&ders) {
+            assert_eq!(
+                &served_certificate(resolver.clone(), verifier.clone(), host).unwrap(),
+                der
+            );
+        }
+    }
+}
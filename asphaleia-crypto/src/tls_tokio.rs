@@ -0,0 +1,99 @@
+use super::tls::TLSConfig;
+use rustls::pki_types::ServerName;
+use std::io;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::{client, server, TlsAcceptor, TlsConnector};
+
+// Runs the client handshake over `stream`. The result is itself `AsyncRead + AsyncWrite`;
+// `get_ref().1` is the rustls connection, e.g. for the negotiated ALPN protocol.
+pub async fn connect<IO>(
+    config: &TLSConfig,
+    stream: IO,
+    server_name: &str,
+) -> io::Result<client::TlsStream<IO>>
+where
+    IO: AsyncRead + AsyncWrite + Unpin,
+{
+    let name = ServerName::try_from(server_name.to_string())
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    TlsConnector::from(config.client_config())
+        .connect(name, stream)
+        .await
+}
+
+pub async fn accept<IO>(config: &TLSConfig, stream: IO) -> io::Result<server::TlsStream<IO>>
+where
+    IO: AsyncRead + AsyncWrite + Unpin,
+{
+    TlsAcceptor::from(config.server_config())
+        .accept(stream)
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        certificate::CertifiedKey,
+        tls::{rustls_certified_key, PinnedCertVerifier, TLSCertResolver},
+    };
+    use rustls::{crypto::ring::default_provider, ClientConfig, ServerConfig};
+    use std::sync::Arc;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    fn config(server: &CertifiedKey) -> TLSConfig {
+        let provider = Arc::new(default_provider());
+        let pin = PinnedCertVerifier::new(server.certificate().sha256_fingerprint());
+        let mut resolver = TLSCertResolver::new();
+        resolver.set_default(Some(Arc::new(rustls_certified_key(server).unwrap())));
+        let client = ClientConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(pin))
+            .with_no_client_auth();
+        let server = ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_no_client_auth()
+            .with_cert_resolver(Arc::new(resolver));
+        TLSConfig::new(client, server)
+    }
+
+    #[tokio::test]
+    async fn test_connect_and_accept() {
+        let server_key = CertifiedKey::new("stream.example.com").unwrap();
+        let mut config = config(&server_key);
+        config.with_alpn_protocols(vec![b"asphaleia/1".to_vec()]);
+        let (client_io, server_io) = tokio::io::duplex(4096);
+
+        let server = async {
+            let mut stream = accept(&config, server_io).await.unwrap();
+            let mut request = [0u8; 4];
+            stream.read_exact(&mut request).await.unwrap();
+            stream.write_all(b"pong").await.unwrap();
+            stream.shutdown().await.unwrap();
+            request
+        };
+        let client = async {
+            let mut stream = connect(&config, client_io, "stream.example.com")
+                .await
+                .unwrap();
+            assert_eq!(
+                stream.get_ref().1.alpn_protocol(),
+                Some(b"asphaleia/1".as_slice())
+            );
+            stream.write_all(b"ping").await.unwrap();
+            let mut response = Vec::new();
+            stream.read_to_end(&mut response).await.unwrap();
+            response
+        };
+        let (request, response) = tokio::join!(server, client);
+        assert_eq!(&request, b"ping");
+        assert_eq!(response, b"pong");
+
+        let (client_io, _server_io) = tokio::io::duplex(64);
+        let err = connect(&config, client_io, "not a name").await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+}
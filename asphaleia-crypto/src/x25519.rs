@@ -0,0 +1,76 @@
+use super::keys::{DerivedKey, Key};
+pub use x25519_dalek::PublicKey;
+
+// HKDF `info` for `Key::from(SharedSecret)`; use `derive` with your own label for anything
+// beyond a single key per exchange.
+pub const SHARED_KEY_INFO: &[u8] = b"asphaleia/x25519/key";
+
+// A single-use X25519 secret; `diffie_hellman` consumes it so it can't be reused.
+pub struct EphemeralSecret(x25519_dalek::EphemeralSecret);
+
+impl EphemeralSecret {
+    pub fn generate() -> Self {
+        Self(x25519_dalek::EphemeralSecret::random())
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        PublicKey::from(&self.0)
+    }
+
+    pub fn diffie_hellman(self, peer: &PublicKey) -> SharedSecret {
+        SharedSecret(self.0.diffie_hellman(peer))
+    }
+}
+
+// The raw curve output is not uniformly random; run it through `derive` before using it
+// as a key, with a distinct `info` per session key.
+pub struct SharedSecret(x25519_dalek::SharedSecret);
+
+impl SharedSecret {
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        self.0.as_bytes()
+    }
+
+    // False when the peer sent a low-order point and the output is all zeros.
+    pub fn was_contributory(&self) -> bool {
+        self.0.was_contributory()
+    }
+
+    pub fn derive(&self, salt: Option<&[u8]>, info: &[u8], output_length: usize) -> DerivedKey {
+        DerivedKey::from_shared_secret(self.as_bytes(), salt, info, output_length)
+    }
+}
+
+impl From<SharedSecret> for Key {
+    fn from(secret: SharedSecret) -> Self {
+        secret.derive(None, SHARED_KEY_INFO, 32).to_key()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keys::ManageKey;
+
+    #[test]
+    fn test_key_agreement() {
+        let alice = EphemeralSecret::generate();
+        let bob = EphemeralSecret::generate();
+        let (alice_public, bob_public) = (alice.public_key(), bob.public_key());
+
+        let alice_shared = alice.diffie_hellman(&bob_public);
+        let bob_shared = bob.diffie_hellman(&alice_public);
+        assert_eq!(alice_shared.as_bytes(), bob_shared.as_bytes());
+        assert!(alice_shared.was_contributory());
+
+        let session = alice_shared.derive(None, b"asphaleia/session", 32);
+        assert_eq!(session, bob_shared.derive(None, b"asphaleia/session", 32));
+        assert_ne!(session, bob_shared.derive(None, b"asphaleia/other", 32));
+
+        let expected = bob_shared.derive(None, SHARED_KEY_INFO, 32).to_key();
+        let raw = *bob_shared.as_bytes();
+        let key = Key::from(bob_shared);
+        assert_eq!(key.as_bytes(), expected.as_bytes());
+        assert_ne!(key.as_bytes(), &raw);
+    }
+}
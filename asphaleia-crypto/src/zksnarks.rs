@@ -1,9 +1,10 @@
+use super::hash::Sha256;
 use bellman::{
     groth16::{
         create_random_proof, generate_random_parameters, prepare_verifying_key, verify_proof,
-        Proof, VerifyingKey,
+        Parameters, PreparedVerifyingKey, Proof, VerifyingKey,
     },
-    VerificationError,
+    Index, LinearCombination, Variable, VerificationError,
 };
 use bls12_381::Bls12;
 use thiserror::Error;
@@ -25,8 +26,14 @@ pub enum ZkSnarkError {
     ProofDeserializationError(std::io::Error),
     #[error("Failed to deserialize verifying key: {0}")]
     VkDeserializationError(std::io::Error),
+    #[error("Verifying key is followed by {0} unexpected bytes")]
+    VkTrailingBytes(usize),
+    #[error("Verifying key does not match the number of public inputs")]
+    VkInputMismatch,
     #[error("Failed to verify proof: {0}")]
     ProofVerificationError(bellman::SynthesisError),
+    #[error("Circuit exceeds the budget of {max} constraints")]
+    ConstraintBudgetExceeded { max: usize },
 }
 
 pub fn generate_proof<C>(circuit: C) -> Result<(Vec<u8>, Vec<u8>), ZkSnarkError>
@@ -36,6 +43,49 @@ where
     let params =
         generate_random_parameters::<Bls12, _, _>(circuit.clone(), &mut rand::thread_rng())
             .map_err(ZkSnarkError::ParameterGenerationError)?;
+    prove(circuit, params)
+}
+
+pub fn generate_proof_bounded<C>(
+    circuit: C,
+    max_constraints: usize,
+) -> Result<(Vec<u8>, Vec<u8>), ZkSnarkError>
+where
+    C: Circuit<Scalar> + Clone,
+{
+    let params = setup_bounded(circuit.clone(), max_constraints)?;
+    prove(circuit, params)
+}
+
+// Synthesizes the circuit once against a counter, which ignores witnesses and aborts at
+// the first allocation past the budget, before paying for parameter generation.
+pub fn setup_bounded<C>(
+    circuit: C,
+    max_constraints: usize,
+) -> Result<Parameters<Bls12>, ZkSnarkError>
+where
+    C: Circuit<Scalar> + Clone,
+{
+    let mut counter = ConstraintCounter {
+        constraints: 0,
+        variables: 0,
+        max_constraints,
+    };
+    let counted = circuit.clone().synthesize(&mut counter);
+    if counter.constraints > max_constraints {
+        return Err(ZkSnarkError::ConstraintBudgetExceeded {
+            max: max_constraints,
+        });
+    }
+    counted.map_err(ZkSnarkError::ParameterGenerationError)?;
+    generate_random_parameters::<Bls12, _, _>(circuit, &mut rand::thread_rng())
+        .map_err(ZkSnarkError::ParameterGenerationError)
+}
+
+fn prove<C>(circuit: C, params: Parameters<Bls12>) -> Result<(Vec<u8>, Vec<u8>), ZkSnarkError>
+where
+    C: Circuit<Scalar>,
+{
     let proof = create_random_proof(circuit, &params, &mut rand::thread_rng())
         .map_err(ZkSnarkError::ProofCreationError)?;
     let vk = params.vk;
@@ -55,16 +105,127 @@ pub fn verify_zk_proof(
     vk: &[u8],
     public_inputs: &[Scalar],
 ) -> Result<(), ZkSnarkError> {
-    let proof = Proof::<Bls12>::read(proof).map_err(ZkSnarkError::ProofDeserializationError)?;
-    let pvk = prepare_verifying_key(
-        &VerifyingKey::<Bls12>::read(vk).map_err(ZkSnarkError::VkDeserializationError)?,
-    );
+    verify_with_vk(&import_vk(vk)?, proof, public_inputs)
+}
+
+// Parses a verifying key in bellman's serialization, e.g. one produced by an external
+// setup ceremony. Points off the BLS12-381 curve or outside its subgroup, as well as
+// truncated input, fail with `VkDeserializationError`; leftover bytes are rejected too.
+pub fn import_vk(bytes: &[u8]) -> Result<PreparedVerifyingKey<Bls12>, ZkSnarkError> {
+    let mut reader = bytes;
+    let vk =
+        VerifyingKey::<Bls12>::read(&mut reader).map_err(ZkSnarkError::VkDeserializationError)?;
+    if !reader.is_empty() {
+        return Err(ZkSnarkError::VkTrailingBytes(reader.len()));
+    }
+    Ok(prepare_verifying_key(&vk))
+}
 
-    verify_proof(&pvk, &proof, public_inputs).map_err(|_: VerificationError| {
-        ZkSnarkError::ProofVerificationError(bellman::SynthesisError::Unsatisfiable)
+// A short, stable identifier for a verifying key, for configs and logs. It covers the
+// exact serialized bytes, so both sides should fingerprint what they distributed.
+pub fn vk_fingerprint(vk_bytes: &[u8]) -> Sha256 {
+    Sha256::new(vk_bytes)
+}
+
+// An imported verifying key, prepared once for repeated verification.
+pub struct PreparedVerifier {
+    pvk: PreparedVerifyingKey<Bls12>,
+    fingerprint: Sha256,
+}
+
+impl PreparedVerifier {
+    pub fn from_bytes(vk_bytes: &[u8]) -> Result<Self, ZkSnarkError> {
+        Ok(Self {
+            pvk: import_vk(vk_bytes)?,
+            fingerprint: vk_fingerprint(vk_bytes),
+        })
+    }
+
+    pub fn fingerprint(&self) -> Sha256 {
+        self.fingerprint
+    }
+
+    pub fn verify(&self, proof: &[u8], public_inputs: &[Scalar]) -> Result<(), ZkSnarkError> {
+        verify_with_vk(&self.pvk, proof, public_inputs)
+    }
+}
+
+pub fn verify_with_vk(
+    pvk: &PreparedVerifyingKey<Bls12>,
+    proof: &[u8],
+    public_inputs: &[Scalar],
+) -> Result<(), ZkSnarkError> {
+    let proof = Proof::<Bls12>::read(proof).map_err(ZkSnarkError::ProofDeserializationError)?;
+    verify_proof(pvk, &proof, public_inputs).map_err(|e| match e {
+        VerificationError::InvalidVerifyingKey => ZkSnarkError::VkInputMismatch,
+        VerificationError::InvalidProof => {
+            ZkSnarkError::ProofVerificationError(bellman::SynthesisError::Unsatisfiable)
+        }
     })
 }
 
+struct ConstraintCounter {
+    constraints: usize,
+    variables: usize,
+    max_constraints: usize,
+}
+
+impl ConstraintCounter {
+    fn next_variable(&mut self, index: fn(usize) -> Index) -> Result<Variable, SynthesisError> {
+        if self.constraints > self.max_constraints {
+            return Err(SynthesisError::Unsatisfiable);
+        }
+        self.variables += 1;
+        Ok(Variable::new_unchecked(index(self.variables)))
+    }
+}
+
+impl ConstraintSystem<Scalar> for ConstraintCounter {
+    type Root = Self;
+
+    fn alloc<F, A, AR>(&mut self, _: A, _: F) -> Result<Variable, SynthesisError>
+    where
+        F: FnOnce() -> Result<Scalar, SynthesisError>,
+        A: FnOnce() -> AR,
+        AR: Into<String>,
+    {
+        self.next_variable(Index::Aux)
+    }
+
+    fn alloc_input<F, A, AR>(&mut self, _: A, _: F) -> Result<Variable, SynthesisError>
+    where
+        F: FnOnce() -> Result<Scalar, SynthesisError>,
+        A: FnOnce() -> AR,
+        AR: Into<String>,
+    {
+        self.next_variable(Index::Input)
+    }
+
+    fn enforce<A, AR, LA, LB, LC>(&mut self, _: A, _: LA, _: LB, _: LC)
+    where
+        A: FnOnce() -> AR,
+        AR: Into<String>,
+        LA: FnOnce(LinearCombination<Scalar>) -> LinearCombination<Scalar>,
+        LB: FnOnce(LinearCombination<Scalar>) -> LinearCombination<Scalar>,
+        LC: FnOnce(LinearCombination<Scalar>) -> LinearCombination<Scalar>,
+    {
+        self.constraints += 1;
+    }
+
+    fn push_namespace<NR, N>(&mut self, _: N)
+    where
+        NR: Into<String>,
+        N: FnOnce() -> NR,
+    {
+    }
+
+    fn pop_namespace(&mut self) {}
+
+    fn get_root(&mut self) -> &mut Self::Root {
+        self
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -130,4 +291,82 @@ mod tests {
         let result = verify_zk_proof(&proof, &vk, &[invalid_c]);
         assert!(result.is_err());
     }
+
+    #[test]
+    fn test_import_vk() {
+        let (a, b) = (Scalar::from(2), Scalar::from(3));
+        let (proof, vk) = generate_proof(TestCircuit {
+            a: Some(a),
+            b: Some(b),
+        })
+        .unwrap();
+
+        let pvk = import_vk(&vk).unwrap();
+        assert!(verify_with_vk(&pvk, &proof, &[a * b]).is_ok());
+        assert!(matches!(
+            verify_with_vk(&pvk, &proof, &[a * b, a]),
+            Err(ZkSnarkError::VkInputMismatch)
+        ));
+        assert!(matches!(
+            verify_with_vk(&pvk, &proof, &[a]),
+            Err(ZkSnarkError::ProofVerificationError(_))
+        ));
+
+        // The first point is stored uncompressed; garbage there is not on the curve.
+        let mut off_curve = vk.clone();
+        off_curve[1..48].fill(0xff);
+        assert!(matches!(
+            import_vk(&off_curve),
+            Err(ZkSnarkError::VkDeserializationError(e)) if e.kind() == std::io::ErrorKind::InvalidData
+        ));
+        assert!(matches!(
+            import_vk(&vk[..vk.len() - 1]),
+            Err(ZkSnarkError::VkDeserializationError(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof
+        ));
+        let mut trailing = vk.clone();
+        trailing.extend_from_slice(&[0; 3]);
+        assert!(matches!(
+            import_vk(&trailing),
+            Err(ZkSnarkError::VkTrailingBytes(3))
+        ));
+    }
+
+    #[test]
+    fn test_vk_fingerprint() {
+        let (a, b) = (Scalar::from(2), Scalar::from(3));
+        let circuit = TestCircuit {
+            a: Some(a),
+            b: Some(b),
+        };
+        let (proof, vk) = generate_proof(circuit.clone()).unwrap();
+        let (_, other_vk) = generate_proof(circuit).unwrap();
+
+        let first = PreparedVerifier::from_bytes(&vk).unwrap();
+        let second = PreparedVerifier::from_bytes(&vk.clone()).unwrap();
+        assert_eq!(first.fingerprint(), second.fingerprint());
+        assert_eq!(first.fingerprint(), vk_fingerprint(&vk));
+        assert_ne!(
+            first.fingerprint(),
+            PreparedVerifier::from_bytes(&other_vk)
+                .unwrap()
+                .fingerprint()
+        );
+        assert!(first.verify(&proof, &[a * b]).is_ok());
+    }
+
+    #[test]
+    fn test_constraint_budget() {
+        let (a, b) = (Scalar::from(2), Scalar::from(3));
+        let circuit = TestCircuit {
+            a: Some(a),
+            b: Some(b),
+        };
+
+        assert!(matches!(
+            generate_proof_bounded(circuit.clone(), 0),
+            Err(ZkSnarkError::ConstraintBudgetExceeded { max: 0 })
+        ));
+        let (proof, vk) = generate_proof_bounded(circuit, 1).unwrap();
+        assert!(verify_zk_proof(&proof, &vk, &[a * b]).is_ok());
+    }
 }
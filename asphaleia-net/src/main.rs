@@ -1,4 +1,3 @@
-use libp2p::kad;
 fn main() {
     println!("Hello, world!");
 }
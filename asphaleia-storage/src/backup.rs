@@ -1,16 +1,22 @@
 use super::{
-    compression::{compress_bytes, decompress_bytes},
+    compression::decompress_bytes,
     fragment::{Fragment, FragmentError},
-    versioning::VersionControl,
+    legacy::LegacyVersionControl,
+    store::{BackupStore, FileSystemStore},
+    versioning::{Version, VersionControl},
 };
 
-use asphaleia_crypto::hash::Sha256;
-use serde::{Deserialize, Serialize};
+use asphaleia_crypto::{
+    aes_gcm::Aes256Gcm,
+    hash::{Sha256, Sha256Hasher},
+    Key, ManageKey, AES,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use std::{
-    fs::{create_dir_all, File},
+    collections::{hash_map::Entry, HashMap},
     io::{Read, Write},
     path::Path,
-    time::SystemTime,
+    time::{SystemTime, UNIX_EPOCH},
 };
 use thiserror::Error;
 
@@ -26,9 +32,62 @@ pub enum BackupError {
     FragmentError(#[from] FragmentError),
     #[error("No versions found")]
     NoVersionsFound,
+    #[error("Integrity check failed at version {version}")]
+    IntegrityFailure { version: u64 },
+    #[error("Backup payload is encrypted with {0}; a key is required to load it")]
+    EncryptedPayload(String),
+    #[error("Failed to encrypt backup payload")]
+    EncryptionFailed,
+    #[error("Failed to decrypt backup payload")]
+    DecryptionFailed,
+    #[error("Backup payload does not match its recorded checksum")]
+    CorruptPayload,
+    #[error("Invalid key length: expected {expected} bytes, got {actual}")]
+    InvalidKeyLength { expected: usize, actual: usize },
+    #[error("A backup already exists at {0}")]
+    AlreadyExists(String),
 }
 
-#[derive(Serialize, Deserialize)]
+// What `save_to_disk_with_mode` does when the target already holds a backup. `Versioned`
+// always writes into a new subdirectory named after the save time and leaves the rest of
+// the target alone.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SaveMode {
+    #[default]
+    Overwrite,
+    FailIfExists,
+    Versioned,
+}
+
+const METADATA_FILE: &str = "metadata.json";
+const VERSIONS_FILE: &str = "versions.bin";
+const MANIFEST_FILE: &str = "manifest.json";
+const PAYLOAD_AEAD: &str = "AES-256-GCM";
+const PAYLOAD_KEY_SIZE: usize = 32;
+// Format 0 is the first release's single bincode blob of the whole history, migrated on
+// load by `legacy`; format 1 is a zstd stream of length-prefixed frames: one
+// `PayloadHeader`, then one frame per version.
+const LEGACY_PAYLOAD_FORMAT: u32 = 0;
+const FRAMED_PAYLOAD_FORMAT: u32 = 1;
+// Format 2 follows the header with a frame holding every distinct stored value once;
+// version frames then carry little-endian u32 pool indices in place of their values.
+const POOLED_PAYLOAD_FORMAT: u32 = 2;
+
+// Tracks the delta files written by `save_incremental` on top of `versions.bin`;
+// `last_digest` detects a history that was rewritten (rollback, compaction) since.
+// `delta_digests` holds the SHA3-256 of each delta file as written; deltas from older
+// manifests have none and are read unverified.
+#[derive(Serialize, Deserialize, Default)]
+struct BackupManifest {
+    last_version: u64,
+    last_digest: Option<Sha256>,
+    deltas: Vec<String>,
+    #[serde(default)]
+    delta_digests: HashMap<String, [u8; 32]>,
+    tags: HashMap<String, u64>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
 struct BackupMetadata {
     creation_date: SystemTime,
     fragment_count: usize,
@@ -36,6 +95,21 @@ struct BackupMetadata {
     version_count: usize,
     compression_level: Option<usize>,
     max_versions: Option<usize>,
+    #[serde(default)]
+    encryption: Option<String>,
+    // SHA3-256 of `versions.bin` exactly as written; absent in older backups.
+    #[serde(default, alias = "payload_sha256")]
+    payload_digest: Option<[u8; 32]>,
+    #[serde(default)]
+    payload_format: u32,
+    #[serde(default)]
+    deduplicate: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+struct PayloadHeader {
+    max_versions: Option<usize>,
+    tags: HashMap<String, u64>,
 }
 
 pub struct Backup {
@@ -44,6 +118,28 @@ pub struct Backup {
     hash: Sha256,
 }
 
+// An owned copy of a backup's state, so the slow write can happen after the
+// lock guarding the live `Backup` has been released.
+pub struct BackupSnapshot {
+    metadata: BackupMetadata,
+    version_control: VersionControl,
+}
+
+impl BackupSnapshot {
+    pub fn save_to_disk(&self, path: &str, level: Option<usize>) -> Result<(), BackupError> {
+        self.save_to_store(&mut FileSystemStore::new(path), level)
+    }
+
+    pub fn save_to_store<S: BackupStore + ?Sized>(
+        &self,
+        store: &mut S,
+        level: Option<usize>,
+    ) -> Result<(), BackupError> {
+        write_backup(&self.metadata, &self.version_control, store, level, None)?;
+        Ok(())
+    }
+}
+
 impl Backup {
     pub fn new(fragment: Fragment, max_versions: Option<usize>) -> Result<Self, BackupError> {
         let mut version_control = VersionControl::new(max_versions);
@@ -56,9 +152,13 @@ impl Backup {
             version_count: 1,
             compression_level: None,
             max_versions,
+            encryption: None,
+            payload_digest: None,
+            payload_format: FRAMED_PAYLOAD_FORMAT,
+            deduplicate: false,
         };
 
-        let hash = Sha256::new(&fragment.to_bytes()?);
+        let hash = fragment.digest(&fragment.to_bytes()?);
 
         Ok(Self {
             metadata,
@@ -93,48 +193,256 @@ impl Backup {
             .map(|v| &v.fragment)
     }
 
+    pub fn get_latest_version_number(&self) -> Option<u64> {
+        self.version_control.get_latest_version().map(|v| v.version)
+    }
+
+    pub fn get_version_control(&self) -> &VersionControl {
+        &self.version_control
+    }
+
+    pub fn tag_version(&mut self, version: u64, tag: String) -> bool {
+        self.version_control.tag_version(version, tag)
+    }
+
+    pub fn is_version_trimmed(&self, version: u64) -> bool {
+        self.version_control.is_trimmed(version)
+    }
+
+    pub fn verify(&self) -> Result<(), BackupError> {
+        for version in self.version_control.get_history() {
+            if version.fragment.get_hash() != &version.fragment.compute_hash() {
+                return Err(BackupError::IntegrityFailure {
+                    version: version.version,
+                });
+            }
+        }
+        let latest = self
+            .version_control
+            .get_latest_version()
+            .ok_or(BackupError::NoVersionsFound)?;
+        if latest.fragment.digest(&latest.fragment.to_bytes()?) != self.hash {
+            return Err(BackupError::IntegrityFailure {
+                version: latest.version,
+            });
+        }
+        Ok(())
+    }
+
+    pub fn repair_hashes(&mut self) -> Vec<u64> {
+        self.version_control.repair_hashes()
+    }
+
+    // Makes every later full save write each distinct stored value once and reference it
+    // from the versions that hold it. Returns the bytes of repeated values that are no
+    // longer written.
+    pub fn deduplicate(&mut self) -> Result<usize, BackupError> {
+        self.metadata.deduplicate = true;
+        Ok(BlobPool::new(&self.version_control).saved)
+    }
+
     pub fn save_to_disk(&mut self, path: &str, level: Option<usize>) -> Result<(), BackupError> {
-        let backup_dir = Path::new(path);
-        create_dir_all(backup_dir)?;
-
-        let metadata_path = backup_dir.join("metadata.json");
-        let mut metadata_file = File::create(metadata_path)?;
-        let metadata_json = serde_json::to_string(&self.metadata)?;
-        metadata_file.write_all(metadata_json.as_bytes())?;
-
-        let versions_path = backup_dir.join("versions.bin");
-        let mut versions_file = File::create(versions_path)?;
-        let versions_data = bincode::serialize(&self.version_control)?;
-        let level_compression = level.unwrap_or(3);
-        let compressed = compress_bytes(&versions_data, level_compression.try_into().unwrap())?;
-        self.metadata.compression_level = Some(level_compression);
-        versions_file.write_all(&compressed)?;
+        self.save_to_store(&mut FileSystemStore::new(path), level)
+    }
+
+    // Returns the directory the backup was written to.
+    pub fn save_to_disk_with_mode(
+        &mut self,
+        path: &str,
+        level: Option<usize>,
+        mode: SaveMode,
+    ) -> Result<String, BackupError> {
+        let target = match mode {
+            SaveMode::Overwrite => path.to_string(),
+            SaveMode::FailIfExists => {
+                if holds_backup(&FileSystemStore::new(path))? {
+                    return Err(BackupError::AlreadyExists(path.to_string()));
+                }
+                path.to_string()
+            }
+            SaveMode::Versioned => {
+                let elapsed = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default();
+                let name = format!("{}-{:09}", elapsed.as_secs(), elapsed.subsec_nanos());
+                let target = Path::new(path).join(name).to_string_lossy().into_owned();
+                if holds_backup(&FileSystemStore::new(&target))? {
+                    return Err(BackupError::AlreadyExists(target));
+                }
+                target
+            }
+        };
+        self.save_to_disk(&target, level)?;
+        Ok(target)
+    }
+
+    pub fn save_to_store<S: BackupStore + ?Sized>(
+        &mut self,
+        store: &mut S,
+        level: Option<usize>,
+    ) -> Result<(), BackupError> {
+        self.metadata = write_backup(&self.metadata, &self.version_control, store, level, None)?;
+        self.metadata.compression_level = Some(level.unwrap_or(3));
+        Ok(())
+    }
+
+    // Only the versions payload is encrypted, fragment metadata and compression dictionaries
+    // included; metadata.json stays readable and names the AEAD.
+    pub fn save_to_disk_encrypted(&mut self, path: &str, key: &Key) -> Result<(), BackupError> {
+        self.save_to_store_encrypted(&mut FileSystemStore::new(path), key)
+    }
 
+    pub fn save_to_store_encrypted<S: BackupStore + ?Sized>(
+        &mut self,
+        store: &mut S,
+        key: &Key,
+    ) -> Result<(), BackupError> {
+        let level = self.metadata.compression_level;
+        self.metadata = write_backup(
+            &self.metadata,
+            &self.version_control,
+            store,
+            level,
+            Some(key),
+        )?;
+        self.metadata.compression_level = Some(level.unwrap_or(3));
         Ok(())
     }
 
+    pub fn snapshot(&self) -> BackupSnapshot {
+        BackupSnapshot {
+            metadata: self.metadata.clone(),
+            version_control: self.version_control.clone(),
+        }
+    }
+
+    pub fn save_snapshot_to_disk(
+        &self,
+        path: &str,
+        level: Option<usize>,
+    ) -> Result<(), BackupError> {
+        self.snapshot().save_to_disk(path, level)
+    }
+
+    // Writes only the versions added since the previous save as a numbered delta file,
+    // falling back to a full save when there is no base or the history was rewritten.
+    pub fn save_incremental(&mut self, path: &str) -> Result<(), BackupError> {
+        self.save_incremental_to_store(&mut FileSystemStore::new(path))
+    }
+
+    pub fn save_incremental_to_store<S: BackupStore + ?Sized>(
+        &mut self,
+        store: &mut S,
+    ) -> Result<(), BackupError> {
+        if let Some(aead) = &self.metadata.encryption {
+            return Err(BackupError::EncryptedPayload(aead.clone()));
+        }
+        let level = self.metadata.compression_level;
+        let mut manifest = match read_manifest(store)? {
+            Some(manifest)
+                if store.exists(VERSIONS_FILE)?
+                    && self.metadata.payload_format != LEGACY_PAYLOAD_FORMAT =>
+            {
+                manifest
+            }
+            _ => return self.save_to_store(store, level),
+        };
+        let extends_saved = self
+            .version_control
+            .get_version(manifest.last_version)
+            .is_some_and(|v| manifest.last_digest == Some(v.digest()));
+        if !extends_saved {
+            return self.save_to_store(store, level);
+        }
+
+        write_metadata(store, &self.metadata)?;
+        let new_versions = self.version_control.versions_after(manifest.last_version);
+        if let Some(latest) = new_versions.last() {
+            let name = format!("delta-{:06}.bin", manifest.deltas.len() + 1);
+            let mut writer = HashingWriter::new(store.writer(&name)?);
+            encode_frames(&mut writer, None, None, new_versions.iter(), level)?;
+            writer.flush()?;
+            manifest
+                .delta_digests
+                .insert(name.clone(), writer.finalize().0);
+            manifest.deltas.push(name);
+            manifest.last_version = latest.version;
+            manifest.last_digest = Some(latest.digest());
+        }
+        manifest.tags = self.version_control.get_tags().clone();
+        write_manifest(store, &manifest)
+    }
+
+    // Folds any delta files back into a single `versions.bin`.
+    pub fn compact_backup(path: &str) -> Result<(), BackupError> {
+        let mut backup = Self::load_from_disk(path)?;
+        let level = backup.metadata.compression_level;
+        backup.save_to_disk(path, level)
+    }
+
     pub fn load_from_disk(path: &str) -> Result<Self, BackupError> {
-        let backup_dir = Path::new(path);
+        Self::load(&FileSystemStore::new(path), None)
+    }
+
+    pub fn load_from_disk_encrypted(path: &str, key: &Key) -> Result<Self, BackupError> {
+        Self::load(&FileSystemStore::new(path), Some(key))
+    }
 
-        let metadata_path = backup_dir.join("metadata.json");
-        let mut metadata_file = File::open(metadata_path)?;
-        let mut metadata_json = String::new();
-        metadata_file.read_to_string(&mut metadata_json)?;
-        let metadata: BackupMetadata = serde_json::from_str(&metadata_json)?;
+    pub fn load_from_store<S: BackupStore + ?Sized>(store: &S) -> Result<Self, BackupError> {
+        Self::load(store, None)
+    }
+
+    pub fn load_from_store_encrypted<S: BackupStore + ?Sized>(
+        store: &S,
+        key: &Key,
+    ) -> Result<Self, BackupError> {
+        Self::load(store, Some(key))
+    }
+
+    fn load<S: BackupStore + ?Sized>(store: &S, key: Option<&Key>) -> Result<Self, BackupError> {
+        let metadata: BackupMetadata = serde_json::from_slice(&store.read(METADATA_FILE)?)?;
+        let key = match (&metadata.encryption, key) {
+            (Some(_), Some(key)) => Some(key),
+            (Some(aead), None) => return Err(BackupError::EncryptedPayload(aead.clone())),
+            (None, _) => None,
+        };
+
+        if let Some(expected) = metadata.payload_digest {
+            if stored_digest(store, VERSIONS_FILE)?.0 != expected {
+                return Err(BackupError::CorruptPayload);
+            }
+        }
+        let mut version_control = match key {
+            Some(key) => {
+                let payload = payload_cipher(key)?
+                    .decrypt(&store.read(VERSIONS_FILE)?)
+                    .map_err(|_| BackupError::DecryptionFailed)?;
+                decode_versions(payload.as_slice(), metadata.payload_format)?
+            }
+            None => decode_versions(store.reader(VERSIONS_FILE)?, metadata.payload_format)?,
+        };
 
-        let versions_path = backup_dir.join("versions.bin");
-        let mut versions_file = File::open(versions_path)?;
-        let mut compressed_versions_data = Vec::new();
-        versions_file.read_to_end(&mut compressed_versions_data)?;
-        let versions_data = decompress_bytes(&compressed_versions_data)?;
-        let version_control: VersionControl = bincode::deserialize(&versions_data)?;
+        if let Some(manifest) = read_manifest(store)? {
+            for name in &manifest.deltas {
+                if let Some(expected) = manifest.delta_digests.get(name) {
+                    if stored_digest(store, name)?.0 != *expected {
+                        return Err(BackupError::CorruptPayload);
+                    }
+                }
+                let reader = zstd::stream::Decoder::new(store.reader(name)?)?;
+                version_control.extend_versions(decode_frames(reader)?);
+            }
+            if !manifest.deltas.is_empty() {
+                version_control.set_tags(manifest.tags);
+            }
+        }
 
         let latest_fragment = version_control
             .get_latest_version()
             .ok_or(BackupError::NoVersionsFound)?
             .fragment
             .clone();
-        let hash = Sha256::new(&latest_fragment.to_bytes()?);
+        let hash = latest_fragment.digest(&latest_fragment.to_bytes()?);
 
         Ok(Self {
             metadata,
@@ -145,7 +453,8 @@ impl Backup {
 
     fn update_hash(&mut self) -> Result<(), BackupError> {
         if let Some(latest_version) = self.version_control.get_latest_version() {
-            self.hash = Sha256::new(&latest_version.fragment.to_bytes()?);
+            let fragment = &latest_version.fragment;
+            self.hash = fragment.digest(&fragment.to_bytes()?);
         }
         Ok(())
     }
@@ -168,6 +477,21 @@ impl Backup {
         }
     }
 
+    pub fn compact(&mut self) {
+        self.version_control.compact();
+        self.update_counts();
+    }
+
+    pub fn compact_keep_last(&mut self, n: usize) {
+        self.version_control.compact_keep_last(n);
+        self.update_counts();
+    }
+
+    fn update_counts(&mut self) {
+        self.metadata.fragment_count = self.version_control.get_version_count();
+        self.metadata.version_count = self.version_control.get_version_count();
+    }
+
     pub fn get_history(&self) -> Vec<&Fragment> {
         self.version_control
             .get_history()
@@ -176,3 +500,840 @@ impl Backup {
             .collect()
     }
 }
+
+// A full save becomes the new base, so deltas from earlier incremental saves are removed.
+// Returns the metadata as written. Plain payloads are streamed straight to the file;
+// encrypted ones are buffered because the AEAD seals the payload as a whole.
+fn write_backup<S: BackupStore + ?Sized>(
+    metadata: &BackupMetadata,
+    version_control: &VersionControl,
+    store: &mut S,
+    level: Option<usize>,
+    key: Option<&Key>,
+) -> Result<BackupMetadata, BackupError> {
+    let header = PayloadHeader {
+        max_versions: version_control.get_max_versions(),
+        tags: version_control.get_tags().clone(),
+    };
+    let pool = metadata.deduplicate.then(|| BlobPool::new(version_control));
+    let versions = version_control.get_history().into_iter();
+    let mut file = HashingWriter::new(store.writer(VERSIONS_FILE)?);
+    match key {
+        Some(key) => {
+            let mut payload = Vec::new();
+            encode_frames(&mut payload, Some(&header), pool.as_ref(), versions, level)?;
+            let payload = payload_cipher(key)?
+                .encrypt(&payload, None)
+                .map_err(|_| BackupError::EncryptionFailed)?;
+            file.write_all(&payload)?;
+        }
+        None => encode_frames(&mut file, Some(&header), pool.as_ref(), versions, level)?,
+    }
+    file.flush()?;
+
+    let metadata = BackupMetadata {
+        encryption: key.map(|_| PAYLOAD_AEAD.to_string()),
+        payload_digest: Some(file.finalize().0),
+        payload_format: if pool.is_some() {
+            POOLED_PAYLOAD_FORMAT
+        } else {
+            FRAMED_PAYLOAD_FORMAT
+        },
+        ..metadata.clone()
+    };
+    write_metadata(store, &metadata)?;
+
+    if let Some(stale) = read_manifest(store)? {
+        for name in stale.deltas {
+            store.remove(&name)?;
+        }
+    }
+    let latest = version_control.get_latest_version();
+    write_manifest(
+        store,
+        &BackupManifest {
+            last_version: latest.map_or(0, |v| v.version),
+            last_digest: latest.map(Version::digest),
+            deltas: Vec::new(),
+            delta_digests: HashMap::new(),
+            tags: version_control.get_tags().clone(),
+        },
+    )?;
+    Ok(metadata)
+}
+
+fn write_metadata<S: BackupStore + ?Sized>(
+    store: &mut S,
+    metadata: &BackupMetadata,
+) -> Result<(), BackupError> {
+    let metadata_json = serde_json::to_string(metadata)?;
+    store.write(METADATA_FILE, metadata_json.as_bytes())?;
+    Ok(())
+}
+
+fn encode_frames<'a, W: Write>(
+    writer: W,
+    header: Option<&PayloadHeader>,
+    pool: Option<&BlobPool>,
+    versions: impl Iterator<Item = &'a Version>,
+    level: Option<usize>,
+) -> Result<(), BackupError> {
+    let level_compression = level.unwrap_or(3);
+    let mut encoder = zstd::stream::Encoder::new(writer, level_compression.try_into().unwrap())?;
+    if let Some(header) = header {
+        write_frame(&mut encoder, header)?;
+    }
+    if let Some(pool) = pool {
+        write_frame(&mut encoder, &pool.blobs)?;
+    }
+    for version in versions {
+        match pool {
+            Some(pool) => write_frame(&mut encoder, &pool.pooled(version))?,
+            None => write_frame(&mut encoder, version)?,
+        }
+    }
+    encoder.finish()?;
+    Ok(())
+}
+
+fn decode_versions<R: Read>(mut reader: R, format: u32) -> Result<VersionControl, BackupError> {
+    if format == LEGACY_PAYLOAD_FORMAT {
+        let mut compressed = Vec::new();
+        reader.read_to_end(&mut compressed)?;
+        let legacy: LegacyVersionControl = bincode::deserialize(&decompress_bytes(&compressed)?)?;
+        return Ok(legacy.migrate());
+    }
+    let mut decoder = zstd::stream::Decoder::new(reader)?;
+    let header: PayloadHeader = read_frame(&mut decoder)?.ok_or(BackupError::NoVersionsFound)?;
+    let versions = if format == POOLED_PAYLOAD_FORMAT {
+        let blobs: Vec<Vec<u8>> = read_frame(&mut decoder)?.ok_or(BackupError::CorruptPayload)?;
+        let mut versions = decode_frames(decoder)?;
+        for version in &mut versions {
+            resolve_pooled(version, &blobs)?;
+        }
+        versions
+    } else {
+        decode_frames(decoder)?
+    };
+    Ok(VersionControl::from_parts(
+        versions,
+        header.max_versions,
+        header.tags,
+    ))
+}
+
+fn decode_frames<R: Read>(mut reader: R) -> Result<Vec<Version>, BackupError> {
+    let mut versions = Vec::new();
+    while let Some(version) = read_frame(&mut reader)? {
+        versions.push(version);
+    }
+    Ok(versions)
+}
+
+// Every distinct stored value in the history, in first-seen order.
+struct BlobPool<'a> {
+    blobs: Vec<&'a [u8]>,
+    index: HashMap<&'a [u8], u32>,
+    saved: usize,
+}
+
+impl<'a> BlobPool<'a> {
+    fn new(version_control: &'a VersionControl) -> Self {
+        let mut pool = Self {
+            blobs: Vec::new(),
+            index: HashMap::new(),
+            saved: 0,
+        };
+        for version in version_control.get_history() {
+            for stored in version.fragment.stored_values() {
+                match pool.index.entry(stored) {
+                    Entry::Occupied(_) => pool.saved += stored.len(),
+                    Entry::Vacant(entry) => {
+                        entry.insert(pool.blobs.len() as u32);
+                        pool.blobs.push(stored);
+                    }
+                }
+            }
+        }
+        pool
+    }
+
+    fn pooled(&self, version: &Version) -> Version {
+        let mut pooled = version.clone();
+        for stored in pooled.fragment.values_mut() {
+            *stored = self.index[stored.as_slice()].to_le_bytes().to_vec();
+        }
+        pooled
+    }
+}
+
+fn resolve_pooled(version: &mut Version, blobs: &[Vec<u8>]) -> Result<(), BackupError> {
+    for stored in version.fragment.values_mut() {
+        let index = <[u8; 4]>::try_from(stored.as_slice())
+            .map(u32::from_le_bytes)
+            .map_err(|_| BackupError::CorruptPayload)?;
+        *stored = blobs
+            .get(index as usize)
+            .ok_or(BackupError::CorruptPayload)?
+            .clone();
+    }
+    Ok(())
+}
+
+// Each frame is a little-endian u64 length followed by that many bytes of bincode.
+fn write_frame<W: Write, T: Serialize>(writer: &mut W, value: &T) -> Result<(), BackupError> {
+    writer.write_all(&bincode::serialized_size(value)?.to_le_bytes())?;
+    bincode::serialize_into(writer, value)?;
+    Ok(())
+}
+
+fn read_frame<R: Read, T: DeserializeOwned>(reader: &mut R) -> Result<Option<T>, BackupError> {
+    let mut len = [0u8; 8];
+    if reader.read(&mut len[..1])? == 0 {
+        return Ok(None);
+    }
+    reader.read_exact(&mut len[1..])?;
+    let frame = reader.take(u64::from_le_bytes(len));
+    Ok(Some(bincode::deserialize_from(frame)?))
+}
+
+fn stored_digest<S: BackupStore + ?Sized>(store: &S, name: &str) -> Result<Sha256, BackupError> {
+    let mut reader = store.reader(name)?;
+    let mut hasher = Sha256Hasher::new();
+    let mut buffer = [0u8; 64 * 1024];
+    loop {
+        let read = reader.read(&mut buffer)?;
+        if read == 0 {
+            return Ok(hasher.finalize());
+        }
+        hasher.update(&buffer[..read]);
+    }
+}
+
+struct HashingWriter<W> {
+    inner: W,
+    hasher: Sha256Hasher,
+}
+
+impl<W: Write> HashingWriter<W> {
+    fn new(inner: W) -> Self {
+        Self {
+            inner,
+            hasher: Sha256Hasher::new(),
+        }
+    }
+
+    fn finalize(self) -> Sha256 {
+        self.hasher.finalize()
+    }
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+fn payload_cipher(key: &Key) -> Result<AES<Aes256Gcm>, BackupError> {
+    if key.len() != PAYLOAD_KEY_SIZE {
+        return Err(BackupError::InvalidKeyLength {
+            expected: PAYLOAD_KEY_SIZE,
+            actual: key.len(),
+        });
+    }
+    Ok(AES::new(key.as_bytes()))
+}
+
+fn read_manifest<S: BackupStore + ?Sized>(
+    store: &S,
+) -> Result<Option<BackupManifest>, BackupError> {
+    if !store.exists(MANIFEST_FILE)? {
+        return Ok(None);
+    }
+    Ok(Some(serde_json::from_slice(&store.read(MANIFEST_FILE)?)?))
+}
+
+fn write_manifest<S: BackupStore + ?Sized>(
+    store: &mut S,
+    manifest: &BackupManifest,
+) -> Result<(), BackupError> {
+    store.write(MANIFEST_FILE, serde_json::to_string(manifest)?.as_bytes())?;
+    Ok(())
+}
+
+fn holds_backup<S: BackupStore + ?Sized>(store: &S) -> Result<bool, BackupError> {
+    Ok(store.exists(METADATA_FILE)? || store.exists(VERSIONS_FILE)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        compression::{train_dictionary, CompressionAlgorithm},
+        fragment::{HashMode, DEFAULT_MIN_COMPRESS_SIZE},
+    };
+    use std::{
+        sync::{Arc, RwLock},
+        thread,
+    };
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_snapshot_save_while_reading() {
+        let mut fragment = Fragment::new(CompressionAlgorithm::Zstd, 3, None);
+        let key = Sha256::new(b"key");
+        fragment.insert(b"value".to_vec(), key).unwrap();
+        let backup = Arc::new(RwLock::new(Backup::new(fragment, None).unwrap()));
+
+        let reader = {
+            let backup = Arc::clone(&backup);
+            thread::spawn(move || {
+                for _ in 0..100 {
+                    let guard = backup.read().unwrap();
+                    let latest = guard.get_latest_version().unwrap();
+                    assert_eq!(latest.get(&key).unwrap(), Some(b"value".to_vec()));
+                }
+            })
+        };
+
+        let dir = tempdir().unwrap();
+        let path = dir.path().to_str().unwrap();
+        let snapshot = backup.read().unwrap().snapshot();
+        snapshot.save_to_disk(path, None).unwrap();
+        reader.join().unwrap();
+
+        let loaded = Backup::load_from_disk(path).unwrap();
+        assert_eq!(
+            loaded.get_latest_version_number(),
+            backup.read().unwrap().get_latest_version_number()
+        );
+        assert_eq!(
+            loaded.get_latest_version().unwrap().get(&key).unwrap(),
+            Some(b"value".to_vec())
+        );
+
+        let other = tempdir().unwrap();
+        let other_path = other.path().to_str().unwrap();
+        backup
+            .read()
+            .unwrap()
+            .save_snapshot_to_disk(other_path, Some(5))
+            .unwrap();
+        assert!(Backup::load_from_disk(other_path).is_ok());
+    }
+
+    #[test]
+    fn test_incremental_saves() {
+        let fragment_with = |i: u8| {
+            let mut fragment = Fragment::new(CompressionAlgorithm::Zstd, 3, None);
+            fragment.insert(vec![i; 32], Sha256::new(&[i])).unwrap();
+            fragment
+        };
+        let dir = tempdir().unwrap();
+        let path = dir.path().to_str().unwrap();
+        let base = dir.path().join(VERSIONS_FILE);
+        let delta_files = || {
+            let mut names: Vec<String> = std::fs::read_dir(dir.path())
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+                .filter(|name| name.starts_with("delta-"))
+                .collect();
+            names.sort();
+            names
+        };
+
+        let mut backup = Backup::new(fragment_with(0), None).unwrap();
+        backup.save_incremental(path).unwrap();
+        let base_bytes = std::fs::read(&base).unwrap();
+        assert!(delta_files().is_empty());
+
+        backup.add_version(fragment_with(1)).unwrap();
+        backup.add_version(fragment_with(2)).unwrap();
+        backup.save_incremental(path).unwrap();
+        backup.tag_version(3, "stable".to_string());
+        backup.add_version(fragment_with(3)).unwrap();
+        backup.save_incremental(path).unwrap();
+        backup.save_incremental(path).unwrap();
+        assert_eq!(delta_files(), vec!["delta-000001.bin", "delta-000002.bin"]);
+        assert_eq!(std::fs::read(&base).unwrap(), base_bytes);
+
+        let loaded = Backup::load_from_disk(path).unwrap();
+        assert_eq!(loaded.get_latest_version_number(), Some(4));
+        assert_eq!(loaded.get_version_control().get_tag("stable"), Some(3));
+        assert!(loaded.get_version_control().verify_chain().is_ok());
+        let latest = loaded.get_latest_version().unwrap();
+        assert_eq!(latest.get(&Sha256::new(&[3])).unwrap(), Some(vec![3; 32]));
+
+        let delta = dir.path().join("delta-000002.bin");
+        let delta_bytes = std::fs::read(&delta).unwrap();
+        std::fs::write(&delta, &delta_bytes[..delta_bytes.len() - 1]).unwrap();
+        assert!(matches!(
+            Backup::load_from_disk(path),
+            Err(BackupError::CorruptPayload)
+        ));
+        std::fs::write(&delta, &delta_bytes).unwrap();
+
+        Backup::compact_backup(path).unwrap();
+        assert!(delta_files().is_empty());
+        let compacted = Backup::load_from_disk(path).unwrap();
+        assert_eq!(compacted.get_history().len(), loaded.get_history().len());
+        assert_eq!(compacted.get_version_control().get_tag("stable"), Some(3));
+
+        backup.rollback(2).unwrap();
+        backup.add_version(fragment_with(9)).unwrap();
+        backup.save_incremental(path).unwrap();
+        assert!(delta_files().is_empty());
+        let rewritten = Backup::load_from_disk(path).unwrap();
+        assert_eq!(rewritten.get_latest_version_number(), Some(3));
+        let latest = rewritten.get_latest_version().unwrap();
+        assert_eq!(latest.get(&Sha256::new(&[9])).unwrap(), Some(vec![9; 32]));
+    }
+
+    #[test]
+    fn test_verify_detects_first_bad_version() {
+        let fragment_with = |i: u8| {
+            let mut fragment = Fragment::new(CompressionAlgorithm::Zstd, 3, None);
+            fragment.insert(vec![i; 32], Sha256::new(&[i])).unwrap();
+            fragment
+        };
+        let dir = tempdir().unwrap();
+        let path = dir.path().to_str().unwrap();
+        let mut backup = Backup::new(fragment_with(0), None).unwrap();
+        backup.add_version(fragment_with(1)).unwrap();
+        backup.save_to_disk(path, None).unwrap();
+        let mut loaded = Backup::load_from_disk(path).unwrap();
+        assert!(loaded.verify().is_ok());
+
+        let mut drifted = serde_json::to_value(fragment_with(2)).unwrap();
+        drifted["hash"] = serde_json::to_value(Sha256::new(b"drifted")).unwrap();
+        loaded
+            .add_version(serde_json::from_value(drifted).unwrap())
+            .unwrap();
+        loaded.add_version(fragment_with(3)).unwrap();
+        assert!(matches!(
+            loaded.verify(),
+            Err(BackupError::IntegrityFailure { version: 3 })
+        ));
+
+        loaded.repair_hashes();
+        assert!(loaded.verify().is_ok());
+        loaded.hash = Sha256::new(b"stale");
+        assert!(matches!(
+            loaded.verify(),
+            Err(BackupError::IntegrityFailure { version: 4 })
+        ));
+    }
+
+    #[test]
+    fn test_encrypted_backup_roundtrip() {
+        let mut fragment = Fragment::new(CompressionAlgorithm::Zstd, 3, None);
+        let entry = Sha256::new(b"secret");
+        fragment.insert(b"secret value".to_vec(), entry).unwrap();
+        let dir = tempdir().unwrap();
+        let path = dir.path().to_str().unwrap();
+        let key = Key::generate(32).unwrap();
+
+        let mut backup = Backup::new(fragment, None).unwrap();
+        backup.save_to_disk_encrypted(path, &key).unwrap();
+        let metadata = std::fs::read_to_string(dir.path().join(METADATA_FILE)).unwrap();
+        assert!(metadata.contains(PAYLOAD_AEAD));
+
+        let loaded = Backup::load_from_disk_encrypted(path, &key).unwrap();
+        assert_eq!(
+            loaded.get_latest_version().unwrap().get(&entry).unwrap(),
+            Some(b"secret value".to_vec())
+        );
+        assert!(matches!(
+            Backup::load_from_disk(path),
+            Err(BackupError::EncryptedPayload(aead)) if aead == PAYLOAD_AEAD
+        ));
+        assert!(matches!(
+            Backup::load_from_disk_encrypted(path, &Key::generate(32).unwrap()),
+            Err(BackupError::DecryptionFailed)
+        ));
+        assert!(matches!(
+            backup.save_to_disk_encrypted(path, &Key::generate(16).unwrap()),
+            Err(BackupError::InvalidKeyLength { .. })
+        ));
+        assert!(matches!(
+            backup.save_incremental(path),
+            Err(BackupError::EncryptedPayload(_))
+        ));
+
+        backup.save_to_disk(path, None).unwrap();
+        assert!(Backup::load_from_disk(path).is_ok());
+    }
+
+    #[test]
+    fn test_save_modes() {
+        let mut fragment = Fragment::new(CompressionAlgorithm::Zstd, 3, None);
+        let (old_key, new_key) = (Sha256::new(b"old"), Sha256::new(b"new"));
+        fragment.insert(b"good backup".to_vec(), old_key).unwrap();
+        let dir = tempdir().unwrap();
+        let path = dir.path().to_str().unwrap();
+        Backup::new(fragment.clone(), None)
+            .unwrap()
+            .save_to_disk(path, None)
+            .unwrap();
+        fragment.insert(b"replacement".to_vec(), new_key).unwrap();
+        let mut backup = Backup::new(fragment, None).unwrap();
+        let holds = |path: &str, key: &Sha256| {
+            Backup::load_from_disk(path)
+                .unwrap()
+                .get_latest_version()
+                .unwrap()
+                .contains_key(key)
+        };
+
+        assert!(matches!(
+            backup.save_to_disk_with_mode(path, None, SaveMode::FailIfExists),
+            Err(BackupError::AlreadyExists(existing)) if existing == path
+        ));
+        assert!(!holds(path, &new_key));
+
+        let versioned = backup
+            .save_to_disk_with_mode(path, None, SaveMode::Versioned)
+            .unwrap();
+        assert_eq!(Path::new(&versioned).parent(), Some(dir.path()));
+        assert!(holds(&versioned, &new_key));
+        assert!(!holds(path, &new_key));
+
+        let written = backup
+            .save_to_disk_with_mode(path, None, SaveMode::Overwrite)
+            .unwrap();
+        assert_eq!(written, path);
+        assert!(holds(path, &new_key));
+
+        let fresh = dir.path().join("fresh");
+        let fresh = fresh.to_str().unwrap();
+        backup
+            .save_to_disk_with_mode(fresh, None, SaveMode::FailIfExists)
+            .unwrap();
+        assert!(holds(fresh, &old_key));
+    }
+
+    #[test]
+    fn test_encrypted_backup_hides_compression_dict() {
+        let samples: Vec<Vec<u8>> = (0..200)
+            .map(|i| {
+                format!(
+                    r#"{{"patient":"record-{}","ward":"oncology-{}","dosage_mg":{}}}"#,
+                    i,
+                    i % 5,
+                    100 + i % 17
+                )
+                .into_bytes()
+            })
+            .collect();
+        let dict = train_dictionary(&samples, 4096).unwrap();
+        let mut fragment = Fragment::new(CompressionAlgorithm::Zstd, 3, None);
+        for sample in &samples {
+            fragment
+                .insert(sample.clone(), Sha256::new(sample))
+                .unwrap();
+        }
+        let fragment = fragment.recompress(Some(dict.clone())).unwrap();
+        let dir = tempdir().unwrap();
+        let path = dir.path().to_str().unwrap();
+        let key = Key::generate(32).unwrap();
+
+        Backup::new(fragment, None)
+            .unwrap()
+            .save_to_disk_encrypted(path, &key)
+            .unwrap();
+        let needle = &dict[dict.len() - 32..];
+        for entry in std::fs::read_dir(dir.path()).unwrap() {
+            let on_disk = std::fs::read(entry.unwrap().path()).unwrap();
+            assert!(!on_disk.windows(needle.len()).any(|window| window == needle));
+        }
+
+        let loaded = Backup::load_from_disk_encrypted(path, &key).unwrap();
+        let latest = loaded.get_latest_version().unwrap();
+        assert_eq!(latest.get_metadata().compression_dict, Some(dict));
+        for sample in &samples {
+            assert_eq!(
+                latest.get(&Sha256::new(sample)).unwrap().as_ref(),
+                Some(sample)
+            );
+        }
+    }
+
+    #[test]
+    fn test_payload_checksum() {
+        let mut fragment = Fragment::new(CompressionAlgorithm::Zstd, 3, None);
+        fragment
+            .insert(vec![7u8; 256], Sha256::new(b"payload"))
+            .unwrap();
+        let dir = tempdir().unwrap();
+        let path = dir.path().to_str().unwrap();
+        let versions = dir.path().join(VERSIONS_FILE);
+        let mut backup = Backup::new(fragment, None).unwrap();
+        backup.save_to_disk(path, None).unwrap();
+        assert_eq!(
+            backup.metadata.payload_digest,
+            Some(Sha256::new(&std::fs::read(&versions).unwrap()).0)
+        );
+        assert!(Backup::load_from_disk(path).is_ok());
+
+        let mut truncated = std::fs::read(&versions).unwrap();
+        truncated.truncate(truncated.len() / 2);
+        std::fs::write(&versions, &truncated).unwrap();
+        assert!(matches!(
+            Backup::load_from_disk(path),
+            Err(BackupError::CorruptPayload)
+        ));
+
+        backup.save_to_disk(path, None).unwrap();
+        let metadata_path = dir.path().join(METADATA_FILE);
+        let mut legacy: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&metadata_path).unwrap()).unwrap();
+        legacy.as_object_mut().unwrap().remove("payload_digest");
+        std::fs::write(&metadata_path, legacy.to_string()).unwrap();
+        let loaded = Backup::load_from_disk(path).unwrap();
+        assert_eq!(loaded.metadata.payload_digest, None);
+
+        backup.save_to_disk(path, None).unwrap();
+        let mut renamed: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&metadata_path).unwrap()).unwrap();
+        let fields = renamed.as_object_mut().unwrap();
+        let digest = fields.remove("payload_digest").unwrap();
+        fields.insert("payload_sha256".to_string(), digest);
+        std::fs::write(&metadata_path, renamed.to_string()).unwrap();
+        let loaded = Backup::load_from_disk(path).unwrap();
+        assert_eq!(
+            loaded.metadata.payload_digest,
+            backup.metadata.payload_digest
+        );
+    }
+
+    #[test]
+    fn test_streamed_payload() {
+        let fragment_with = |i: u8| {
+            let mut fragment = Fragment::new(CompressionAlgorithm::Zstd, 3, None);
+            fragment.insert(vec![i; 128], Sha256::new(&[i])).unwrap();
+            fragment
+        };
+        let dir = tempdir().unwrap();
+        let path = dir.path().to_str().unwrap();
+        let mut backup = Backup::new(fragment_with(0), Some(8)).unwrap();
+        for i in 1..5 {
+            backup.add_version(fragment_with(i)).unwrap();
+        }
+        backup.tag_version(2, "old".to_string());
+        backup.save_to_disk(path, None).unwrap();
+
+        let framed = zstd::stream::decode_all(
+            std::fs::read(dir.path().join(VERSIONS_FILE))
+                .unwrap()
+                .as_slice(),
+        )
+        .unwrap();
+        let mut frames = framed.as_slice();
+        let header: PayloadHeader = read_frame(&mut frames).unwrap().unwrap();
+        assert_eq!(header.max_versions, Some(8));
+        assert_eq!(decode_frames(frames).unwrap().len(), 6);
+
+        let loaded = Backup::load_from_disk(path).unwrap();
+        assert_eq!(loaded.get_history().len(), 6);
+        assert_eq!(loaded.get_max_versions(), Some(8));
+        assert_eq!(loaded.get_version_control().get_tag("old"), Some(2));
+        assert!(loaded.get_version_control().verify_chain().is_ok());
+    }
+
+    // The fixture was written by the first release: an empty genesis version, then
+    // `alpha`, then `alpha` and `beta`, all under `max_versions` 10.
+    #[test]
+    fn test_load_legacy_backup() {
+        let legacy = tempdir().unwrap();
+        let legacy_path = legacy.path().to_str().unwrap();
+        let fixture = Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures/legacy_backup");
+        for name in [METADATA_FILE, VERSIONS_FILE] {
+            std::fs::copy(fixture.join(name), legacy.path().join(name)).unwrap();
+        }
+
+        let mut upgraded = Backup::load_from_disk(legacy_path).unwrap();
+        assert_eq!(upgraded.get_history().len(), 3);
+        assert_eq!(upgraded.get_max_versions(), Some(10));
+        assert!(upgraded.verify().is_ok());
+        assert!(upgraded.get_version_control().verify_chain().is_ok());
+        let latest = upgraded.get_latest_version().unwrap();
+        assert_eq!(
+            latest.get(&Sha256::new(b"alpha")).unwrap().unwrap(),
+            b"alpha"
+        );
+        assert_eq!(
+            latest.get(&Sha256::new(b"beta")).unwrap().unwrap(),
+            b"beta".repeat(32)
+        );
+
+        let mut next = latest.clone();
+        next.insert(b"gamma".to_vec(), Sha256::new(b"gamma"))
+            .unwrap();
+        upgraded.add_version(next).unwrap();
+        upgraded.save_incremental(legacy_path).unwrap();
+        assert_eq!(upgraded.metadata.payload_format, FRAMED_PAYLOAD_FORMAT);
+        let reloaded = Backup::load_from_disk(legacy_path).unwrap();
+        assert_eq!(reloaded.get_latest_version_number(), Some(3));
+        let latest = reloaded.get_latest_version().unwrap();
+        assert_eq!(
+            latest.get(&Sha256::new(b"alpha")).unwrap().unwrap(),
+            b"alpha"
+        );
+        assert_eq!(
+            latest.get(&Sha256::new(b"gamma")).unwrap().unwrap(),
+            b"gamma"
+        );
+    }
+
+    #[derive(Default)]
+    struct MemoryStore {
+        files: HashMap<String, Vec<u8>>,
+    }
+
+    impl BackupStore for MemoryStore {
+        fn write(&mut self, name: &str, bytes: &[u8]) -> std::io::Result<()> {
+            self.files.insert(name.to_string(), bytes.to_vec());
+            Ok(())
+        }
+
+        fn read(&self, name: &str) -> std::io::Result<Vec<u8>> {
+            self.files
+                .get(name)
+                .cloned()
+                .ok_or_else(|| std::io::ErrorKind::NotFound.into())
+        }
+
+        fn list(&self) -> std::io::Result<Vec<String>> {
+            let mut names: Vec<String> = self.files.keys().cloned().collect();
+            names.sort();
+            Ok(names)
+        }
+
+        fn remove(&mut self, name: &str) -> std::io::Result<()> {
+            self.files.remove(name);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_custom_backup_store() {
+        let fragment_with = |i: u8| {
+            let mut fragment = Fragment::new(CompressionAlgorithm::Zstd, 3, None);
+            fragment.insert(vec![i; 32], Sha256::new(&[i])).unwrap();
+            fragment
+        };
+        let mut store = MemoryStore::default();
+        let mut backup = Backup::new(fragment_with(0), None).unwrap();
+        backup.save_to_store(&mut store, None).unwrap();
+        assert_eq!(
+            store.list().unwrap(),
+            vec![MANIFEST_FILE, METADATA_FILE, VERSIONS_FILE]
+        );
+
+        backup.add_version(fragment_with(1)).unwrap();
+        backup.save_incremental_to_store(&mut store).unwrap();
+        assert!(store.exists("delta-000001.bin").unwrap());
+        let loaded = Backup::load_from_store(&store).unwrap();
+        assert_eq!(loaded.get_latest_version_number(), Some(2));
+
+        backup.save_to_store(&mut store, Some(9)).unwrap();
+        assert!(!store.exists("delta-000001.bin").unwrap());
+
+        let key = Key::generate(32).unwrap();
+        backup.save_to_store_encrypted(&mut store, &key).unwrap();
+        assert!(matches!(
+            Backup::load_from_store(&store),
+            Err(BackupError::EncryptedPayload(_))
+        ));
+        let decrypted = Backup::load_from_store_encrypted(&store, &key).unwrap();
+        assert_eq!(
+            decrypted
+                .get_latest_version()
+                .unwrap()
+                .get(&Sha256::new(&[1]))
+                .unwrap(),
+            Some(vec![1; 32])
+        );
+
+        let dir = tempdir().unwrap();
+        let mut disk = FileSystemStore::new(dir.path().join("nested"));
+        assert!(disk.list().unwrap().is_empty());
+        backup.save_to_store(&mut disk, None).unwrap();
+        assert_eq!(disk.list().unwrap(), store.list().unwrap());
+        assert!(Backup::load_from_disk(dir.path().join("nested").to_str().unwrap()).is_ok());
+    }
+
+    #[test]
+    fn test_deduplicate_shares_repeated_values() {
+        // At level 1 each version is larger than zstd's 512 KiB match window, so the
+        // stream compressor alone cannot find the repeats.
+        let blobs: Vec<Vec<u8>> = (0..3u64)
+            .map(|seed| {
+                let mut state = seed.wrapping_add(1).wrapping_mul(0x9E37_79B9_7F4A_7C15);
+                (0..256 * 1024)
+                    .map(|_| {
+                        state ^= state << 13;
+                        state ^= state >> 7;
+                        state ^= state << 17;
+                        state as u8
+                    })
+                    .collect()
+            })
+            .collect();
+        let mut base = Fragment::new(CompressionAlgorithm::Zstd, 3, None);
+        base.set_hash_mode(HashMode::Incremental);
+        for (i, blob) in blobs.into_iter().enumerate() {
+            base.insert(blob, Sha256::new(&[i as u8])).unwrap();
+        }
+        let fragment_with = |round: usize| {
+            let mut fragment = base.clone();
+            fragment
+                .insert(vec![round as u8; 8], Sha256::new(b"round"))
+                .unwrap();
+            fragment
+        };
+        let mut backup = Backup::new(fragment_with(0), None).unwrap();
+        for round in 1..3 {
+            backup.add_version(fragment_with(round)).unwrap();
+        }
+        let plain = tempdir().unwrap();
+        let pooled = tempdir().unwrap();
+        let size_of = |dir: &tempfile::TempDir| {
+            std::fs::metadata(dir.path().join(VERSIONS_FILE))
+                .unwrap()
+                .len()
+        };
+        backup
+            .save_to_disk(plain.path().to_str().unwrap(), Some(1))
+            .unwrap();
+
+        let saved = backup.deduplicate().unwrap();
+        let repeated: usize = backup.get_history()[2..]
+            .iter()
+            .flat_map(|fragment| fragment.stored_values())
+            .filter(|stored| stored.len() > DEFAULT_MIN_COMPRESS_SIZE)
+            .map(Vec::len)
+            .sum();
+        assert_eq!(saved, repeated);
+        let path = pooled.path().to_str().unwrap();
+        backup.save_to_disk(path, Some(1)).unwrap();
+        assert!(size_of(&pooled) * 2 < size_of(&plain));
+
+        let loaded = Backup::load_from_disk(path).unwrap();
+        assert!(loaded.verify().is_ok());
+        assert_eq!(loaded.get_history().len(), backup.get_history().len());
+        for (loaded, original) in loaded.get_history().iter().zip(backup.get_history()) {
+            assert_eq!(loaded.get_hash(), original.get_hash());
+            assert_eq!(
+                loaded.get(&Sha256::new(b"round")).unwrap(),
+                original.get(&Sha256::new(b"round")).unwrap()
+            );
+        }
+    }
+}
@@ -2,6 +2,10 @@ use super::{backup::Backup, fragment::Fragment};
 use asphaleia_crypto::hash::Sha256;
 use std::{
     collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex, PoisonError, RwLock,
+    },
     time::{Duration, Instant},
 };
 use thiserror::Error;
@@ -16,11 +20,46 @@ pub enum CacheError {
 
 pub struct CacheEntry {
     fragment: Fragment,
-    last_accessed: Instant,
+    last_accessed: Mutex<Instant>,
+    access_count: AtomicU64,
+    size: usize,
+    ttl: Option<Duration>,
+}
+
+impl CacheEntry {
+    fn new(fragment: Fragment, size: usize) -> Self {
+        Self {
+            fragment,
+            last_accessed: Mutex::new(Instant::now()),
+            access_count: AtomicU64::new(0),
+            size,
+            ttl: None,
+        }
+    }
+
+    fn access_count(&self) -> u64 {
+        self.access_count.load(Ordering::Relaxed)
+    }
+
+    fn last_accessed(&self) -> Instant {
+        *self
+            .last_accessed
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn touch(&self) {
+        self.access_count.fetch_add(1, Ordering::Relaxed);
+        *self
+            .last_accessed
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = Instant::now();
+    }
 }
 
 pub struct CacheConfig {
     pub max_size: usize,
+    pub max_entries: Option<usize>,
     pub ttl: Duration,
     pub eviction_strategy: EvictionStrategy,
 }
@@ -28,87 +67,100 @@ impl Default for CacheConfig {
     fn default() -> Self {
         Self {
             max_size: 1024 * 1024 * 1024,
+            max_entries: None,
             ttl: Duration::from_secs(300),
             eviction_strategy: EvictionStrategy::LeastRecentlyUsed,
         }
     }
 }
+pub type EvictionCallback = Box<dyn Fn(Sha256, Fragment) + Send + Sync>;
+
 pub enum EvictionStrategy {
     LeastRecentlyUsed,
     FirstInFirstOut,
+    LeastFrequentlyUsed,
 }
 
-pub struct CacheManager {
+struct CacheShard {
     cache: HashMap<Sha256, CacheEntry>,
-    config: CacheConfig,
+    current_bytes: usize,
 }
 
-impl CacheManager {
-    pub fn new(config: CacheConfig) -> Self {
+impl CacheShard {
+    fn new() -> Self {
         Self {
             cache: HashMap::new(),
-            config,
-        }
-    }
-
-    pub fn get(&mut self, key: &Sha256) -> Option<&Fragment> {
-        if let Some(entry) = self.cache.get_mut(key) {
-            entry.last_accessed = Instant::now();
-            Some(&entry.fragment)
-        } else {
-            None
+            current_bytes: 0,
         }
     }
 
-    pub fn insert(&mut self, fragment: Fragment) -> Result<(), CacheError> {
-        let key = Sha256::new(
-            &fragment
-                .to_bytes()
-                .map_err(|e| CacheError::InsertionError(e.to_string()))?,
-        );
-        let entry = CacheEntry {
-            fragment,
-            last_accessed: Instant::now(),
-        };
-
-        if self.cache.len() >= self.config.max_size {
-            self.evict()?;
+    fn insert(
+        &mut self,
+        key: Sha256,
+        entry: CacheEntry,
+        max_size: usize,
+        max_entries: Option<usize>,
+        strategy: &EvictionStrategy,
+    ) -> Result<Vec<(Sha256, Fragment)>, CacheError> {
+        self.remove(&key);
+        let mut evicted = Vec::new();
+        while !self.cache.is_empty()
+            && (self.current_bytes + entry.size > max_size
+                || max_entries.is_some_and(|max| self.cache.len() >= max))
+        {
+            evicted.push(self.evict(strategy)?);
         }
 
+        self.current_bytes += entry.size;
         self.cache.insert(key, entry);
-        Ok(())
+        Ok(evicted)
     }
 
-    pub fn remove(&mut self, key: &Sha256) -> Option<Fragment> {
-        self.cache.remove(key).map(|entry| entry.fragment)
+    fn remove(&mut self, key: &Sha256) -> Option<Fragment> {
+        self.cache.remove(key).map(|entry| {
+            self.current_bytes -= entry.size;
+            entry.fragment
+        })
     }
 
-    pub fn clear(&mut self) {
+    fn clear(&mut self) {
         self.cache.clear();
+        self.current_bytes = 0;
     }
 
-    pub fn evict_expired(&mut self) {
+    fn evict_expired(&mut self, ttl: Duration) -> Vec<(Sha256, Fragment)> {
         let now = Instant::now();
-        self.cache
-            .retain(|_, entry| now.duration_since(entry.last_accessed) < self.config.ttl);
+        let expired: Vec<Sha256> = self
+            .cache
+            .iter()
+            .filter(|(_, entry)| {
+                now.duration_since(entry.last_accessed()) >= entry.ttl.unwrap_or(ttl)
+            })
+            .map(|(key, _)| *key)
+            .collect();
+        expired
+            .into_iter()
+            .filter_map(|key| self.remove(&key).map(|fragment| (key, fragment)))
+            .collect()
     }
 
-    fn evict(&mut self) -> Result<(), CacheError> {
-        match self.config.eviction_strategy {
+    fn evict(&mut self, strategy: &EvictionStrategy) -> Result<(Sha256, Fragment), CacheError> {
+        match strategy {
             EvictionStrategy::LeastRecentlyUsed => self.evict_lru(),
             EvictionStrategy::FirstInFirstOut => self.evict_fifo(),
+            EvictionStrategy::LeastFrequentlyUsed => self.evict_lfu(),
         }
     }
 
-    fn evict_lru(&mut self) -> Result<(), CacheError> {
+    fn evict_lru(&mut self) -> Result<(Sha256, Fragment), CacheError> {
         if let Some(oldest_key) = self
             .cache
             .iter()
-            .min_by_key(|(_, entry)| entry.last_accessed)
+            .min_by_key(|(_, entry)| entry.last_accessed())
             .map(|(key, _)| *key)
         {
-            self.cache.remove(&oldest_key);
-            Ok(())
+            let fragment = self.remove(&oldest_key).expect("evicted key is present");
+            Ok((oldest_key, fragment))
         } else {
             Err(CacheError::InsertionError(
                 "Failed to evict LRU item".to_string(),
@@ -116,16 +168,134 @@ impl CacheManager {
         }
     }
 
-    fn evict_fifo(&mut self) -> Result<(), CacheError> {
+    fn evict_lfu(&mut self) -> Result<(Sha256, Fragment), CacheError> {
+        if let Some(coldest_key) = self
+            .cache
+            .iter()
+            .min_by_key(|(_, entry)| (entry.access_count(), entry.last_accessed()))
+            .map(|(key, _)| *key)
+        {
+            let fragment = self.remove(&coldest_key).expect("evicted key is present");
+            Ok((coldest_key, fragment))
+        } else {
+            Err(CacheError::InsertionError(
+                "Failed to evict LFU item".to_string(),
+            ))
+        }
+    }
+
+    fn evict_fifo(&mut self) -> Result<(Sha256, Fragment), CacheError> {
         if let Some(first_key) = self.cache.keys().next().cloned() {
-            self.cache.remove(&first_key);
-            Ok(())
+            let fragment = self.remove(&first_key).expect("evicted key is present");
+            Ok((first_key, fragment))
         } else {
             Err(CacheError::InsertionError(
                 "Failed to evict FIFO item".to_string(),
             ))
         }
     }
+}
+
+fn cache_entry(fragment: Fragment) -> Result<(Sha256, CacheEntry), CacheError> {
+    let bytes = fragment
+        .to_bytes()
+        .map_err(|e| CacheError::InsertionError(e.to_string()))?;
+    let key = fragment.digest(&bytes);
+    Ok((key, CacheEntry::new(fragment, bytes.len())))
+}
+
+pub struct CacheManager {
+    shard: CacheShard,
+    config: CacheConfig,
+    on_evict: Option<EvictionCallback>,
+}
+
+impl CacheManager {
+    pub fn new(config: CacheConfig) -> Self {
+        Self {
+            shard: CacheShard::new(),
+            config,
+            on_evict: None,
+        }
+    }
+
+    // Called once per entry dropped for capacity or expiry, after it has left the cache;
+    // explicit `remove` and `clear` do not count as evictions.
+    pub fn set_eviction_callback(
+        &mut self,
+        callback: impl Fn(Sha256, Fragment) + Send + Sync + 'static,
+    ) {
+        self.on_evict = Some(Box::new(callback));
+    }
+
+    fn notify_evicted(&self, evicted: Vec<(Sha256, Fragment)>) {
+        if let Some(callback) = &self.on_evict {
+            for (key, fragment) in evicted {
+                callback(key, fragment);
+            }
+        }
+    }
+
+    pub fn get(&mut self, key: &Sha256) -> Option<&Fragment> {
+        if let Some(entry) = self.shard.cache.get_mut(key) {
+            *entry.access_count.get_mut() += 1;
+            *entry
+                .last_accessed
+                .get_mut()
+                .unwrap_or_else(PoisonError::into_inner) = Instant::now();
+            Some(&entry.fragment)
+        } else {
+            None
+        }
+    }
+
+    pub fn get_shared(&self, key: &Sha256) -> Option<&Fragment> {
+        self.shard.cache.get(key).map(|entry| {
+            entry.touch();
+            &entry.fragment
+        })
+    }
+
+    // Returns the key the fragment is cached under.
+    pub fn insert(&mut self, fragment: Fragment) -> Result<Sha256, CacheError> {
+        let (key, entry) = cache_entry(fragment)?;
+        self.insert_entry(key, entry)
+    }
+
+    pub fn insert_with_ttl(
+        &mut self,
+        fragment: Fragment,
+        ttl: Duration,
+    ) -> Result<Sha256, CacheError> {
+        let (key, mut entry) = cache_entry(fragment)?;
+        entry.ttl = Some(ttl);
+        self.insert_entry(key, entry)
+    }
+
+    fn insert_entry(&mut self, key: Sha256, entry: CacheEntry) -> Result<Sha256, CacheError> {
+        let evicted = self.shard.insert(
+            key,
+            entry,
+            self.config.max_size,
+            self.config.max_entries,
+            &self.config.eviction_strategy,
+        )?;
+        self.notify_evicted(evicted);
+        Ok(key)
+    }
+
+    pub fn remove(&mut self, key: &Sha256) -> Option<Fragment> {
+        self.shard.remove(key)
+    }
+
+    pub fn clear(&mut self) {
+        self.shard.clear();
+    }
+
+    pub fn evict_expired(&mut self) {
+        let evicted = self.shard.evict_expired(self.config.ttl);
+        self.notify_evicted(evicted);
+    }
 
     pub fn load_from_backup(&mut self, backup: &Backup) -> Result<(), CacheError> {
         if let Some(fragment) = backup.get_latest_version() {
@@ -135,18 +305,404 @@ impl CacheManager {
     }
 
     pub fn get_size(&self) -> usize {
-        self.cache.len()
+        self.shard.cache.len()
+    }
+
+    pub fn current_bytes(&self) -> usize {
+        self.shard.current_bytes
     }
 
     pub fn is_empty(&self) -> bool {
-        self.cache.is_empty()
+        self.shard.cache.is_empty()
     }
 
     pub fn contains_key(&self, key: &Sha256) -> bool {
-        self.cache.contains_key(key)
+        self.shard.cache.contains_key(key)
     }
 
     pub fn update_config(&mut self, config: CacheConfig) {
         self.config = config;
     }
 }
+
+const SHARD_COUNT: usize = 16;
+
+// Shards are picked by the first byte of the key and each gets an equal slice of
+// `max_size` and `max_entries` (rounded up), so eviction only ever locks the shard
+// being written to.
+pub struct ConcurrentCacheManager {
+    shards: Vec<RwLock<CacheShard>>,
+    config: CacheConfig,
+    on_evict: Option<EvictionCallback>,
+}
+
+impl ConcurrentCacheManager {
+    pub fn new(config: CacheConfig) -> Self {
+        Self {
+            shards: (0..SHARD_COUNT)
+                .map(|_| RwLock::new(CacheShard::new()))
+                .collect(),
+            config,
+            on_evict: None,
+        }
+    }
+
+    // The shard lock is released before the callback runs, so it may use the cache.
+    pub fn set_eviction_callback(
+        &mut self,
+        callback: impl Fn(Sha256, Fragment) + Send + Sync + 'static,
+    ) {
+        self.on_evict = Some(Box::new(callback));
+    }
+
+    fn notify_evicted(&self, evicted: Vec<(Sha256, Fragment)>) {
+        if let Some(callback) = &self.on_evict {
+            for (key, fragment) in evicted {
+                callback(key, fragment);
+            }
+        }
+    }
+
+    fn shard(&self, key: &Sha256) -> &RwLock<CacheShard> {
+        &self.shards[key.0[0] as usize % SHARD_COUNT]
+    }
+
+    fn shard_budget(&self) -> usize {
+        self.config.max_size / SHARD_COUNT
+    }
+
+    fn shard_entry_budget(&self) -> Option<usize> {
+        self.config.max_entries.map(|max| max.div_ceil(SHARD_COUNT))
+    }
+
+    pub fn get(&self, key: &Sha256) -> Option<Fragment> {
+        let shard = self
+            .shard(key)
+            .read()
+            .unwrap_or_else(PoisonError::into_inner);
+        shard.cache.get(key).map(|entry| {
+            entry.touch();
+            entry.fragment.clone()
+        })
+    }
+
+    pub fn insert(&self, fragment: Fragment) -> Result<Sha256, CacheError> {
+        let (key, entry) = cache_entry(fragment)?;
+        self.insert_entry(key, entry)
+    }
+
+    pub fn insert_with_ttl(&self, fragment: Fragment, ttl: Duration) -> Result<Sha256, CacheError> {
+        let (key, mut entry) = cache_entry(fragment)?;
+        entry.ttl = Some(ttl);
+        self.insert_entry(key, entry)
+    }
+
+    fn insert_entry(&self, key: Sha256, entry: CacheEntry) -> Result<Sha256, CacheError> {
+        let evicted = self
+            .shard(&key)
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(
+                key,
+                entry,
+                self.shard_budget(),
+                self.shard_entry_budget(),
+                &self.config.eviction_strategy,
+            )?;
+        self.notify_evicted(evicted);
+        Ok(key)
+    }
+
+    pub fn remove(&self, key: &Sha256) -> Option<Fragment> {
+        self.shard(key)
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(key)
+    }
+
+    pub fn clear(&self) {
+        for shard in &self.shards {
+            shard
+                .write()
+                .unwrap_or_else(PoisonError::into_inner)
+                .clear();
+        }
+    }
+
+    pub fn evict_expired(&self) {
+        for shard in &self.shards {
+            let evicted = shard
+                .write()
+                .unwrap_or_else(PoisonError::into_inner)
+                .evict_expired(self.config.ttl);
+            self.notify_evicted(evicted);
+        }
+    }
+
+    pub fn load_from_backup(&self, backup: &Backup) -> Result<(), CacheError> {
+        if let Some(fragment) = backup.get_latest_version() {
+            self.insert(fragment.clone())?;
+        }
+        Ok(())
+    }
+
+    pub fn get_size(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| {
+                shard
+                    .read()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .cache
+                    .len()
+            })
+            .sum()
+    }
+
+    pub fn current_bytes(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| {
+                shard
+                    .read()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .current_bytes
+            })
+            .sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.get_size() == 0
+    }
+
+    pub fn contains_key(&self, key: &Sha256) -> bool {
+        self.shard(key)
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .cache
+            .contains_key(key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compression::CompressionAlgorithm;
+    use std::{sync::Arc, thread};
+
+    fn fragment_with(value: &[u8]) -> (Sha256, Fragment) {
+        let mut fragment = Fragment::new(CompressionAlgorithm::Zstd, 3, None);
+        fragment.insert(value.to_vec(), Sha256::new(value)).unwrap();
+        (Sha256::new(&fragment.to_bytes().unwrap()), fragment)
+    }
+
+    fn size_of(fragment: &Fragment) -> usize {
+        fragment.to_bytes().unwrap().len()
+    }
+
+    #[test]
+    fn test_byte_size_eviction() {
+        let fragments: Vec<_> = (0..4u8).map(|i| fragment_with(&[i; 64])).collect();
+        let budget = size_of(&fragments[0].1) * 2;
+        let mut cache = CacheManager::new(CacheConfig {
+            max_size: budget,
+            max_entries: None,
+            ttl: Duration::from_secs(300),
+            eviction_strategy: EvictionStrategy::LeastRecentlyUsed,
+        });
+
+        for (_, fragment) in &fragments {
+            cache.insert(fragment.clone()).unwrap();
+            assert!(cache.current_bytes() <= budget);
+        }
+        assert_eq!(cache.get_size(), 2);
+        assert!(cache.contains_key(&fragments[3].0));
+        assert!(!cache.contains_key(&fragments[0].0));
+
+        cache.insert(fragments[3].1.clone()).unwrap();
+        assert_eq!(cache.current_bytes(), budget);
+
+        cache.remove(&fragments[3].0);
+        assert_eq!(cache.current_bytes(), budget / 2);
+        cache.clear();
+        assert_eq!(cache.current_bytes(), 0);
+    }
+
+    #[test]
+    fn test_concurrent_cache_manager() {
+        let cache = Arc::new(ConcurrentCacheManager::new(CacheConfig::default()));
+        let fragments: Vec<_> = (0..32u8).map(|i| fragment_with(&[i; 16])).collect();
+        for (_, fragment) in &fragments {
+            cache.insert(fragment.clone()).unwrap();
+        }
+        assert_eq!(cache.get_size(), fragments.len());
+
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let cache = Arc::clone(&cache);
+                let keys: Vec<Sha256> = fragments.iter().map(|(key, _)| *key).collect();
+                thread::spawn(move || {
+                    for key in &keys {
+                        assert!(cache.get(key).is_some());
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        assert!(cache.remove(&fragments[0].0).is_some());
+        assert!(!cache.contains_key(&fragments[0].0));
+        cache.clear();
+        assert!(cache.is_empty());
+        assert_eq!(cache.current_bytes(), 0);
+    }
+
+    #[test]
+    fn test_concurrent_cache_evicts_within_shard() {
+        let candidates: Vec<_> = (0..=u8::MAX).map(|i| fragment_with(&[b's', i])).collect();
+        let (key, fragment) = candidates[0].clone();
+        let shard_of = |key: &Sha256| key.0[0] as usize % SHARD_COUNT;
+        let (neighbour_key, neighbour) = candidates[1..]
+            .iter()
+            .find(|(other, f)| {
+                shard_of(other) == shard_of(&key) && size_of(f) == size_of(&fragment)
+            })
+            .cloned()
+            .unwrap();
+
+        let cache = ConcurrentCacheManager::new(CacheConfig {
+            max_size: size_of(&fragment) * SHARD_COUNT,
+            max_entries: None,
+            ttl: Duration::from_secs(300),
+            eviction_strategy: EvictionStrategy::LeastRecentlyUsed,
+        });
+        cache.insert(fragment).unwrap();
+        cache.insert(neighbour).unwrap();
+
+        assert!(!cache.contains_key(&key));
+        assert!(cache.contains_key(&neighbour_key));
+        assert_eq!(cache.get_size(), 1);
+    }
+
+    #[test]
+    fn test_per_entry_ttl_overrides_global() {
+        let mut cache = CacheManager::new(CacheConfig {
+            ttl: Duration::from_millis(50),
+            ..CacheConfig::default()
+        });
+        let (cheap_key, cheap) = fragment_with(b"cheap");
+        let (costly_key, costly) = fragment_with(b"costly");
+        let (brief_key, brief) = fragment_with(b"brief");
+        cache.insert(cheap).unwrap();
+        cache
+            .insert_with_ttl(costly, Duration::from_secs(300))
+            .unwrap();
+        cache.insert_with_ttl(brief, Duration::ZERO).unwrap();
+
+        cache.evict_expired();
+        assert!(!cache.contains_key(&brief_key));
+        assert!(cache.contains_key(&cheap_key));
+
+        thread::sleep(Duration::from_millis(100));
+        cache.evict_expired();
+        assert!(!cache.contains_key(&cheap_key));
+        assert!(cache.contains_key(&costly_key));
+        assert_eq!(
+            cache.current_bytes(),
+            size_of(&cache.shard.cache[&costly_key].fragment)
+        );
+    }
+
+    #[test]
+    fn test_lfu_eviction_keeps_hot_entries() {
+        let (hot_key, hot) = fragment_with(b"hot");
+        let (cold_key, cold) = fragment_with(b"cold");
+        let (new_key, new) = fragment_with(b"new");
+        let mut cache = CacheManager::new(CacheConfig {
+            max_size: size_of(&hot) + size_of(&cold),
+            max_entries: None,
+            ttl: Duration::from_secs(300),
+            eviction_strategy: EvictionStrategy::LeastFrequentlyUsed,
+        });
+
+        cache.insert(hot).unwrap();
+        cache.insert(cold).unwrap();
+        for _ in 0..3 {
+            assert!(cache.get(&hot_key).is_some());
+        }
+        assert!(cache.get_shared(&cold_key).is_some());
+
+        cache.insert(new).unwrap();
+        assert!(cache.contains_key(&hot_key));
+        assert!(!cache.contains_key(&cold_key));
+        assert!(cache.contains_key(&new_key));
+    }
+
+    #[test]
+    fn test_max_entries_eviction() {
+        let fragments: Vec<_> = (0..4u8).map(|i| fragment_with(&[i])).collect();
+        let mut cache = CacheManager::new(CacheConfig {
+            max_entries: Some(2),
+            ..CacheConfig::default()
+        });
+
+        for (_, fragment) in &fragments {
+            cache.insert(fragment.clone()).unwrap();
+            assert!(cache.get_size() <= 2);
+        }
+        assert!(cache.current_bytes() < cache.config.max_size);
+        assert!(!cache.contains_key(&fragments[0].0));
+        assert!(!cache.contains_key(&fragments[1].0));
+        assert!(cache.contains_key(&fragments[3].0));
+
+        cache.insert(fragments[3].1.clone()).unwrap();
+        assert_eq!(cache.get_size(), 2);
+        assert!(cache.contains_key(&fragments[2].0));
+    }
+
+    #[test]
+    fn test_eviction_callback() {
+        let fragments: Vec<_> = (0..3u8).map(|i| fragment_with(&[i; 64])).collect();
+        let evicted = Arc::new(Mutex::new(Vec::new()));
+        let mut cache = CacheManager::new(CacheConfig {
+            max_entries: Some(2),
+            ..CacheConfig::default()
+        });
+        let sink = Arc::clone(&evicted);
+        cache.set_eviction_callback(move |key, fragment| {
+            sink.lock().unwrap().push((key, fragment));
+        });
+
+        for (_, fragment) in &fragments {
+            cache.insert(fragment.clone()).unwrap();
+        }
+        cache.remove(&fragments[2].0);
+        {
+            let evicted = evicted.lock().unwrap();
+            assert_eq!(evicted.len(), 1);
+            assert_eq!(evicted[0].0, fragments[0].0);
+            assert_eq!(evicted[0].1.get_hash(), fragments[0].1.get_hash());
+        }
+
+        let shared = Arc::new(RwLock::new(None::<Arc<ConcurrentCacheManager>>));
+        let mut concurrent = ConcurrentCacheManager::new(CacheConfig {
+            ttl: Duration::ZERO,
+            ..CacheConfig::default()
+        });
+        let reentrant = Arc::clone(&shared);
+        let sink = Arc::clone(&evicted);
+        concurrent.set_eviction_callback(move |key, fragment| {
+            let cache = reentrant.read().unwrap().clone().unwrap();
+            assert!(!cache.contains_key(&key));
+            sink.lock().unwrap().push((key, fragment));
+        });
+        let concurrent = Arc::new(concurrent);
+        *shared.write().unwrap() = Some(Arc::clone(&concurrent));
+        concurrent.insert(fragments[1].1.clone()).unwrap();
+        concurrent.evict_expired();
+        assert_eq!(evicted.lock().unwrap().len(), 2);
+        assert!(concurrent.is_empty());
+    }
+}
@@ -1,6 +1,126 @@
-use std::io::{Error, Write};
+use serde::{Deserialize, Serialize};
+use std::io::{Error, ErrorKind, Read, Write};
+use thiserror::Error;
 use zstd::{decode_all, encode_all};
 
+const BROTLI_BUFFER_SIZE: usize = 4096;
+const BROTLI_WINDOW_SIZE: u32 = 22;
+const ZSTD_MAGIC: u32 = 0xFD2F_B528;
+// Skippable frames use any magic in 0x184D2A50..=0x184D2A5F.
+const ZSTD_SKIPPABLE_MAGIC: u32 = 0x184D_2A50;
+// lz4 blocks can't expand output by more than this much per input byte.
+const LZ4_MAX_RATIO: usize = 255;
+
+#[derive(Error, Debug)]
+pub enum CompressionError {
+    #[error("IO error: {0}")]
+    IoError(#[from] Error),
+    #[error("No samples to train a dictionary from")]
+    NoSamples,
+}
+
+// Dictionaries are a zstd feature; the other algorithms ignore them.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum CompressionAlgorithm {
+    #[default]
+    Zstd,
+    Lz4,
+    Brotli,
+    None,
+}
+
+impl CompressionAlgorithm {
+    pub fn compress(
+        &self,
+        input: &[u8],
+        level: i32,
+        dictionary: Option<&[u8]>,
+    ) -> Result<Vec<u8>, Error> {
+        match (self, dictionary) {
+            (Self::Zstd, Some(dict)) => compress_bytes_with_dict(input, level, dict),
+            (Self::Zstd, None) => compress_bytes(input, level),
+            (Self::Lz4, _) => Ok(lz4_flex::compress_prepend_size(input)),
+            (Self::Brotli, _) => {
+                let mut compressed = Vec::new();
+                {
+                    let mut encoder = brotli::CompressorWriter::new(
+                        &mut compressed,
+                        BROTLI_BUFFER_SIZE,
+                        level.clamp(0, 11) as u32,
+                        BROTLI_WINDOW_SIZE,
+                    );
+                    encoder.write_all(input)?;
+                }
+                Ok(compressed)
+            }
+            (Self::None, _) => Ok(input.to_vec()),
+        }
+    }
+
+    pub fn decompress(&self, input: &[u8], dictionary: Option<&[u8]>) -> Result<Vec<u8>, Error> {
+        let mut decompressed = Vec::new();
+        self.decompress_into(input, dictionary, &mut decompressed)?;
+        Ok(decompressed)
+    }
+
+    // Appends to `output`, so a caller can reuse one buffer across many values.
+    pub fn decompress_into(
+        &self,
+        input: &[u8],
+        dictionary: Option<&[u8]>,
+        output: &mut Vec<u8>,
+    ) -> Result<(), Error> {
+        match (self, dictionary) {
+            (Self::Zstd, Some(dict)) => {
+                let mut decoder = zstd::Decoder::with_dictionary(input, dict)?;
+                std::io::copy(&mut decoder, output)?;
+            }
+            (Self::Zstd, None) => {
+                zstd::stream::copy_decode(input, &mut *output)?;
+            }
+            (Self::Lz4, _) => {
+                let (size, block) = input
+                    .split_first_chunk::<4>()
+                    .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Truncated lz4 block"))?;
+                let size = u32::from_le_bytes(*size) as usize;
+                if size > block.len().saturating_mul(LZ4_MAX_RATIO) {
+                    return Err(Error::new(ErrorKind::InvalidData, "Implausible lz4 size"));
+                }
+                let start = output.len();
+                output.resize(start + size, 0);
+                let written = lz4_flex::block::decompress_into(block, &mut output[start..])
+                    .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+                output.truncate(start + written);
+            }
+            (Self::Brotli, _) => {
+                brotli::Decompressor::new(input, BROTLI_BUFFER_SIZE).read_to_end(output)?;
+            }
+            (Self::None, _) => output.extend_from_slice(input),
+        }
+        Ok(())
+    }
+}
+
+// Recognises zstd frames by their magic number and the size-prefixed lz4 blocks written
+// by `CompressionAlgorithm::Lz4`, which carry no magic, by decoding them fully. Brotli
+// streams have no reliable signature and are never detected.
+pub fn detect_algorithm(bytes: &[u8]) -> Option<CompressionAlgorithm> {
+    let (prefix, block) = bytes.split_first_chunk::<4>()?;
+    let magic = u32::from_le_bytes(*prefix);
+    if magic == ZSTD_MAGIC || magic & !0xF == ZSTD_SKIPPABLE_MAGIC {
+        return Some(CompressionAlgorithm::Zstd);
+    }
+    let size = magic as usize;
+    if size == 0 || block.is_empty() || size > block.len().saturating_mul(LZ4_MAX_RATIO) {
+        return None;
+    }
+    let mut output = vec![0; size];
+    match lz4_flex::block::decompress_into(block, &mut output) {
+        Ok(written) if written == size => Some(CompressionAlgorithm::Lz4),
+        _ => None,
+    }
+}
+
 pub fn compress_bytes(input: &[u8], level: i32) -> Result<Vec<u8>, Error> {
     encode_all(input, level)
 }
@@ -30,3 +150,33 @@ pub fn decompress_bytes_with_dict(
     std::io::copy(&mut decoder, &mut decompressed)?;
     Ok(decompressed)
 }
+
+pub fn train_dictionary(
+    samples: &[Vec<u8>],
+    dict_size: usize,
+) -> Result<Vec<u8>, CompressionError> {
+    if samples.is_empty() {
+        return Err(CompressionError::NoSamples);
+    }
+    Ok(zstd::dict::from_samples(samples, dict_size)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detect_algorithm() {
+        let input = b"detect me, detect me, detect me, detect me".repeat(8);
+        let zstd = CompressionAlgorithm::Zstd
+            .compress(&input, 3, None)
+            .unwrap();
+        let lz4 = CompressionAlgorithm::Lz4.compress(&input, 3, None).unwrap();
+        assert_eq!(detect_algorithm(&zstd), Some(CompressionAlgorithm::Zstd));
+        assert_eq!(detect_algorithm(&lz4), Some(CompressionAlgorithm::Lz4));
+
+        assert_eq!(detect_algorithm(&input), None);
+        assert_eq!(detect_algorithm(&[]), None);
+        assert_eq!(detect_algorithm(&lz4[..lz4.len() - 1]), None);
+    }
+}
@@ -0,0 +1,51 @@
+use asphaleia_crypto::hash::{Blake3, Blake3Hasher, Sha256, Sha256Hasher};
+use serde::{Deserialize, Serialize};
+
+// The digest behind fragment hashes, content-addressed keys and cache keys. Each fragment
+// records its own in its metadata, so data written under one algorithm keeps verifying
+// and resolving after later fragments switch. Both produce 32 bytes carried in `Sha256`.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum HashAlgorithm {
+    #[default]
+    Sha3_256,
+    Blake3,
+}
+
+impl HashAlgorithm {
+    pub fn digest(self, data: &[u8]) -> Sha256 {
+        match self {
+            Self::Sha3_256 => Sha256::new(data),
+            Self::Blake3 => Sha256(Blake3::new(data).0),
+        }
+    }
+}
+
+// Incremental form of `HashAlgorithm::digest`, for inputs that are cheaper to feed in
+// pieces.
+pub(crate) enum Digester {
+    Sha3_256(Box<Sha256Hasher>),
+    Blake3(Box<Blake3Hasher>),
+}
+
+impl Digester {
+    pub(crate) fn new(algorithm: HashAlgorithm) -> Self {
+        match algorithm {
+            HashAlgorithm::Sha3_256 => Self::Sha3_256(Box::default()),
+            HashAlgorithm::Blake3 => Self::Blake3(Box::default()),
+        }
+    }
+
+    pub(crate) fn update(&mut self, data: &[u8]) {
+        match self {
+            Self::Sha3_256(hasher) => hasher.update(data),
+            Self::Blake3(hasher) => hasher.update(data),
+        }
+    }
+
+    pub(crate) fn finalize(self) -> Sha256 {
+        match self {
+            Self::Sha3_256(hasher) => hasher.finalize(),
+            Self::Blake3(hasher) => Sha256(hasher.finalize().0),
+        }
+    }
+}
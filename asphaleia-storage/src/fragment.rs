@@ -1,14 +1,26 @@
 use super::{
-    compression::{
-        compress_bytes, compress_bytes_with_dict, decompress_bytes, decompress_bytes_with_dict,
-    },
+    compression::{detect_algorithm, CompressionAlgorithm},
+    digest::{Digester, HashAlgorithm},
     table::Table,
 };
-use asphaleia_crypto::hash::Sha256;
+use asphaleia_crypto::{
+    ed25519::Ed25519,
+    ed25519_dalek::{Signature, Verifier, VerifyingKey},
+    hash::{Sha256, ToHex},
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
 use serde::{Deserialize, Serialize};
-use std::time::SystemTime;
+use std::{collections::BTreeMap, io::Write, time::SystemTime};
 use thiserror::Error;
 
+pub const DEFAULT_MIN_COMPRESS_SIZE: usize = 64;
+
+pub const DEFAULT_ADAPTIVE_SAMPLE_SIZE: usize = 4096;
+
+// Every stored value starts with one of these tags.
+const RAW_TAG: u8 = 0;
+const COMPRESSED_TAG: u8 = 1;
+
 #[derive(Error, Debug)]
 pub enum FragmentError {
     #[error("Compression error: {0}")]
@@ -17,16 +29,52 @@ pub enum FragmentError {
     DecompressionError(String),
     #[error("Serialization error: {0}")]
     SerializationError(#[from] bincode::Error),
+    #[error("IO error: {0}")]
+    IoError(#[from] std::io::Error),
+    #[error("Checksum mismatch for key {}", .key.encode_hex::<String>())]
+    ChecksumMismatch { key: Sha256 },
+    #[error("Expected {expected:?} data, found {detected:?}")]
+    AlgorithmMismatch {
+        expected: CompressionAlgorithm,
+        detected: Option<CompressionAlgorithm>,
+    },
+}
+
+// `Incremental` folds a digest per entry into a running sum so a mutation costs O(1)
+// instead of rehashing the whole table. That sum (AdHash) is only good for noticing that
+// a table changed: generalized-birthday attacks find colliding tables far below 2^128
+// work, so use `Full` where the hash addresses content or must reveal tampering.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum HashMode {
+    #[default]
+    Full,
+    Incremental,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Metadata {
     pub creation_date: SystemTime,
     pub last_modified: SystemTime,
-    pub compression: String,
+    pub compression: CompressionAlgorithm,
     pub compression_level: i32,
     pub compression_dict: Option<Vec<u8>>,
+    #[serde(default)]
+    pub min_compress_size: usize,
+    #[serde(default)]
+    pub adaptive_sample_size: Option<usize>,
+    #[serde(default)]
+    pub checksums: bool,
     pub size: usize,
+    #[serde(default)]
+    pub expirations: BTreeMap<Sha256, SystemTime>,
+    #[serde(default)]
+    pub hash_mode: HashMode,
+    // Unset for fragments written before values carried a tag byte; every value in
+    // those is compressed with `compression` and has no tag.
+    #[serde(default)]
+    pub tagged_values: bool,
+    #[serde(default)]
+    pub hash_algorithm: HashAlgorithm,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -34,16 +82,18 @@ pub struct Fragment {
     table: Table,
     hash: Sha256,
     metadata: Metadata,
+    #[serde(default)]
+    accumulator: [u8; 32],
 }
 
 impl Fragment {
     pub fn new(
-        compression: String,
+        compression: CompressionAlgorithm,
         compression_level: i32,
         compression_dict: Option<Vec<u8>>,
     ) -> Self {
         let table = Table::new();
-        let hash = Sha256::new(&table.to_bytes());
+        let hash = table_digest(HashAlgorithm::default(), &table);
         let now = SystemTime::now();
         let metadata = Metadata {
             creation_date: now,
@@ -51,19 +101,268 @@ impl Fragment {
             compression,
             compression_level,
             compression_dict,
+            min_compress_size: DEFAULT_MIN_COMPRESS_SIZE,
+            adaptive_sample_size: None,
+            checksums: false,
             size: 0,
+            expirations: BTreeMap::new(),
+            hash_mode: HashMode::Full,
+            tagged_values: true,
+            hash_algorithm: HashAlgorithm::default(),
         };
         Self {
             table,
             hash,
             metadata,
+            accumulator: [0u8; 32],
+        }
+    }
+
+    pub fn with_min_compress_size(mut self, min_compress_size: usize) -> Self {
+        self.metadata.min_compress_size = min_compress_size;
+        self
+    }
+
+    // Adopts entries from the pre-tag layout, which compressed every value with zstd.
+    pub(crate) fn from_untagged(
+        compression_level: i32,
+        compression_dict: Option<Vec<u8>>,
+        creation_date: SystemTime,
+        last_modified: SystemTime,
+        entries: BTreeMap<Sha256, Vec<u8>>,
+    ) -> Self {
+        let mut fragment = Self::new(
+            CompressionAlgorithm::Zstd,
+            compression_level,
+            compression_dict,
+        );
+        for (key, stored) in entries {
+            fragment.table.insert(stored, key);
         }
+        fragment.metadata.tagged_values = false;
+        fragment.metadata.size = fragment.table.len();
+        fragment.metadata.creation_date = creation_date;
+        fragment.metadata.last_modified = last_modified;
+        fragment.hash = fragment.compute_hash();
+        fragment
     }
 
     pub fn get_hash(&self) -> &Sha256 {
         &self.hash
     }
 
+    pub fn compute_hash(&self) -> Sha256 {
+        match self.metadata.hash_mode {
+            HashMode::Full => table_digest(self.metadata.hash_algorithm, &self.table),
+            HashMode::Incremental => finalize_accumulator(
+                self.metadata.hash_algorithm,
+                &self.full_accumulator(),
+                self.table.len(),
+            ),
+        }
+    }
+
+    // Hashes the decompressed key/value pairs, so fragments holding the same data share
+    // an id whatever their compression algorithm, level or dictionary.
+    pub fn content_id(&self) -> Result<Sha256, FragmentError> {
+        let mut bytes = Vec::new();
+        for (key, stored) in self.table.iter() {
+            let value = self.decompress_value(stored)?;
+            bytes.extend_from_slice(key.as_bytes());
+            bytes.extend_from_slice(&(value.len() as u64).to_be_bytes());
+            bytes.extend_from_slice(&value);
+        }
+        Ok(self.metadata.hash_algorithm.digest(&bytes))
+    }
+
+    pub fn sign(&self, signer: &Ed25519) -> Result<Signature, FragmentError> {
+        Ok(signer.sign(self.content_id()?.as_bytes()))
+    }
+
+    pub fn verify_signature(&self, verifying_key: &VerifyingKey, signature: &Signature) -> bool {
+        self.content_id()
+            .is_ok_and(|id| verifying_key.verify(id.as_bytes(), signature).is_ok())
+    }
+
+    // Rehashes the table under `hash_algorithm`; keys are stored as given and unchanged.
+    pub fn with_hash_algorithm(mut self, hash_algorithm: HashAlgorithm) -> Self {
+        self.metadata.hash_algorithm = hash_algorithm;
+        if self.metadata.hash_mode == HashMode::Incremental {
+            self.accumulator = self.full_accumulator();
+        }
+        self.hash = self.compute_hash();
+        self
+    }
+
+    // Digests `data` with the algorithm behind this fragment's hash.
+    pub fn digest(&self, data: &[u8]) -> Sha256 {
+        self.metadata.hash_algorithm.digest(data)
+    }
+
+    pub fn set_hash_mode(&mut self, hash_mode: HashMode) {
+        self.metadata.hash_mode = hash_mode;
+        self.accumulator = match hash_mode {
+            HashMode::Full => [0u8; 32],
+            HashMode::Incremental => self.full_accumulator(),
+        };
+        self.update_hash();
+    }
+
+    // With sampling on, values whose first `sample_size` bytes compress by less than
+    // 10% are stored raw without compressing the rest.
+    pub fn set_adaptive_sampling(&mut self, sample_size: Option<usize>) {
+        self.metadata.adaptive_sample_size = sample_size;
+    }
+
+    // Expired entries are carried over along with their expiry times.
+    pub fn recompress(&self, compression_dict: Option<Vec<u8>>) -> Result<Self, FragmentError> {
+        self.migrate(
+            self.metadata.compression,
+            self.metadata.compression_level,
+            compression_dict,
+        )
+    }
+
+    // Re-encodes every value under new compression settings, like `recompress`.
+    pub fn migrate(
+        &self,
+        compression: CompressionAlgorithm,
+        compression_level: i32,
+        compression_dict: Option<Vec<u8>>,
+    ) -> Result<Self, FragmentError> {
+        let mut fragment = self.migration_target(compression, compression_level, compression_dict);
+        for (key, stored) in self.table.iter() {
+            fragment.insert(self.decompress_value(stored)?, *key)?;
+        }
+        fragment.metadata.expirations = self.metadata.expirations.clone();
+        Ok(fragment)
+    }
+
+    // Same result as `migrate`, with values decoded and re-encoded across the rayon pool.
+    #[cfg(feature = "rayon")]
+    pub fn migrate_parallel(
+        &self,
+        compression: CompressionAlgorithm,
+        compression_level: i32,
+        compression_dict: Option<Vec<u8>>,
+    ) -> Result<Self, FragmentError> {
+        use rayon::prelude::*;
+
+        let mut fragment = self.migration_target(compression, compression_level, compression_dict);
+        let entries: Vec<(&Sha256, &Vec<u8>)> = self.table.iter().collect();
+        let encoded = entries
+            .into_par_iter()
+            .map(|(key, stored)| {
+                let value = self.decompress_value(stored)?;
+                Ok((*key, fragment.compress_value(&value)?))
+            })
+            .collect::<Result<Vec<_>, FragmentError>>()?;
+        for (key, stored) in encoded {
+            let checksum = crc32fast::hash(&stored);
+            fragment.table.insert(stored, key);
+            if fragment.metadata.checksums {
+                fragment.table.set_checksum(key, checksum);
+            }
+        }
+        if fragment.metadata.hash_mode == HashMode::Incremental {
+            fragment.accumulator = fragment.full_accumulator();
+        }
+        fragment.metadata.size = fragment.table.len();
+        fragment.metadata.expirations = self.metadata.expirations.clone();
+        fragment.update_hash();
+        Ok(fragment)
+    }
+
+    fn migration_target(
+        &self,
+        compression: CompressionAlgorithm,
+        compression_level: i32,
+        compression_dict: Option<Vec<u8>>,
+    ) -> Self {
+        let metadata = Metadata {
+            creation_date: self.metadata.creation_date,
+            last_modified: self.metadata.last_modified,
+            compression,
+            compression_level,
+            compression_dict,
+            min_compress_size: self.metadata.min_compress_size,
+            adaptive_sample_size: self.metadata.adaptive_sample_size,
+            checksums: self.metadata.checksums,
+            size: 0,
+            expirations: BTreeMap::new(),
+            hash_mode: self.metadata.hash_mode,
+            tagged_values: true,
+            hash_algorithm: self.metadata.hash_algorithm,
+        };
+        let table = Table::new();
+        let mut fragment = Self {
+            hash: table_digest(metadata.hash_algorithm, &table),
+            table,
+            metadata,
+            accumulator: [0u8; 32],
+        };
+        fragment.update_hash();
+        fragment
+    }
+
+    // Enabling backfills a CRC32 of every stored value; entries written while
+    // disabled have no checksum and are read unverified.
+    pub fn set_checksums(&mut self, enabled: bool) {
+        self.metadata.checksums = enabled;
+        if enabled {
+            let checksums: Vec<(Sha256, u32)> = self
+                .table
+                .iter()
+                .map(|(key, stored)| (*key, crc32fast::hash(stored)))
+                .collect();
+            for (key, checksum) in checksums {
+                self.table.set_checksum(key, checksum);
+            }
+        }
+    }
+
+    // Returns whether the stored hash had drifted from the table contents.
+    pub fn repair_hash(&mut self) -> bool {
+        if self.metadata.hash_mode == HashMode::Incremental {
+            self.accumulator = self.full_accumulator();
+        }
+        let hash = self.compute_hash();
+        if hash == self.hash {
+            return false;
+        }
+        self.hash = hash;
+        true
+    }
+
+    fn full_accumulator(&self) -> [u8; 32] {
+        let mut accumulator = [0u8; 32];
+        for (key, value) in self.table.iter() {
+            add_assign(
+                &mut accumulator,
+                &entry_digest(self.metadata.hash_algorithm, key, value),
+            );
+        }
+        accumulator
+    }
+
+    fn track(&mut self, key: &Sha256, removed: Option<&[u8]>, added: Option<&[u8]>) {
+        if self.metadata.hash_mode != HashMode::Incremental {
+            return;
+        }
+        if let Some(value) = removed {
+            sub_assign(
+                &mut self.accumulator,
+                &entry_digest(self.metadata.hash_algorithm, key, value),
+            );
+        }
+        if let Some(value) = added {
+            add_assign(
+                &mut self.accumulator,
+                &entry_digest(self.metadata.hash_algorithm, key, value),
+            );
+        }
+    }
+
     pub fn get_metadata(&self) -> &Metadata {
         &self.metadata
     }
@@ -73,93 +372,244 @@ impl Fragment {
         value: Vec<u8>,
         key: Sha256,
     ) -> Result<Option<Vec<u8>>, FragmentError> {
-        let compressed_value = match &self.metadata.compression_dict {
-            Some(dict) => compress_bytes_with_dict(&value, self.metadata.compression_level, dict)
-                .map_err(|e| FragmentError::CompressionError(e.to_string()))?,
-            None => compress_bytes(&value, self.metadata.compression_level)
-                .map_err(|e| FragmentError::CompressionError(e.to_string()))?,
-        };
+        let compressed_value = self.compress_value(&value)?;
+        Ok(self.store(compressed_value, key))
+    }
+
+    // Stores bytes already compressed with the fragment's algorithm and dictionary as-is.
+    // With `validate`, zstd and lz4 input is checked with `detect_algorithm` and brotli
+    // input by decoding it, so data from another codec is refused instead of stored.
+    pub fn insert_raw(
+        &mut self,
+        compressed: Vec<u8>,
+        key: Sha256,
+        validate: bool,
+    ) -> Result<Option<Vec<u8>>, FragmentError> {
+        let expected = self.metadata.compression;
+        if expected == CompressionAlgorithm::None {
+            return Ok(self.store(tagged(RAW_TAG, &compressed), key));
+        }
+        if validate {
+            let detected = match expected {
+                CompressionAlgorithm::Brotli => expected
+                    .decompress(&compressed, None)
+                    .is_ok()
+                    .then_some(expected)
+                    .or_else(|| detect_algorithm(&compressed)),
+                _ => detect_algorithm(&compressed),
+            };
+            if detected != Some(expected) {
+                return Err(FragmentError::AlgorithmMismatch { expected, detected });
+            }
+        }
+        Ok(self.store(tagged(COMPRESSED_TAG, &compressed), key))
+    }
+
+    fn store(&mut self, compressed_value: Vec<u8>, key: Sha256) -> Option<Vec<u8>> {
+        self.tag_values();
+        let checksum = self
+            .metadata
+            .checksums
+            .then(|| crc32fast::hash(&compressed_value));
+        self.track(&key, None, Some(&compressed_value));
         let result = self.table.insert(compressed_value, key);
+        if let Some(checksum) = checksum {
+            self.table.set_checksum(key, checksum);
+        }
+        self.track(&key, result.as_deref(), None);
+        self.metadata.expirations.remove(&key);
         self.update_hash();
         self.metadata.size = self.table.len();
+        result
+    }
+
+    pub fn insert_with_expiry(
+        &mut self,
+        value: Vec<u8>,
+        key: Sha256,
+        expires_at: SystemTime,
+    ) -> Result<Option<Vec<u8>>, FragmentError> {
+        let result = self.insert(value, key)?;
+        self.metadata.expirations.insert(key, expires_at);
         Ok(result)
     }
 
+    pub fn get_expiry(&self, key: &Sha256) -> Option<&SystemTime> {
+        self.metadata.expirations.get(key)
+    }
+
+    pub fn is_expired(&self, key: &Sha256) -> bool {
+        self.is_expired_at(key, SystemTime::now())
+    }
+
+    pub fn purge_expired(&mut self) -> usize {
+        let now = SystemTime::now();
+        let expired: Vec<Sha256> = self
+            .metadata
+            .expirations
+            .iter()
+            .filter(|(_, expires_at)| **expires_at <= now)
+            .map(|(key, _)| *key)
+            .collect();
+        for key in &expired {
+            let removed = self.table.remove(key);
+            self.track(key, removed.as_deref(), None);
+            self.metadata.expirations.remove(key);
+        }
+        if !expired.is_empty() {
+            self.update_hash();
+            self.metadata.size = self.table.len();
+        }
+        expired.len()
+    }
+
     pub fn get(&self, key: &Sha256) -> Result<Option<Vec<u8>>, FragmentError> {
+        if self.is_expired(key) {
+            return Ok(None);
+        }
         self.table
             .get(key)
-            .map(|compressed_value| match &self.metadata.compression_dict {
-                Some(dict) => decompress_bytes_with_dict(compressed_value, dict)
-                    .map_err(|e| FragmentError::DecompressionError(e.to_string())),
-                None => decompress_bytes(compressed_value)
-                    .map_err(|e| FragmentError::DecompressionError(e.to_string())),
+            .map(|compressed_value| {
+                self.verify_checksum(key, compressed_value)?;
+                self.decompress_value(compressed_value)
             })
             .transpose()
     }
 
     pub fn remove(&mut self, key: &Sha256) -> Option<Vec<u8>> {
+        let expired = self.is_expired(key);
         let result = self.table.remove(key);
+        self.track(key, result.as_deref(), None);
+        self.metadata.expirations.remove(key);
         self.update_hash();
         self.metadata.size = self.table.len();
+        if expired {
+            return None;
+        }
 
-        result.map(|compressed| match &self.metadata.compression_dict {
-            Some(dict) => {
-                decompress_bytes_with_dict(&compressed, dict).expect("Failed to decompress value")
-            }
-            None => decompress_bytes(&compressed).expect("Failed to decompress value"),
+        result.map(|compressed| {
+            self.decompress_value(&compressed)
+                .expect("Failed to decompress value")
         })
     }
 
     pub fn contains_key(&self, key: &Sha256) -> bool {
-        self.table.contains_key(key)
+        self.table.contains_key(key) && !self.is_expired(key)
     }
 
+    // Expired entries still held until `purge_expired` are not counted.
     pub fn len(&self) -> usize {
-        self.table.len()
+        let now = SystemTime::now();
+        let expired = self
+            .metadata
+            .expirations
+            .iter()
+            .filter(|(key, expires_at)| **expires_at <= now && self.table.contains_key(key))
+            .count();
+        self.table.len() - expired
+    }
+
+    pub fn compressed_size(&self) -> usize {
+        self.table.values().map(Vec::len).sum()
+    }
+
+    pub fn decompressed_size(&self) -> Result<usize, FragmentError> {
+        self.table.values().try_fold(0, |total, stored| {
+            let len = match self.split_tag(stored) {
+                Some((&RAW_TAG, value)) => value.len(),
+                _ => self.decompress_value(stored)?.len(),
+            };
+            Ok(total + len)
+        })
+    }
+
+    // Decompressed bytes per stored byte; an empty fragment reports 1.0.
+    pub fn compression_ratio(&self) -> Result<f64, FragmentError> {
+        let compressed = self.compressed_size();
+        if compressed == 0 {
+            return Ok(1.0);
+        }
+        Ok(self.decompressed_size()? as f64 / compressed as f64)
     }
 
     pub fn is_empty(&self) -> bool {
-        self.table.is_empty()
+        self.len() == 0
     }
 
     pub fn clear(&mut self) {
         self.table.clear();
+        self.accumulator = [0u8; 32];
+        self.metadata.expirations.clear();
         self.update_hash();
         self.metadata.size = 0;
     }
 
     pub fn iter(&self) -> impl Iterator<Item = Result<(&Sha256, Vec<u8>), FragmentError>> {
-        self.table.iter().map(|(key, compressed_value)| {
-            let decompressed_value = match &self.metadata.compression_dict {
-                Some(dict) => decompress_bytes_with_dict(compressed_value, dict)
-                    .map_err(|e| FragmentError::DecompressionError(e.to_string()))?,
-                None => decompress_bytes(compressed_value)
-                    .map_err(|e| FragmentError::DecompressionError(e.to_string()))?,
-            };
+        self.live_entries().map(|(key, compressed_value)| {
+            let decompressed_value = self.decompress_value(compressed_value)?;
             Ok((key, decompressed_value))
         })
     }
 
     pub fn iter_mut(&mut self) -> impl Iterator<Item = (&Sha256, &mut Vec<u8>)> {
+        self.tag_values();
         self.table.iter_mut()
     }
 
     pub fn keys(&self) -> impl Iterator<Item = &Sha256> {
-        self.table.keys()
+        self.live_entries().map(|(key, _)| key)
     }
 
     pub fn values(&self) -> impl Iterator<Item = Result<Vec<u8>, FragmentError>> + '_ {
-        self.table
-            .values()
-            .map(|compressed_value| match &self.metadata.compression_dict {
-                Some(dict) => decompress_bytes_with_dict(compressed_value, dict)
-                    .map_err(|e| FragmentError::DecompressionError(e.to_string())),
-                None => decompress_bytes(compressed_value)
-                    .map_err(|e| FragmentError::DecompressionError(e.to_string())),
-            })
+        self.live_entries()
+            .map(|(_, compressed_value)| self.decompress_value(compressed_value))
+    }
+
+    // Each value is decompressed only when the iterator is advanced, so dropping it
+    // before calling `next` keeps at most one value alive.
+    pub fn values_streaming(&self) -> impl Iterator<Item = Result<Vec<u8>, FragmentError>> + '_ {
+        self.values()
+    }
+
+    // Decompresses every live value into one reused buffer; `f` sees a borrowed slice
+    // that is only valid for the duration of the call.
+    pub fn for_each_value<F>(&self, mut f: F) -> Result<(), FragmentError>
+    where
+        F: FnMut(&Sha256, &[u8]) -> Result<(), FragmentError>,
+    {
+        let mut scratch = Vec::new();
+        for (key, stored) in self.live_entries() {
+            self.verify_checksum(key, stored)?;
+            match self.split_tag(stored) {
+                Some((&RAW_TAG, value)) => f(key, value)?,
+                Some((&COMPRESSED_TAG, compressed)) => {
+                    scratch.clear();
+                    self.metadata
+                        .compression
+                        .decompress_into(
+                            compressed,
+                            self.metadata.compression_dict.as_deref(),
+                            &mut scratch,
+                        )
+                        .map_err(|e| FragmentError::DecompressionError(e.to_string()))?;
+                    f(key, &scratch)?;
+                }
+                _ => {
+                    return Err(FragmentError::DecompressionError(
+                        "Unknown value tag".to_string(),
+                    ))
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn stored_values(&self) -> impl Iterator<Item = &Vec<u8>> {
+        self.table.values()
     }
 
     pub fn values_mut(&mut self) -> impl Iterator<Item = &mut Vec<u8>> {
+        self.tag_values();
         self.table.values_mut()
     }
 
@@ -167,11 +617,17 @@ impl Fragment {
         &mut self,
         key: Sha256,
     ) -> std::collections::btree_map::Entry<'_, Sha256, Vec<u8>> {
+        self.tag_values();
         self.table.entry(key)
     }
 
     pub fn append(&mut self, other: &mut Fragment) {
+        self.tag_values();
+        other.tag_values();
         self.table.append(&mut other.table);
+        if self.metadata.hash_mode == HashMode::Incremental {
+            self.accumulator = self.full_accumulator();
+        }
         self.update_hash();
         self.metadata.size = self.table.len();
     }
@@ -183,49 +639,42 @@ impl Fragment {
     where
         R: std::ops::RangeBounds<Sha256>,
     {
-        self.table.range(range).map(|(key, compressed_value)| {
-            let decompressed_value = match &self.metadata.compression_dict {
-                Some(dict) => decompress_bytes_with_dict(compressed_value, dict)
-                    .map_err(|e| FragmentError::DecompressionError(e.to_string()))?,
-                None => decompress_bytes(compressed_value)
-                    .map_err(|e| FragmentError::DecompressionError(e.to_string()))?,
-            };
-            Ok((key, decompressed_value))
-        })
+        let now = SystemTime::now();
+        self.table
+            .range(range)
+            .filter(move |(key, _)| !self.is_expired_at(key, now))
+            .map(|(key, compressed_value)| {
+                let decompressed_value = self.decompress_value(compressed_value)?;
+                Ok((key, decompressed_value))
+            })
     }
 
     pub fn range_mut<R>(&mut self, range: R) -> impl Iterator<Item = (&Sha256, &mut Vec<u8>)>
     where
         R: std::ops::RangeBounds<Sha256>,
     {
+        self.tag_values();
         self.table.range_mut(range)
     }
 
     pub fn first_key_value(&self) -> Result<Option<(&Sha256, Vec<u8>)>, FragmentError> {
-        self.table
-            .first_key_value()
+        self.live_entries()
+            .next()
             .map(|(key, compressed_value)| {
-                let decompressed_value = match &self.metadata.compression_dict {
-                    Some(dict) => decompress_bytes_with_dict(compressed_value, dict)
-                        .map_err(|e| FragmentError::DecompressionError(e.to_string()))?,
-                    None => decompress_bytes(compressed_value)
-                        .map_err(|e| FragmentError::DecompressionError(e.to_string()))?,
-                };
+                let decompressed_value = self.decompress_value(compressed_value)?;
                 Ok((key, decompressed_value))
             })
             .transpose()
     }
 
     pub fn last_key_value(&self) -> Result<Option<(&Sha256, Vec<u8>)>, FragmentError> {
+        let now = SystemTime::now();
         self.table
-            .last_key_value()
+            .iter()
+            .rev()
+            .find(|(key, _)| !self.is_expired_at(key, now))
             .map(|(key, compressed_value)| {
-                let decompressed_value = match &self.metadata.compression_dict {
-                    Some(dict) => decompress_bytes_with_dict(compressed_value, dict)
-                        .map_err(|e| FragmentError::DecompressionError(e.to_string()))?,
-                    None => decompress_bytes(compressed_value)
-                        .map_err(|e| FragmentError::DecompressionError(e.to_string()))?,
-                };
+                let decompressed_value = self.decompress_value(compressed_value)?;
                 Ok((key, decompressed_value))
             })
             .transpose()
@@ -234,27 +683,27 @@ impl Fragment {
     pub fn first_entry(
         &mut self,
     ) -> Option<std::collections::btree_map::OccupiedEntry<'_, Sha256, Vec<u8>>> {
+        self.tag_values();
         self.table.first_entry()
     }
 
     pub fn last_entry(
         &mut self,
     ) -> Option<std::collections::btree_map::OccupiedEntry<'_, Sha256, Vec<u8>>> {
+        self.tag_values();
         self.table.last_entry()
     }
 
     pub fn pop_first(&mut self) -> Result<Option<(Sha256, Vec<u8>)>, FragmentError> {
         let result = self.table.pop_first();
+        if let Some((key, value)) = &result {
+            self.track(key, Some(value), None);
+        }
         self.update_hash();
         self.metadata.size = self.table.len();
         result
             .map(|(key, compressed_value)| {
-                let decompressed_value = match &self.metadata.compression_dict {
-                    Some(dict) => decompress_bytes_with_dict(&compressed_value, dict)
-                        .map_err(|e| FragmentError::DecompressionError(e.to_string()))?,
-                    None => decompress_bytes(&compressed_value)
-                        .map_err(|e| FragmentError::DecompressionError(e.to_string()))?,
-                };
+                let decompressed_value = self.decompress_value(&compressed_value)?;
                 Ok((key, decompressed_value))
             })
             .transpose()
@@ -262,26 +711,154 @@ impl Fragment {
 
     pub fn pop_last(&mut self) -> Result<Option<(Sha256, Vec<u8>)>, FragmentError> {
         let result = self.table.pop_last();
+        if let Some((key, value)) = &result {
+            self.track(key, Some(value), None);
+        }
         self.update_hash();
         self.metadata.size = self.table.len();
         result
             .map(|(key, compressed_value)| {
-                let decompressed_value = match &self.metadata.compression_dict {
-                    Some(dict) => decompress_bytes_with_dict(&compressed_value, dict)
-                        .map_err(|e| FragmentError::DecompressionError(e.to_string()))?,
-                    None => decompress_bytes(&compressed_value)
-                        .map_err(|e| FragmentError::DecompressionError(e.to_string()))?,
-                };
+                let decompressed_value = self.decompress_value(&compressed_value)?;
                 Ok((key, decompressed_value))
             })
             .transpose()
     }
 
+    // Values below `min_compress_size`, or that would not shrink, are stored raw.
+    fn compress_value(&self, value: &[u8]) -> Result<Vec<u8>, FragmentError> {
+        if value.len() >= self.metadata.min_compress_size
+            && self.metadata.compression != CompressionAlgorithm::None
+            && !self.sample_is_incompressible(value)?
+        {
+            let compressed = self.compress_raw(value)?;
+            if compressed.len() < value.len() {
+                return Ok(tagged(COMPRESSED_TAG, &compressed));
+            }
+        }
+        Ok(tagged(RAW_TAG, value))
+    }
+
+    fn sample_is_incompressible(&self, value: &[u8]) -> Result<bool, FragmentError> {
+        match self.metadata.adaptive_sample_size {
+            Some(sample_size) if sample_size > 0 && value.len() > sample_size => {
+                let compressed = self.compress_raw(&value[..sample_size])?;
+                Ok(compressed.len() * 10 >= sample_size * 9)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    fn compress_raw(&self, value: &[u8]) -> Result<Vec<u8>, FragmentError> {
+        self.metadata
+            .compression
+            .compress(
+                value,
+                self.metadata.compression_level,
+                self.metadata.compression_dict.as_deref(),
+            )
+            .map_err(|e| FragmentError::CompressionError(e.to_string()))
+    }
+
+    fn verify_checksum(&self, key: &Sha256, stored: &[u8]) -> Result<(), FragmentError> {
+        if !self.metadata.checksums {
+            return Ok(());
+        }
+        match self.table.get_checksum(key) {
+            Some(checksum) if checksum != crc32fast::hash(stored) => {
+                Err(FragmentError::ChecksumMismatch { key: *key })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn decompress_value(&self, stored: &[u8]) -> Result<Vec<u8>, FragmentError> {
+        match self.split_tag(stored) {
+            Some((&RAW_TAG, value)) => Ok(value.to_vec()),
+            Some((&COMPRESSED_TAG, compressed)) => self
+                .metadata
+                .compression
+                .decompress(compressed, self.metadata.compression_dict.as_deref())
+                .map_err(|e| FragmentError::DecompressionError(e.to_string())),
+            _ => Err(FragmentError::DecompressionError(
+                "Unknown value tag".to_string(),
+            )),
+        }
+    }
+
+    fn split_tag<'a>(&self, stored: &'a [u8]) -> Option<(&'a u8, &'a [u8])> {
+        if self.metadata.tagged_values {
+            stored.split_first()
+        } else {
+            Some((&COMPRESSED_TAG, stored))
+        }
+    }
+
+    // Gives the values of an untagged fragment their tag byte, so new writes can be
+    // tagged without confusing them with the old ones.
+    fn tag_values(&mut self) {
+        if self.metadata.tagged_values {
+            return;
+        }
+        for stored in self.table.values_mut() {
+            stored.insert(0, COMPRESSED_TAG);
+        }
+        self.metadata.tagged_values = true;
+        if self.metadata.checksums {
+            self.set_checksums(true);
+        }
+        if self.metadata.hash_mode == HashMode::Incremental {
+            self.accumulator = self.full_accumulator();
+        }
+        self.update_hash();
+    }
+
+    fn is_expired_at(&self, key: &Sha256, now: SystemTime) -> bool {
+        self.metadata
+            .expirations
+            .get(key)
+            .is_some_and(|expires_at| *expires_at <= now)
+    }
+
+    fn live_entries(&self) -> impl Iterator<Item = (&Sha256, &Vec<u8>)> {
+        let now = SystemTime::now();
+        self.table
+            .iter()
+            .filter(move |(key, _)| !self.is_expired_at(key, now))
+    }
+
     fn update_hash(&mut self) {
-        self.hash = Sha256::new(&self.table.to_bytes());
+        self.hash = match self.metadata.hash_mode {
+            HashMode::Full => table_digest(self.metadata.hash_algorithm, &self.table),
+            HashMode::Incremental => finalize_accumulator(
+                self.metadata.hash_algorithm,
+                &self.accumulator,
+                self.table.len(),
+            ),
+        };
         self.metadata.last_modified = SystemTime::now();
     }
 
+    // One `{"key": "<hex>", "value_len": N}` object per line, plus a base64 `value`
+    // when `include_values` is set. Expired entries are skipped.
+    pub fn dump_ndjson<W: Write>(
+        &self,
+        mut writer: W,
+        include_values: bool,
+    ) -> Result<(), FragmentError> {
+        for entry in self.iter() {
+            let (key, value) = entry?;
+            let mut record = serde_json::json!({
+                "key": key.encode_hex::<String>(),
+                "value_len": value.len(),
+            });
+            if include_values {
+                record["value"] = STANDARD.encode(&value).into();
+            }
+            writeln!(writer, "{}", record)?;
+        }
+        Ok(())
+    }
+
     pub fn to_bytes(&self) -> Result<Vec<u8>, FragmentError> {
         let mut bytes = Vec::new();
         bytes.extend_from_slice(&self.table.to_bytes());
@@ -290,3 +867,548 @@ impl Fragment {
         Ok(bytes)
     }
 }
+
+fn tagged(tag: u8, bytes: &[u8]) -> Vec<u8> {
+    let mut stored = Vec::with_capacity(bytes.len() + 1);
+    stored.push(tag);
+    stored.extend_from_slice(bytes);
+    stored
+}
+
+fn table_digest(algorithm: HashAlgorithm, table: &Table) -> Sha256 {
+    let mut digester = Digester::new(algorithm);
+    table.feed_bytes(|chunk| digester.update(chunk));
+    digester.finalize()
+}
+
+fn entry_digest(algorithm: HashAlgorithm, key: &Sha256, value: &[u8]) -> [u8; 32] {
+    let mut bytes = Vec::with_capacity(36 + value.len());
+    bytes.extend_from_slice(key.as_bytes());
+    bytes.extend_from_slice(&(value.len() as u32).to_be_bytes());
+    bytes.extend_from_slice(value);
+    algorithm.digest(&bytes).0
+}
+
+fn finalize_accumulator(algorithm: HashAlgorithm, accumulator: &[u8; 32], len: usize) -> Sha256 {
+    let mut bytes = [0u8; 40];
+    bytes[..32].copy_from_slice(accumulator);
+    bytes[32..].copy_from_slice(&(len as u64).to_be_bytes());
+    algorithm.digest(&bytes)
+}
+
+fn add_assign(accumulator: &mut [u8; 32], digest: &[u8; 32]) {
+    let mut carry = 0u16;
+    for (a, d) in accumulator.iter_mut().zip(digest).rev() {
+        let sum = *a as u16 + *d as u16 + carry;
+        *a = sum as u8;
+        carry = sum >> 8;
+    }
+}
+
+fn sub_assign(accumulator: &mut [u8; 32], digest: &[u8; 32]) {
+    let mut borrow = 0i16;
+    for (a, d) in accumulator.iter_mut().zip(digest).rev() {
+        let diff = *a as i16 - *d as i16 - borrow;
+        *a = diff.rem_euclid(256) as u8;
+        borrow = (diff < 0) as i16;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backup::Backup;
+    use tempfile::tempdir;
+
+    fn migration_source() -> Fragment {
+        let mut fragment =
+            Fragment::new(CompressionAlgorithm::Zstd, 3, None).with_min_compress_size(0);
+        fragment.set_checksums(true);
+        for i in 0..64u32 {
+            let value = format!("value {i} ").repeat(i as usize + 1).into_bytes();
+            fragment
+                .insert(value, Sha256::new(&i.to_be_bytes()))
+                .unwrap();
+        }
+        let expiring = Sha256::new(b"expiring");
+        let expires_at = SystemTime::now() + std::time::Duration::from_secs(3600);
+        fragment
+            .insert_with_expiry(b"short lived".to_vec(), expiring, expires_at)
+            .unwrap();
+        fragment
+    }
+
+    #[test]
+    fn test_migrate() {
+        let fragment = migration_source();
+        let migrated = fragment
+            .migrate(CompressionAlgorithm::Brotli, 5, None)
+            .unwrap();
+        assert_eq!(
+            migrated.get_metadata().compression,
+            CompressionAlgorithm::Brotli
+        );
+        assert_eq!(migrated.get_metadata().compression_level, 5);
+        assert_eq!(
+            migrated.get_metadata().expirations,
+            fragment.get_metadata().expirations
+        );
+        assert_eq!(migrated.len(), fragment.len());
+        for (key, value) in fragment.iter().map(Result::unwrap) {
+            assert_eq!(migrated.get(key).unwrap(), Some(value));
+        }
+        assert_eq!(migrated.get_hash(), &migrated.compute_hash());
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn test_migrate_parallel_matches_serial() {
+        let fragment = migration_source();
+        let serial = fragment
+            .migrate(CompressionAlgorithm::Lz4, 3, None)
+            .unwrap();
+        let parallel = fragment
+            .migrate_parallel(CompressionAlgorithm::Lz4, 3, None)
+            .unwrap();
+        assert_eq!(parallel.get_hash(), serial.get_hash());
+        assert_eq!(parallel.get_hash(), &parallel.compute_hash());
+        assert_eq!(parallel.compressed_size(), serial.compressed_size());
+        assert_eq!(parallel.get_metadata().size, serial.get_metadata().size);
+        for (key, value) in serial.iter().map(Result::unwrap) {
+            assert_eq!(parallel.get(key).unwrap(), Some(value));
+            assert_eq!(
+                parallel.table.get_checksum(key),
+                serial.table.get_checksum(key)
+            );
+        }
+        assert_eq!(
+            parallel.get_metadata().expirations,
+            serial.get_metadata().expirations
+        );
+    }
+
+    #[test]
+    fn test_expired_entries_hidden_from_accessors() {
+        let mut fragment = Fragment::new(CompressionAlgorithm::Zstd, 3, None);
+        let mut keys: Vec<Sha256> = (0..4u32).map(|i| Sha256::new(&i.to_be_bytes())).collect();
+        keys.sort();
+        for key in &keys {
+            fragment.insert(key.as_bytes().to_vec(), *key).unwrap();
+        }
+        for key in [keys[0], keys[3]] {
+            fragment
+                .insert_with_expiry(b"expired".to_vec(), key, SystemTime::UNIX_EPOCH)
+                .unwrap();
+        }
+
+        assert_eq!(fragment.len(), 2);
+        assert!(!fragment.is_empty());
+        let (first, value) = fragment.first_key_value().unwrap().unwrap();
+        assert_eq!((first, value), (&keys[1], keys[1].as_bytes().to_vec()));
+        let (last, value) = fragment.last_key_value().unwrap().unwrap();
+        assert_eq!((last, value), (&keys[2], keys[2].as_bytes().to_vec()));
+
+        for key in [keys[1], keys[2]] {
+            fragment
+                .insert_with_expiry(b"expired".to_vec(), key, SystemTime::UNIX_EPOCH)
+                .unwrap();
+        }
+        assert_eq!(fragment.len(), 0);
+        assert!(fragment.is_empty());
+        assert!(fragment.first_key_value().unwrap().is_none());
+        assert!(fragment.last_key_value().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_insert_raw_validates_algorithm() {
+        let value = b"imported value ".repeat(16);
+        let zstd = CompressionAlgorithm::Zstd
+            .compress(&value, 3, None)
+            .unwrap();
+        let lz4 = CompressionAlgorithm::Lz4.compress(&value, 3, None).unwrap();
+        let mut fragment =
+            Fragment::new(CompressionAlgorithm::Lz4, 3, None).with_min_compress_size(0);
+        let key = Sha256::new(b"imported");
+
+        assert!(matches!(
+            fragment.insert_raw(zstd.clone(), key, true),
+            Err(FragmentError::AlgorithmMismatch {
+                expected: CompressionAlgorithm::Lz4,
+                detected: Some(CompressionAlgorithm::Zstd),
+            })
+        ));
+        assert!(!fragment.contains_key(&key));
+        fragment.insert_raw(lz4, key, true).unwrap();
+        assert_eq!(fragment.get(&key).unwrap(), Some(value.clone()));
+        assert_eq!(fragment.get_hash(), &fragment.compute_hash());
+
+        // Unvalidated imports are trusted, and only fail once read.
+        fragment.insert_raw(zstd, key, false).unwrap();
+        assert!(fragment.get(&key).is_err());
+    }
+
+    #[test]
+    fn test_incremental_hash_matches_recomputation() {
+        let mut fragment = Fragment::new(CompressionAlgorithm::Zstd, 3, None);
+        fragment.set_hash_mode(HashMode::Incremental);
+        let keys: Vec<Sha256> = (0..20u8).map(|i| Sha256::new(&[i])).collect();
+
+        for (i, key) in keys.iter().enumerate() {
+            fragment.insert(vec![i as u8; 100], *key).unwrap();
+        }
+        fragment.insert(b"overwritten".to_vec(), keys[3]).unwrap();
+        fragment.remove(&keys[7]);
+        fragment.pop_first().unwrap();
+        fragment.pop_last().unwrap();
+        let mut other = Fragment::new(CompressionAlgorithm::Zstd, 3, None);
+        other.insert(b"appended".to_vec(), keys[10]).unwrap();
+        fragment.append(&mut other);
+        assert_eq!(fragment.get_hash(), &fragment.compute_hash());
+
+        let mut rebuilt = Fragment::new(CompressionAlgorithm::Zstd, 3, None);
+        rebuilt.set_hash_mode(HashMode::Incremental);
+        for entry in fragment.iter() {
+            let (key, value) = entry.unwrap();
+            rebuilt.insert(value, *key).unwrap();
+        }
+        assert_eq!(rebuilt.get_hash(), fragment.get_hash());
+
+        fragment.clear();
+        let mut empty = Fragment::new(CompressionAlgorithm::Zstd, 3, None);
+        empty.set_hash_mode(HashMode::Incremental);
+        assert_eq!(fragment.get_hash(), &fragment.compute_hash());
+        assert_eq!(fragment.get_hash(), empty.get_hash());
+    }
+
+    #[test]
+    fn test_mixed_algorithm_backup_roundtrip() {
+        let algorithms = [
+            CompressionAlgorithm::Zstd,
+            CompressionAlgorithm::Lz4,
+            CompressionAlgorithm::Brotli,
+            CompressionAlgorithm::None,
+        ];
+        let key = Sha256::new(b"key");
+        let value = b"repetitive value ".repeat(32);
+        let fragments: Vec<Fragment> = algorithms
+            .iter()
+            .map(|algorithm| {
+                let mut fragment = Fragment::new(*algorithm, 5, None);
+                fragment.insert(value.clone(), key).unwrap();
+                fragment
+            })
+            .collect();
+
+        let mut backup = Backup::new(fragments[0].clone(), None).unwrap();
+        for fragment in &fragments[1..] {
+            backup.add_version(fragment.clone()).unwrap();
+        }
+        let dir = tempdir().unwrap();
+        let path = dir.path().to_str().unwrap();
+        backup.save_to_disk(path, None).unwrap();
+
+        let loaded = Backup::load_from_disk(path).unwrap();
+        let history = loaded.get_history();
+        assert_eq!(history.len(), algorithms.len() + 1);
+        for (fragment, algorithm) in history[1..].iter().zip(algorithms) {
+            assert_eq!(fragment.get_metadata().compression, algorithm);
+            assert_eq!(fragment.get(&key).unwrap(), Some(value.clone()));
+        }
+    }
+
+    #[test]
+    fn test_small_and_incompressible_values_stored_raw() {
+        let mut fragment =
+            Fragment::new(CompressionAlgorithm::Zstd, 3, None).with_min_compress_size(32);
+        let tiny = (Sha256::new(b"tiny"), b"twelve bytes".to_vec());
+        let noisy = (
+            Sha256::new(b"noisy"),
+            (0..64u8)
+                .map(|i| i.wrapping_mul(167) ^ 0x5a)
+                .collect::<Vec<u8>>(),
+        );
+        let repetitive = (Sha256::new(b"repetitive"), vec![7u8; 256]);
+        for (key, value) in [&tiny, &noisy, &repetitive] {
+            fragment.insert(value.clone(), *key).unwrap();
+        }
+
+        let stored = |key: &Sha256| fragment.table.get(key).unwrap().clone();
+        assert_eq!(stored(&tiny.0), [&[RAW_TAG][..], &tiny.1].concat());
+        assert_eq!(stored(&noisy.0), [&[RAW_TAG][..], &noisy.1].concat());
+        assert_eq!(stored(&repetitive.0)[0], COMPRESSED_TAG);
+        assert!(stored(&repetitive.0).len() < repetitive.1.len());
+
+        for (key, value) in [tiny, noisy, repetitive] {
+            assert_eq!(fragment.get(&key).unwrap(), Some(value));
+        }
+    }
+
+    #[test]
+    fn test_adaptive_sampling() {
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        let mut random = |len: usize| -> Vec<u8> {
+            (0..len)
+                .map(|_| {
+                    state ^= state << 13;
+                    state ^= state >> 7;
+                    state ^= state << 17;
+                    state as u8
+                })
+                .collect()
+        };
+        let sample_size = DEFAULT_ADAPTIVE_SAMPLE_SIZE;
+        let incompressible = (Sha256::new(b"random"), random(4 * sample_size));
+        let compressible = (Sha256::new(b"text"), b"lorem ipsum ".repeat(sample_size));
+        let mixed = (
+            Sha256::new(b"mixed"),
+            [random(sample_size), vec![0u8; 8 * sample_size]].concat(),
+        );
+
+        let mut plain =
+            Fragment::new(CompressionAlgorithm::Zstd, 3, None).with_min_compress_size(0);
+        let mut adaptive = plain.clone();
+        adaptive.set_adaptive_sampling(Some(sample_size));
+        for fragment in [&mut plain, &mut adaptive] {
+            for (key, value) in [&incompressible, &compressible, &mixed] {
+                fragment.insert(value.clone(), *key).unwrap();
+            }
+        }
+
+        let tag = |fragment: &Fragment, key: &Sha256| fragment.table.get(key).unwrap()[0];
+        assert_eq!(tag(&adaptive, &incompressible.0), RAW_TAG);
+        assert_eq!(tag(&adaptive, &compressible.0), COMPRESSED_TAG);
+        assert_eq!(tag(&adaptive, &mixed.0), RAW_TAG);
+        assert_eq!(tag(&plain, &mixed.0), COMPRESSED_TAG);
+        for (key, value) in [incompressible, compressible, mixed] {
+            assert_eq!(adaptive.get(&key).unwrap(), Some(value));
+        }
+    }
+
+    #[test]
+    fn test_dump_ndjson() {
+        let mut fragment =
+            Fragment::new(CompressionAlgorithm::Zstd, 3, None).with_min_compress_size(0);
+        let values = [b"alpha".to_vec(), b"beta".to_vec(), vec![0u8; 300]];
+        for value in &values {
+            fragment.insert(value.clone(), Sha256::new(value)).unwrap();
+        }
+
+        let mut out = Vec::new();
+        fragment.dump_ndjson(&mut out, true).unwrap();
+        let lines: Vec<serde_json::Value> = String::from_utf8(out)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), values.len());
+        for line in &lines {
+            let value = STANDARD.decode(line["value"].as_str().unwrap()).unwrap();
+            assert_eq!(line["value_len"], value.len());
+            assert_eq!(line["key"], Sha256::new(&value).encode_hex::<String>());
+        }
+
+        let mut out = Vec::new();
+        fragment.dump_ndjson(&mut out, false).unwrap();
+        let first: serde_json::Value =
+            serde_json::from_str(String::from_utf8(out).unwrap().lines().next().unwrap()).unwrap();
+        assert!(first.get("value").is_none());
+    }
+
+    #[test]
+    fn test_checksum_mismatch_on_corruption() {
+        let mut fragment =
+            Fragment::new(CompressionAlgorithm::Zstd, 3, None).with_min_compress_size(0);
+        let legacy = Sha256::new(b"legacy");
+        fragment.insert(vec![1u8; 128], legacy).unwrap();
+        fragment.set_checksums(true);
+        let key = Sha256::new(b"key");
+        fragment.insert(vec![2u8; 128], key).unwrap();
+
+        assert_eq!(fragment.get(&legacy).unwrap(), Some(vec![1u8; 128]));
+        assert_eq!(fragment.get(&key).unwrap(), Some(vec![2u8; 128]));
+
+        for (_, stored) in fragment.iter_mut() {
+            let last = stored.len() - 1;
+            stored[last] ^= 0xff;
+        }
+        for k in [legacy, key] {
+            assert!(matches!(
+                fragment.get(&k),
+                Err(FragmentError::ChecksumMismatch { key: mismatched }) if mismatched == k
+            ));
+        }
+
+        let mut unchecked =
+            Fragment::new(CompressionAlgorithm::Zstd, 3, None).with_min_compress_size(0);
+        unchecked.insert(vec![3u8; 128], key).unwrap();
+        for (_, stored) in unchecked.iter_mut() {
+            let last = stored.len() - 1;
+            stored[last] ^= 0xff;
+        }
+        assert!(matches!(
+            unchecked.get(&key),
+            Err(FragmentError::DecompressionError(_))
+        ));
+    }
+
+    #[test]
+    fn test_untagged_values_read_and_upgrade() {
+        let old = Sha256::new(b"old");
+        let mut fragment = Fragment::new(CompressionAlgorithm::Zstd, 3, None);
+        fragment.metadata.tagged_values = false;
+        fragment.table.insert(
+            crate::compression::compress_bytes(b"written untagged", 3).unwrap(),
+            old,
+        );
+        assert_eq!(fragment.get(&old).unwrap().unwrap(), b"written untagged");
+        assert_eq!(fragment.decompressed_size().unwrap(), 16);
+
+        let new = Sha256::new(b"new");
+        fragment.insert(b"tagged".to_vec(), new).unwrap();
+        assert!(fragment.metadata.tagged_values);
+        assert_eq!(fragment.get(&old).unwrap().unwrap(), b"written untagged");
+        assert_eq!(fragment.get(&new).unwrap().unwrap(), b"tagged");
+        assert_eq!(*fragment.get_hash(), fragment.compute_hash());
+    }
+
+    #[test]
+    fn test_hash_algorithm_is_per_fragment() {
+        let mut sha3 = Fragment::new(CompressionAlgorithm::Zstd, 3, None);
+        sha3.insert(b"value".to_vec(), Sha256::new(b"key")).unwrap();
+        let blake3 = sha3.clone().with_hash_algorithm(HashAlgorithm::Blake3);
+        assert_eq!(sha3.get_metadata().hash_algorithm, HashAlgorithm::Sha3_256);
+        assert_ne!(sha3.get_hash(), blake3.get_hash());
+        assert_ne!(sha3.content_id().unwrap(), blake3.content_id().unwrap());
+        assert_eq!(*blake3.get_hash(), blake3.compute_hash());
+
+        let mut incremental = blake3.clone();
+        incremental.set_hash_mode(HashMode::Incremental);
+        incremental
+            .insert(b"more".to_vec(), Sha256::new(b"more"))
+            .unwrap();
+        assert_eq!(*incremental.get_hash(), incremental.compute_hash());
+
+        let restored: Fragment =
+            bincode::deserialize(&bincode::serialize(&blake3).unwrap()).unwrap();
+        assert_eq!(
+            restored.get_metadata().hash_algorithm,
+            HashAlgorithm::Blake3
+        );
+        assert_eq!(restored.compute_hash(), *blake3.get_hash());
+    }
+
+    #[test]
+    fn test_compression_stats() {
+        let mut fragment =
+            Fragment::new(CompressionAlgorithm::Zstd, 3, None).with_min_compress_size(16);
+        assert_eq!(fragment.compression_ratio().unwrap(), 1.0);
+
+        fragment
+            .insert(vec![9u8; 4096], Sha256::new(b"zeros"))
+            .unwrap();
+        fragment
+            .insert(b"tiny".to_vec(), Sha256::new(b"tiny"))
+            .unwrap();
+        let stored: usize = fragment.table.values().map(Vec::len).sum();
+
+        assert_eq!(fragment.compressed_size(), stored);
+        assert_eq!(fragment.decompressed_size().unwrap(), 4096 + 4);
+        assert!(fragment.compression_ratio().unwrap() > 10.0);
+    }
+
+    #[test]
+    fn test_for_each_value_matches_iter() {
+        let algorithms = [
+            CompressionAlgorithm::Zstd,
+            CompressionAlgorithm::Lz4,
+            CompressionAlgorithm::Brotli,
+            CompressionAlgorithm::None,
+        ];
+        for algorithm in algorithms {
+            let mut fragment = Fragment::new(algorithm, 3, None).with_min_compress_size(8);
+            for i in 0..16u8 {
+                let value = vec![i; 16 + 64 * i as usize];
+                fragment.insert(value, Sha256::new(&[i])).unwrap();
+            }
+            fragment
+                .insert(b"raw".to_vec(), Sha256::new(b"raw"))
+                .unwrap();
+
+            let mut seen = Vec::new();
+            fragment
+                .for_each_value(|key, value| {
+                    seen.push((*key, value.to_vec()));
+                    Ok(())
+                })
+                .unwrap();
+            let expected: Vec<(Sha256, Vec<u8>)> = fragment
+                .iter()
+                .map(|entry| entry.map(|(key, value)| (*key, value)).unwrap())
+                .collect();
+            assert_eq!(seen, expected);
+
+            let streamed: Vec<Vec<u8>> = fragment.values_streaming().map(Result::unwrap).collect();
+            assert_eq!(streamed.len(), expected.len());
+        }
+
+        let mut fragment =
+            Fragment::new(CompressionAlgorithm::Zstd, 3, None).with_min_compress_size(0);
+        fragment.insert(vec![1u8; 64], Sha256::new(b"a")).unwrap();
+        fragment.insert(vec![2u8; 64], Sha256::new(b"b")).unwrap();
+        let mut calls = 0;
+        let result = fragment.for_each_value(|_, _| {
+            calls += 1;
+            Err(FragmentError::CompressionError("stop".to_string()))
+        });
+        assert!(result.is_err());
+        assert_eq!(calls, 1);
+    }
+
+    #[test]
+    fn test_content_id_ignores_compression_settings() {
+        let build = |algorithm, level| {
+            let mut fragment = Fragment::new(algorithm, level, None).with_min_compress_size(0);
+            for i in 0..8u8 {
+                let value: Vec<u8> = (0..512u32).map(|n| (n % (i as u32 + 3)) as u8).collect();
+                fragment.insert(value, Sha256::new(&[i])).unwrap();
+            }
+            fragment
+        };
+        let fast = build(CompressionAlgorithm::Zstd, 1);
+        let small = build(CompressionAlgorithm::Zstd, 19);
+        assert_ne!(fast.get_hash(), small.get_hash());
+        assert_eq!(fast.content_id().unwrap(), small.content_id().unwrap());
+        assert_eq!(
+            fast.content_id().unwrap(),
+            build(CompressionAlgorithm::None, 3).content_id().unwrap()
+        );
+
+        let mut changed = build(CompressionAlgorithm::Zstd, 1);
+        changed
+            .insert(b"extra".to_vec(), Sha256::new(b"extra"))
+            .unwrap();
+        assert_ne!(changed.content_id().unwrap(), fast.content_id().unwrap());
+    }
+
+    #[test]
+    fn test_signed_content_id() {
+        let signer = Ed25519::new();
+        let mut fragment =
+            Fragment::new(CompressionAlgorithm::Zstd, 3, None).with_min_compress_size(0);
+        fragment
+            .insert(vec![5u8; 256], Sha256::new(b"signed"))
+            .unwrap();
+        let signature = fragment.sign(&signer).unwrap();
+        assert!(fragment.verify_signature(&signer.verifying_key(), &signature));
+
+        let recompressed = fragment.recompress(None).unwrap();
+        assert!(recompressed.verify_signature(&signer.verifying_key(), &signature));
+        assert!(!fragment.verify_signature(&Ed25519::new().verifying_key(), &signature));
+
+        let mut modified = fragment.clone();
+        modified
+            .insert(b"tampered".to_vec(), Sha256::new(b"signed"))
+            .unwrap();
+        assert!(!modified.verify_signature(&signer.verifying_key(), &signature));
+    }
+}
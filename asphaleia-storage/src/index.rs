@@ -1,10 +1,24 @@
 use super::{
     backup::{Backup, BackupError},
     cache::{CacheConfig, CacheManager},
+    compression::CompressionAlgorithm,
+    digest::HashAlgorithm,
     fragment::{Fragment, FragmentError, Metadata},
-    versioning::VersionControl,
+    transaction::{FragmentTxn, PreparedTxn},
+    versioning::{VersionControl, VersionDiff, VersionInfo},
+    wal::{WalError, WalRecord, WriteAheadLog},
+};
+use asphaleia_crypto::{
+    hash::{Sha256, ToHex},
+    ring::rand::{SecureRandom, SystemRandom},
+};
+use std::{
+    collections::HashMap,
+    io,
+    io::Write,
+    sync::{Mutex, PoisonError},
+    time::{Duration, Instant, SystemTime},
 };
-use asphaleia_crypto::hash::Sha256;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -19,16 +33,82 @@ pub enum StorageError {
     KeyNotFound,
     #[error("Version not found")]
     VersionNotFound,
+    #[error("Version {0} was trimmed from history")]
+    VersionTrimmed(u64),
+    #[error("Tag not found: {0}")]
+    TagNotFound(String),
+    #[error("Index is sealed read-only")]
+    ReadOnly,
+    #[error("Latest version stores nothing to compare history against")]
+    EmptyLatestVersion,
     #[error("Backup error: {0}")]
     BackupError(#[from] BackupError),
     #[error("Fragment error: {0}")]
     FragmentError(#[from] FragmentError),
+    #[error("WAL error: {0}")]
+    WalError(#[from] WalError),
+    #[error("Index changed after the transaction was prepared")]
+    StalePrepare,
+    #[error("Transaction aborted by shard {shard}: {source}")]
+    ShardAborted {
+        shard: usize,
+        source: Box<StorageError>,
+    },
+    #[error(
+        "Shard {shard} failed to commit ({source}) and shards {} could not be rolled back",
+        .inconsistent.iter().map(|(shard, _)| shard.to_string()).collect::<Vec<_>>().join(", ")
+    )]
+    PartialCommit {
+        shard: usize,
+        source: Box<StorageError>,
+        inconsistent: Vec<(usize, StorageError)>,
+    },
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct RepairReport {
+    pub repaired_versions: Vec<u64>,
+    pub dropped_cache_entries: usize,
+    pub cache_rebuilt: bool,
+}
+
+impl RepairReport {
+    pub fn is_empty(&self) -> bool {
+        self.repaired_versions.is_empty() && !self.cache_rebuilt
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BenchmarkResult {
+    pub insert_ops_per_sec: f64,
+    pub insert_bytes_per_sec: f64,
+    pub get_ops_per_sec: f64,
+    pub get_bytes_per_sec: f64,
 }
 
 pub struct StorageIndex {
     backup: Backup,
     cache: CacheManager,
     version_control: VersionControl,
+    wal: Option<WriteAheadLog>,
+    key_salt: Option<Vec<u8>>,
+    sealed: bool,
+    read_repair: bool,
+    latest_cache_key: Option<Sha256>,
+    access_counts: Mutex<Option<HashMap<Sha256, u64>>>,
+}
+
+// Default keys are the digest of `salt ++ value`, so identical values in stores with
+// different salts cannot be correlated by key.
+pub(crate) fn content_key(algorithm: HashAlgorithm, salt: Option<&[u8]>, value: &[u8]) -> Sha256 {
+    match salt {
+        Some(salt) => algorithm.digest(&[salt, value].concat()),
+        None => algorithm.digest(value),
+    }
+}
+
+fn get_all(fragment: &Fragment, keys: &[Sha256]) -> Result<Vec<Option<Vec<u8>>>, FragmentError> {
+    keys.iter().map(|key| fragment.get(key)).collect()
 }
 
 impl StorageIndex {
@@ -36,7 +116,39 @@ impl StorageIndex {
         cache_config: CacheConfig,
         max_versions: Option<usize>,
     ) -> Result<Self, StorageError> {
-        let fragment = Fragment::new("zstd".to_string(), 3, None);
+        Self::build(cache_config, max_versions, None, HashAlgorithm::default())
+    }
+
+    pub fn new_with_salt(
+        cache_config: CacheConfig,
+        max_versions: Option<usize>,
+        key_salt: Vec<u8>,
+    ) -> Result<Self, StorageError> {
+        Self::build(
+            cache_config,
+            max_versions,
+            Some(key_salt),
+            HashAlgorithm::default(),
+        )
+    }
+
+    // The algorithm is recorded in every fragment, so it survives saving and reloading.
+    pub fn new_with_hash_algorithm(
+        cache_config: CacheConfig,
+        max_versions: Option<usize>,
+        hash_algorithm: HashAlgorithm,
+    ) -> Result<Self, StorageError> {
+        Self::build(cache_config, max_versions, None, hash_algorithm)
+    }
+
+    fn build(
+        cache_config: CacheConfig,
+        max_versions: Option<usize>,
+        key_salt: Option<Vec<u8>>,
+        hash_algorithm: HashAlgorithm,
+    ) -> Result<Self, StorageError> {
+        let fragment =
+            Fragment::new(CompressionAlgorithm::Zstd, 3, None).with_hash_algorithm(hash_algorithm);
         let backup = Backup::new(fragment, max_versions)?;
         let cache = CacheManager::new(cache_config);
         let version_control = VersionControl::new(max_versions);
@@ -45,79 +157,417 @@ impl StorageIndex {
             backup,
             cache,
             version_control,
+            wal: None,
+            key_salt,
+            sealed: false,
+            read_repair: false,
+            latest_cache_key: None,
+            access_counts: Mutex::new(None),
         })
     }
 
+    // There is no unseal; reload the index to write again.
+    pub fn seal(&mut self) {
+        self.sealed = true;
+    }
+
+    pub fn is_sealed(&self) -> bool {
+        self.sealed
+    }
+
+    fn ensure_writable(&self) -> Result<(), StorageError> {
+        if self.sealed {
+            Err(StorageError::ReadOnly)
+        } else {
+            Ok(())
+        }
+    }
+
+    // The salt is never persisted; set it again after `load_from_disk` or `recover`.
+    pub fn set_key_salt(&mut self, key_salt: Option<Vec<u8>>) {
+        self.key_salt = key_salt;
+    }
+
+    // With read repair on, `get` verifies the cached fragment's hash and, on a mismatch
+    // or unreadable entry, serves and re-caches the backup's copy instead of failing.
+    pub fn set_read_repair(&mut self, read_repair: bool) {
+        self.read_repair = read_repair;
+    }
+
+    // Counts successful `get`s per key in memory; turning it off drops the counts.
+    pub fn set_access_counting(&mut self, enabled: bool) {
+        let access_counts = self
+            .access_counts
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner);
+        let counts = access_counts.take().unwrap_or_default();
+        *access_counts = enabled.then_some(counts);
+    }
+
+    fn count_access(&self, key: &Sha256) {
+        let mut access_counts = self
+            .access_counts
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if let Some(counts) = access_counts.as_mut() {
+            *counts.entry(*key).or_insert(0) += 1;
+        }
+    }
+
+    // The `n` most-read keys, busiest first; ties are broken by key.
+    pub fn hot_keys(&self, n: usize) -> Vec<(Sha256, u64)> {
+        let access_counts = self
+            .access_counts
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let Some(counts) = access_counts.as_ref() else {
+            return Vec::new();
+        };
+        let mut ranked: Vec<(Sha256, u64)> =
+            counts.iter().map(|(key, count)| (*key, *count)).collect();
+        ranked.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        ranked.truncate(n);
+        ranked
+    }
+
+    // Keys follow the latest fragment's hash algorithm.
+    pub fn content_key(&self, value: &[u8]) -> Sha256 {
+        let algorithm = self
+            .backup
+            .get_latest_version()
+            .map(|fragment| fragment.get_metadata().hash_algorithm)
+            .unwrap_or_default();
+        content_key(algorithm, self.key_salt.as_deref(), value)
+    }
+
     pub fn insert(
         &mut self,
         value: Vec<u8>,
         key: Option<Sha256>,
     ) -> Result<Option<Vec<u8>>, StorageError> {
-        let mut fragment = self
-            .backup
-            .get_latest_version()
-            .ok_or(StorageError::VersionNotFound)?
-            .clone();
-        let key = key.unwrap_or_else(|| Sha256::new(&value));
-        let result = fragment.insert(value, key)?;
-        let _ = self.cache.insert(fragment.clone());
-        self.backup.add_version(fragment.clone())?;
-        self.version_control.add_version(fragment);
+        self.insert_entry(value, key, None)
+    }
+
+    pub fn insert_with_ttl(
+        &mut self,
+        value: Vec<u8>,
+        key: Option<Sha256>,
+        ttl: Duration,
+    ) -> Result<Option<Vec<u8>>, StorageError> {
+        self.insert_entry(value, key, Some(SystemTime::now() + ttl))
+    }
+
+    fn insert_entry(
+        &mut self,
+        value: Vec<u8>,
+        key: Option<Sha256>,
+        expires_at: Option<SystemTime>,
+    ) -> Result<Option<Vec<u8>>, StorageError> {
+        self.ensure_writable()?;
+        let mut fragment = self.latest_fragment()?;
+        let key = key.unwrap_or_else(|| self.content_key(&value));
+        // Applied to the copy first, so a failed insert never reaches the log.
+        let result = match expires_at {
+            Some(expires_at) => fragment.insert_with_expiry(value.clone(), key, expires_at)?,
+            None => fragment.insert(value.clone(), key)?,
+        };
+        self.log(|| WalRecord::Insert {
+            key,
+            value,
+            expires_at,
+        })?;
+        self.commit(fragment)?;
         Ok(result)
     }
 
+    pub fn purge_expired(&mut self) -> Result<usize, StorageError> {
+        self.ensure_writable()?;
+        let mut fragment = self.latest_fragment()?;
+        let purged = fragment.purge_expired();
+        if purged > 0 {
+            self.log(|| WalRecord::PurgeExpired)?;
+            self.commit(fragment)?;
+        }
+        Ok(purged)
+    }
+
     pub fn get(&mut self, key: &Sha256) -> Result<Vec<u8>, StorageError> {
-        if let Some(fragment) = self.cache.get(key) {
-            fragment.get(key)?.ok_or(StorageError::KeyNotFound)
-        } else {
-            let fragment = self
-                .backup
-                .get_latest_version()
-                .ok_or(StorageError::VersionNotFound)?
-                .clone();
-            let _ = self.cache.insert(fragment.clone());
-            fragment.get(key)?.ok_or(StorageError::KeyNotFound)
+        let value = self.read(key)?;
+        self.count_access(key);
+        Ok(value)
+    }
+
+    fn read(&mut self, key: &Sha256) -> Result<Vec<u8>, StorageError> {
+        self.read_many(std::slice::from_ref(key))?
+            .pop()
+            .flatten()
+            .ok_or(StorageError::KeyNotFound)
+    }
+
+    // Read repair checks the cached fragment once per batch and re-reads the whole batch
+    // from the backup if it fails.
+    fn read_many(&mut self, keys: &[Sha256]) -> Result<Vec<Option<Vec<u8>>>, StorageError> {
+        let latest = self
+            .backup
+            .get_latest_version()
+            .ok_or(StorageError::VersionNotFound)?;
+        let cached = self
+            .latest_cache_key
+            .and_then(|cache_key| self.cache.get(&cache_key))
+            .filter(|fragment| fragment.get_hash() == latest.get_hash());
+        if let Some(fragment) = cached {
+            if !self.read_repair {
+                return Ok(get_all(fragment, keys)?);
+            }
+            if fragment.compute_hash() == *fragment.get_hash() {
+                if let Ok(values) = get_all(fragment, keys) {
+                    return Ok(values);
+                }
+            }
+            log::warn!("cached fragment failed verification, re-caching it from the backup");
+            if let Some(cache_key) = self.latest_cache_key.take() {
+                self.cache.remove(&cache_key);
+            }
         }
+
+        let fragment = self.latest_fragment()?;
+        self.cache_latest(fragment.clone());
+        Ok(get_all(&fragment, keys)?)
     }
 
-    pub fn remove(&mut self, key: &Sha256) -> Result<Vec<u8>, StorageError> {
-        let mut fragment = self
+    // Only keys that are found count as accesses.
+    pub fn get_many(&mut self, keys: &[Sha256]) -> Result<Vec<Option<Vec<u8>>>, StorageError> {
+        let values = self.read_many(keys)?;
+        for (key, value) in keys.iter().zip(&values) {
+            if value.is_some() {
+                self.count_access(key);
+            }
+        }
+        Ok(values)
+    }
+
+    // Same lookup as `get`, but through `&self`: a cache miss or a cached copy failing read
+    // repair is served from the backup without touching the cache, and the next `get`
+    // re-caches it.
+    pub fn get_shared(&self, key: &Sha256) -> Result<Vec<u8>, StorageError> {
+        let latest = self
             .backup
             .get_latest_version()
-            .ok_or(StorageError::VersionNotFound)?
-            .clone();
+            .ok_or(StorageError::VersionNotFound)?;
+        let cached = self
+            .latest_cache_key
+            .and_then(|cache_key| self.cache.get_shared(&cache_key))
+            .filter(|fragment| fragment.get_hash() == latest.get_hash());
+        let value = match cached {
+            Some(fragment) if !self.read_repair => fragment.get(key)?,
+            Some(fragment) => {
+                let verified = (fragment.compute_hash() == *fragment.get_hash())
+                    .then(|| fragment.get(key).ok())
+                    .flatten();
+                match verified {
+                    Some(value) => value,
+                    None => {
+                        log::warn!(
+                            "cached fragment failed verification, reading the backup's copy"
+                        );
+                        latest.get(key)?
+                    }
+                }
+            }
+            None => latest.get(key)?,
+        }
+        .ok_or(StorageError::KeyNotFound)?;
+        self.count_access(key);
+        Ok(value)
+    }
+
+    pub fn remove(&mut self, key: &Sha256) -> Result<Vec<u8>, StorageError> {
+        self.ensure_writable()?;
+        let mut fragment = self.latest_fragment()?;
         let result = fragment.remove(key).ok_or(StorageError::KeyNotFound)?;
-        let _ = self.cache.insert(fragment.clone());
-        self.backup.add_version(fragment.clone())?;
-        self.version_control.add_version(fragment);
+        self.log(|| WalRecord::Remove { key: *key })?;
+        self.commit(fragment)?;
         Ok(result)
     }
 
-    pub fn create_new_version(&mut self) -> Result<(), StorageError> {
-        let fragment = self
+    pub fn remove_with_version(&mut self, key: &Sha256) -> Result<(Vec<u8>, u64), StorageError> {
+        let value = self.remove(key)?;
+        let version = self
             .backup
-            .get_latest_version()
-            .ok_or(StorageError::VersionNotFound)?
-            .clone();
-        self.backup.add_version(fragment.clone())?;
-        Ok(())
+            .get_latest_version_number()
+            .ok_or(StorageError::VersionNotFound)?;
+        Ok((value, version))
+    }
+
+    pub fn transaction<F>(&mut self, f: F) -> Result<(), StorageError>
+    where
+        F: FnOnce(&mut FragmentTxn) -> Result<(), StorageError>,
+    {
+        let prepared = self.prepare(f)?;
+        self.commit_prepared(prepared)
+    }
+
+    // Runs `f` against a copy of the latest version without committing anything, so
+    // `commit_prepared` can apply it later; see `TwoPhaseCommit`.
+    pub fn prepare<F>(&self, f: F) -> Result<PreparedTxn, StorageError>
+    where
+        F: FnOnce(&mut FragmentTxn) -> Result<(), StorageError>,
+    {
+        self.ensure_writable()?;
+        let base = self.latest_fragment()?;
+        let base_hash = *base.get_hash();
+        let mut txn = FragmentTxn::new(base, self.wal.is_some(), self.key_salt.clone());
+        f(&mut txn)?;
+        Ok(PreparedTxn::new(txn, self.current_version(), base_hash))
+    }
+
+    // Fails with `StalePrepare` if anything was committed since `prepare`.
+    pub fn commit_prepared(&mut self, prepared: PreparedTxn) -> Result<(), StorageError> {
+        self.ensure_writable()?;
+        let latest_hash = self.backup.get_latest_version().map(|f| *f.get_hash());
+        if prepared.base_version() != self.current_version()
+            || Some(prepared.base_hash()) != latest_hash
+        {
+            return Err(StorageError::StalePrepare);
+        }
+        let (fragment, ops) = prepared.into_txn().into_parts();
+        self.log(|| WalRecord::Transaction { ops })?;
+        self.commit(fragment)
+    }
+
+    pub fn recompress_with_dict(&mut self, dict: Vec<u8>) -> Result<(), StorageError> {
+        self.ensure_writable()?;
+        let fragment = self.latest_fragment()?.recompress(Some(dict.clone()))?;
+        self.log(|| WalRecord::Recompress { dict })?;
+        self.commit(fragment)
+    }
+
+    pub fn create_new_version(&mut self) -> Result<u64, StorageError> {
+        self.ensure_writable()?;
+        let fragment = self.latest_fragment()?;
+        self.log(|| WalRecord::CreateVersion)?;
+        self.backup.add_version(fragment)?;
+        self.current_version().ok_or(StorageError::VersionNotFound)
+    }
+
+    pub fn current_version(&self) -> Option<u64> {
+        self.backup.get_latest_version_number()
     }
 
     pub fn rollback(&mut self, version: u64) -> Result<Fragment, StorageError> {
+        self.ensure_writable()?;
+        if self.backup.is_version_trimmed(version) {
+            return Err(StorageError::VersionTrimmed(version));
+        }
+        if self
+            .backup
+            .get_version_control()
+            .get_version(version)
+            .is_none()
+        {
+            return Err(StorageError::VersionNotFound);
+        }
+        self.log(|| WalRecord::Rollback { version })?;
         let fragment = self.backup.rollback(version)?;
-        self.cache.clear();
         let unwrapped_fragment = fragment.ok_or(StorageError::VersionNotFound)?;
-        let _ = self.cache.insert(unwrapped_fragment.clone());
+        self.cache.clear();
+        self.cache_latest(unwrapped_fragment.clone());
         self.version_control.rollback(version);
         Ok(unwrapped_fragment)
     }
 
+    // The live, decompressed entries `rollback(version)` would leave behind, in key order.
+    pub fn preview_rollback(&self, version: u64) -> Result<Vec<(Sha256, Vec<u8>)>, StorageError> {
+        if self.backup.is_version_trimmed(version) {
+            return Err(StorageError::VersionTrimmed(version));
+        }
+        let version = self
+            .backup
+            .get_version_control()
+            .get_version(version)
+            .ok_or(StorageError::VersionNotFound)?;
+        version
+            .fragment
+            .iter()
+            .map(|entry| {
+                let (key, value) = entry?;
+                Ok((*key, value))
+            })
+            .collect()
+    }
+
+    pub fn tag_version(&mut self, version: u64, tag: String) -> Result<(), StorageError> {
+        self.ensure_writable()?;
+        if self.backup.tag_version(version, tag.clone()) {
+            self.log(|| WalRecord::Tag { version, tag })
+        } else if self.backup.is_version_trimmed(version) {
+            Err(StorageError::VersionTrimmed(version))
+        } else {
+            Err(StorageError::VersionNotFound)
+        }
+    }
+
+    pub fn rollback_to_tag(&mut self, tag: &str) -> Result<Fragment, StorageError> {
+        let version = self
+            .backup
+            .get_version_control()
+            .get_tag(tag)
+            .ok_or_else(|| StorageError::TagNotFound(tag.to_string()))?;
+        self.rollback(version)
+    }
+
+    // The backup is authoritative: stale fragment hashes are recomputed in place and
+    // the cache is rebuilt whenever it no longer holds the backup's latest fragment.
+    pub fn repair(&mut self) -> Result<RepairReport, StorageError> {
+        self.ensure_writable()?;
+        let mut report = RepairReport {
+            repaired_versions: self.backup.repair_hashes(),
+            ..RepairReport::default()
+        };
+        self.version_control.repair_hashes();
+
+        let latest = self.latest_fragment()?;
+        let latest_key = latest.digest(&latest.to_bytes()?);
+        if !self.cache.contains_key(&latest_key) {
+            report.dropped_cache_entries = self.cache.get_size();
+            report.cache_rebuilt = true;
+            self.cache.clear();
+            self.cache_latest(latest);
+        }
+        Ok(report)
+    }
+
     pub fn save_to_disk(
         &mut self,
         path: &str,
         compression_level: Option<usize>,
     ) -> Result<(), StorageError> {
-        Ok(self.backup.save_to_disk(path, compression_level)?)
+        self.backup.save_to_disk(path, compression_level)?;
+        if let Some(wal) = self.wal.as_mut() {
+            // Compared canonically so `./x`, `x` and symlinks to it all count.
+            let saved_to = std::fs::canonicalize(path)?;
+            if std::fs::canonicalize(wal.dir())? == saved_to {
+                wal.truncate()?;
+            }
+        }
+        Ok(())
+    }
+
+    // One tar entry per live key of the latest version, named by the key's hex and
+    // holding the decompressed value.
+    pub fn export_tar(&self, writer: impl Write) -> Result<(), StorageError> {
+        let mut builder = tar::Builder::new(writer);
+        if let Some(fragment) = self.backup.get_latest_version() {
+            for entry in fragment.iter() {
+                let (key, value) = entry?;
+                let mut header = tar::Header::new_gnu();
+                header.set_size(value.len() as u64);
+                header.set_mode(0o644);
+                builder.append_data(&mut header, key.encode_hex::<String>(), value.as_slice())?;
+            }
+        }
+        builder.into_inner()?.flush()?;
+        Ok(())
     }
 
     pub fn load_from_disk(path: &str, cache_config: CacheConfig) -> Result<Self, StorageError> {
@@ -132,9 +582,94 @@ impl StorageIndex {
             backup,
             cache,
             version_control,
+            wal: None,
+            key_salt: None,
+            sealed: false,
+            read_repair: false,
+            latest_cache_key: None,
+            access_counts: Mutex::new(None),
         })
     }
 
+    // Writes are journaled to `<path>/wal.log` until the next `save_to_disk` into the same path.
+    pub fn enable_wal(&mut self, path: &str) -> Result<(), StorageError> {
+        self.wal = Some(WriteAheadLog::open(path)?);
+        Ok(())
+    }
+
+    pub fn disable_wal(&mut self) {
+        self.wal = None;
+    }
+
+    pub fn recover(path: &str, cache_config: CacheConfig) -> Result<Self, StorageError> {
+        let mut index = Self::load_from_disk(path, cache_config)?;
+        for record in WriteAheadLog::read_records(path)? {
+            index.replay(record)?;
+        }
+        index.enable_wal(path)?;
+        Ok(index)
+    }
+
+    fn replay(&mut self, record: WalRecord) -> Result<(), StorageError> {
+        match record {
+            WalRecord::Insert {
+                key,
+                value,
+                expires_at,
+            } => {
+                self.insert_entry(value, Some(key), expires_at)?;
+            }
+            WalRecord::Remove { key } => {
+                self.remove(&key)?;
+            }
+            WalRecord::Transaction { ops } => self.transaction(|txn| {
+                for op in ops {
+                    txn.replay(op)?;
+                }
+                Ok(())
+            })?,
+            WalRecord::PurgeExpired => {
+                self.purge_expired()?;
+            }
+            WalRecord::CreateVersion => {
+                self.create_new_version()?;
+            }
+            WalRecord::Rollback { version } => {
+                self.rollback(version)?;
+            }
+            WalRecord::Tag { version, tag } => self.tag_version(version, tag)?,
+            WalRecord::Recompress { dict } => self.recompress_with_dict(dict)?,
+            WalRecord::Compact { keep_last: None } => self.compact()?,
+            WalRecord::Compact { keep_last: Some(n) } => self.compact_keep_last(n)?,
+        }
+        Ok(())
+    }
+
+    fn log(&mut self, record: impl FnOnce() -> WalRecord) -> Result<(), StorageError> {
+        if let Some(wal) = self.wal.as_mut() {
+            wal.append(&record())?;
+        }
+        Ok(())
+    }
+
+    fn latest_fragment(&self) -> Result<Fragment, StorageError> {
+        self.backup
+            .get_latest_version()
+            .cloned()
+            .ok_or(StorageError::VersionNotFound)
+    }
+
+    fn cache_latest(&mut self, fragment: Fragment) {
+        self.latest_cache_key = self.cache.insert(fragment).ok();
+    }
+
+    fn commit(&mut self, fragment: Fragment) -> Result<(), StorageError> {
+        self.cache_latest(fragment.clone());
+        self.backup.add_version(fragment.clone())?;
+        self.version_control.add_version(fragment);
+        Ok(())
+    }
+
     pub fn get_metadata(&self) -> Result<&Metadata, StorageError> {
         self.backup
             .get_latest_version()
@@ -150,6 +685,91 @@ impl StorageIndex {
             .collect()
     }
 
+    pub fn diff_versions(&self, from: u64, to: u64) -> Result<VersionDiff, StorageError> {
+        self.backup
+            .get_version_control()
+            .diff(from, to)?
+            .ok_or(StorageError::VersionNotFound)
+    }
+
+    pub fn versions_between(&self, start: SystemTime, end: SystemTime) -> Vec<VersionInfo> {
+        self.backup
+            .get_version_control()
+            .versions_between(start, end)
+    }
+
+    pub fn last_modified_version(&self, key: &Sha256) -> Result<Option<u64>, StorageError> {
+        Ok(self
+            .backup
+            .get_version_control()
+            .last_modified_version(key)?)
+    }
+
+    // Stored bytes across every retained version per stored byte of the latest one. An
+    // empty history reports 1.0; an empty latest version over a non-empty history has no
+    // finite ratio and is an error.
+    pub fn amplification_factor(&self) -> Result<f64, StorageError> {
+        let latest = self
+            .backup
+            .get_latest_version()
+            .ok_or(StorageError::VersionNotFound)?
+            .compressed_size();
+        let total: usize = self
+            .backup
+            .get_history()
+            .iter()
+            .map(|fragment| fragment.compressed_size())
+            .sum();
+        if total == 0 {
+            return Ok(1.0);
+        }
+        if latest == 0 {
+            return Err(StorageError::EmptyLatestVersion);
+        }
+        Ok(total as f64 / latest as f64)
+    }
+
+    // Times `count` inserts and then `count` gets of random `value_size`-byte values on a
+    // fresh in-memory index with default cache settings; nothing is persisted.
+    pub fn benchmark(value_size: usize, count: usize) -> Result<BenchmarkResult, StorageError> {
+        let rng = SystemRandom::new();
+        let values = (0..count)
+            .map(|_| {
+                let mut value = vec![0u8; value_size];
+                rng.fill(&mut value)
+                    .map(|_| value)
+                    .map_err(|_| io::Error::other("failed to generate benchmark values"))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut index = Self::new(CacheConfig::default(), Some(2))?;
+        let keys: Vec<Sha256> = values
+            .iter()
+            .map(|value| index.content_key(value))
+            .collect();
+        let started = Instant::now();
+        for (value, key) in values.into_iter().zip(&keys) {
+            index.insert(value, Some(*key))?;
+        }
+        let inserting = started.elapsed();
+
+        let started = Instant::now();
+        for key in &keys {
+            index.get(key)?;
+        }
+        let getting = started.elapsed();
+
+        let bytes = (value_size * count) as f64;
+        let per_sec =
+            |amount: f64, elapsed: Duration| amount / elapsed.as_secs_f64().max(f64::MIN_POSITIVE);
+        Ok(BenchmarkResult {
+            insert_ops_per_sec: per_sec(count as f64, inserting),
+            insert_bytes_per_sec: per_sec(bytes, inserting),
+            get_ops_per_sec: per_sec(count as f64, getting),
+            get_bytes_per_sec: per_sec(bytes, getting),
+        })
+    }
+
     pub fn clear_cache(&mut self) {
         self.cache.clear();
     }
@@ -171,11 +791,29 @@ impl StorageIndex {
         self.backup.clear_history();
         self.version_control.clear_history();
     }
+
+    // Leaves the latest fragment untouched, so cached entries stay valid.
+    pub fn compact(&mut self) -> Result<(), StorageError> {
+        self.ensure_writable()?;
+        self.log(|| WalRecord::Compact { keep_last: None })?;
+        self.backup.compact();
+        self.version_control.compact();
+        Ok(())
+    }
+
+    pub fn compact_keep_last(&mut self, n: usize) -> Result<(), StorageError> {
+        self.ensure_writable()?;
+        self.log(|| WalRecord::Compact { keep_last: Some(n) })?;
+        self.backup.compact_keep_last(n);
+        self.version_control.compact_keep_last(n);
+        Ok(())
+    }
 }
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::time::Duration;
+    use crate::{compression::train_dictionary, transaction::TwoPhaseCommit};
+    use std::{io::Write, sync::Arc, thread, time::Duration};
     use tempfile::tempdir;
 
     #[test]
@@ -191,6 +829,55 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_storage_index_concurrent_get_shared() -> Result<(), StorageError> {
+        let mut index = StorageIndex::new(CacheConfig::default(), None)?;
+        let values: Vec<Vec<u8>> = (0..8)
+            .map(|i| format!("value {}", i).into_bytes())
+            .collect();
+        for value in &values {
+            index.insert(value.clone(), None)?;
+        }
+
+        let index = Arc::new(index);
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let index = Arc::clone(&index);
+                let values = values.clone();
+                thread::spawn(move || {
+                    for value in &values {
+                        assert_eq!(&index.get_shared(&Sha256::new(value)).unwrap(), value);
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        assert!(matches!(
+            index.get_shared(&Sha256::new(b"missing")),
+            Err(StorageError::KeyNotFound)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_shared_reads_cached_fragment() -> Result<(), StorageError> {
+        let mut index = StorageIndex::new(CacheConfig::default(), None)?;
+        let key = Sha256::new(b"payload");
+        index.insert(b"payload".to_vec(), Some(key))?;
+        index.set_access_counting(true);
+        let corrupted_key = cache_corrupted_copy(&mut index, &key);
+
+        assert_eq!(index.get_shared(&key)?, b"Payload".to_vec());
+        index.set_read_repair(true);
+        assert_eq!(index.get_shared(&key)?, b"payload".to_vec());
+        assert!(index.cache.contains_key(&corrupted_key));
+        assert_eq!(index.hot_keys(1), vec![(key, 2)]);
+        Ok(())
+    }
+
     #[test]
     fn test_storage_index_remove() -> Result<(), StorageError> {
         let mut index = StorageIndex::new(CacheConfig::default(), None)?;
@@ -205,6 +892,173 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_storage_index_remove_with_version() -> Result<(), StorageError> {
+        let mut index = StorageIndex::new(CacheConfig::default(), None)?;
+        let value = b"test data".to_vec();
+        let key = Sha256::new(&value);
+
+        index.insert(value.clone(), Some(key))?;
+        let before = index.current_version().unwrap();
+        let (removed, version) = index.remove_with_version(&key)?;
+
+        assert_eq!(removed, value);
+        assert_eq!(version, before + 1);
+        assert_eq!(index.current_version(), Some(version));
+
+        index.rollback(before)?;
+        assert_eq!(index.get(&key)?, value);
+        Ok(())
+    }
+
+    #[test]
+    fn test_two_phase_commit() -> Result<(), StorageError> {
+        let mut shards = [
+            StorageIndex::new(CacheConfig::default(), None)?,
+            StorageIndex::new(CacheConfig::default(), None)?,
+        ];
+        let (debit, credit) = (b"debit".to_vec(), b"credit".to_vec());
+        for shard in shards.iter_mut() {
+            shard.insert(b"seed".to_vec(), None)?;
+        }
+        let before: Vec<Option<u64>> = shards.iter().map(|s| s.current_version()).collect();
+
+        let [first, second] = &mut shards;
+        let result = TwoPhaseCommit::new()
+            .participant(first, |txn| {
+                txn.insert(debit.clone(), None)?;
+                Ok(())
+            })
+            .participant(second, |txn| {
+                txn.insert(credit.clone(), None)?;
+                txn.remove(&Sha256::new(b"missing"))?;
+                Ok(())
+            })
+            .commit();
+        assert!(matches!(
+            result,
+            Err(StorageError::ShardAborted { shard: 1, source }) if matches!(*source, StorageError::KeyNotFound)
+        ));
+        for (shard, version) in shards.iter_mut().zip(&before) {
+            assert_eq!(shard.current_version(), *version);
+            assert!(shard.get(&Sha256::new(&debit)).is_err());
+            assert!(shard.get(&Sha256::new(&credit)).is_err());
+        }
+
+        let [first, second] = &mut shards;
+        TwoPhaseCommit::new()
+            .participant(first, |txn| {
+                txn.insert(debit.clone(), None)?;
+                Ok(())
+            })
+            .participant(second, |txn| {
+                txn.insert(credit.clone(), None)?;
+                Ok(())
+            })
+            .commit()?;
+        assert_eq!(shards[0].get(&Sha256::new(&debit))?, debit);
+        assert_eq!(shards[1].get(&Sha256::new(&credit))?, credit);
+
+        let prepared = shards[0].prepare(|txn| {
+            txn.insert(b"late".to_vec(), None)?;
+            Ok(())
+        })?;
+        shards[0].insert(b"interleaved".to_vec(), None)?;
+        assert!(matches!(
+            shards[0].commit_prepared(prepared),
+            Err(StorageError::StalePrepare)
+        ));
+        Ok(())
+    }
+
+    // Appends to /dev/full fail with ENOSPC, so the WAL write inside the second shard's
+    // commit fails after the first shard has committed.
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_two_phase_commit_undoes_committed_shards() -> Result<(), StorageError> {
+        let dir = tempdir()?;
+        std::os::unix::fs::symlink("/dev/full", dir.path().join("wal.log"))?;
+        let wal_dir = dir.path().to_str().unwrap();
+        let value = b"all or none".to_vec();
+        let key = Sha256::new(&value);
+
+        let mut first = StorageIndex::new(CacheConfig::default(), None)?;
+        let mut failing = StorageIndex::new(CacheConfig::default(), None)?;
+        failing.enable_wal(wal_dir)?;
+        let before = first.current_version();
+        let result = TwoPhaseCommit::new()
+            .participant(&mut first, |txn| {
+                txn.insert(value.clone(), None).map(|_| ())
+            })
+            .participant(&mut failing, |txn| {
+                txn.insert(value.clone(), None).map(|_| ())
+            })
+            .commit();
+        assert!(matches!(
+            result,
+            Err(StorageError::ShardAborted { shard: 1, .. })
+        ));
+        assert_eq!(first.current_version(), before);
+        assert!(first.get(&key).is_err());
+
+        let mut trimmed = StorageIndex::new(CacheConfig::default(), Some(1))?;
+        let result = TwoPhaseCommit::new()
+            .participant(&mut trimmed, |txn| {
+                txn.insert(value.clone(), None).map(|_| ())
+            })
+            .participant(&mut failing, |txn| {
+                txn.insert(value.clone(), None).map(|_| ())
+            })
+            .commit();
+        match result {
+            Err(StorageError::PartialCommit {
+                shard: 1,
+                inconsistent,
+                ..
+            }) => {
+                assert_eq!(inconsistent.len(), 1);
+                assert!(matches!(
+                    inconsistent[0],
+                    (0, StorageError::VersionTrimmed(_))
+                ));
+            }
+            other => panic!("expected a partial commit, got {other:?}"),
+        }
+        assert_eq!(trimmed.get(&key)?, value);
+        Ok(())
+    }
+
+    #[test]
+    fn test_storage_index_transaction() -> Result<(), StorageError> {
+        let mut index = StorageIndex::new(CacheConfig::default(), None)?;
+        let value1 = b"test data 1".to_vec();
+        let value2 = b"test data 2".to_vec();
+        let key1 = Sha256::new(&value1);
+        let key2 = Sha256::new(&value2);
+        index.insert(value1.clone(), Some(key1))?;
+
+        let versions_before = index.backup.get_history().len();
+        index.transaction(|txn| {
+            txn.insert(value2.clone(), Some(key2))?;
+            txn.remove(&key1)?;
+            Ok(())
+        })?;
+        assert_eq!(index.backup.get_history().len(), versions_before + 1);
+        assert_eq!(index.get(&key2)?, value2);
+        assert!(matches!(index.get(&key1), Err(StorageError::KeyNotFound)));
+
+        let versions_before = index.backup.get_history().len();
+        let result = index.transaction(|txn| {
+            txn.insert(value1.clone(), Some(key1))?;
+            txn.remove(&Sha256::new(b"missing"))?;
+            Ok(())
+        });
+        assert!(matches!(result, Err(StorageError::KeyNotFound)));
+        assert_eq!(index.backup.get_history().len(), versions_before);
+        assert!(matches!(index.get(&key1), Err(StorageError::KeyNotFound)));
+        Ok(())
+    }
+
     #[test]
     fn test_storage_index_rollback() -> Result<(), StorageError> {
         let mut index = StorageIndex::new(CacheConfig::default(), None)?;
@@ -225,6 +1079,165 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_hot_keys() -> Result<(), StorageError> {
+        let mut index = StorageIndex::new(CacheConfig::default(), None)?;
+        let keys: Vec<Sha256> = (0..4u8).map(|i| index.content_key(&[i])).collect();
+        for (i, key) in keys.iter().enumerate() {
+            index.insert(vec![i as u8], Some(*key))?;
+        }
+        index.get(&keys[0])?;
+        assert!(index.hot_keys(4).is_empty());
+
+        index.set_access_counting(true);
+        for (key, reads) in keys.iter().zip([1, 5, 0, 3]) {
+            for _ in 0..reads {
+                index.get(key)?;
+            }
+        }
+        assert!(index.get(&Sha256::new(b"missing")).is_err());
+        assert_eq!(index.hot_keys(2), vec![(keys[1], 5), (keys[3], 3)]);
+        assert_eq!(index.hot_keys(10).len(), 3);
+
+        index.set_access_counting(false);
+        assert!(index.hot_keys(2).is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_preview_rollback() -> Result<(), StorageError> {
+        let mut index = StorageIndex::new(CacheConfig::default(), None)?;
+        let (first, second) = (b"first".to_vec(), b"second".to_vec());
+        index.insert(first.clone(), None)?;
+        let version = index.current_version().unwrap();
+        index.insert(second.clone(), None)?;
+        let live_version = index.current_version().unwrap();
+
+        let preview = index.preview_rollback(version)?;
+        assert_eq!(preview, vec![(Sha256::new(&first), first.clone())]);
+        assert_eq!(index.current_version(), Some(live_version));
+        assert_eq!(index.get(&Sha256::new(&second))?, second);
+
+        let live = index.preview_rollback(live_version)?;
+        assert_eq!(live.len(), 2);
+        assert_eq!(index.rollback(version)?.iter().count(), preview.len());
+        assert!(matches!(
+            index.preview_rollback(live_version),
+            Err(StorageError::VersionNotFound)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_export_tar() -> Result<(), StorageError> {
+        let mut index = StorageIndex::new(CacheConfig::default(), None)?;
+        let values = [b"alpha".to_vec(), b"beta".repeat(512)];
+        for value in &values {
+            index.insert(value.clone(), None)?;
+        }
+
+        let mut archive = Vec::new();
+        index.export_tar(&mut archive)?;
+        let mut exported = HashMap::new();
+        for entry in tar::Archive::new(archive.as_slice()).entries()? {
+            let mut entry = entry?;
+            let name = entry.path()?.to_string_lossy().into_owned();
+            let mut value = Vec::new();
+            std::io::Read::read_to_end(&mut entry, &mut value)?;
+            exported.insert(name, value);
+        }
+        assert_eq!(exported.len(), values.len());
+        for value in &values {
+            assert_eq!(exported[&Sha256::new(value).encode_hex::<String>()], *value);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_storage_index_create_new_version_returns_id() -> Result<(), StorageError> {
+        let mut index = StorageIndex::new(CacheConfig::default(), Some(3))?;
+        let value = b"test data".to_vec();
+        let key = Sha256::new(&value);
+
+        index.insert(value.clone(), Some(key))?;
+        let snapshot = index.create_new_version()?;
+        assert_eq!(index.current_version(), Some(snapshot));
+
+        index.remove(&key)?;
+        index.create_new_version()?;
+        assert_ne!(index.current_version(), Some(snapshot));
+
+        index.rollback(snapshot)?;
+        assert_eq!(index.get(&key)?, value);
+        assert_eq!(index.current_version(), Some(snapshot));
+        Ok(())
+    }
+
+    #[test]
+    fn test_storage_index_diff_versions() -> Result<(), StorageError> {
+        let mut index = StorageIndex::new(CacheConfig::default(), None)?;
+        let value1 = b"test data 1".to_vec();
+        let value2 = b"test data 2".to_vec();
+        let key1 = Sha256::new(&value1);
+        let key2 = Sha256::new(&value2);
+
+        index.insert(value1, Some(key1))?;
+        let from = index.create_new_version()?;
+        index.insert(value2, Some(key2))?;
+        index.insert(b"updated".to_vec(), Some(key1))?;
+        let to = index.create_new_version()?;
+
+        let diff = index.diff_versions(from, to)?;
+        assert_eq!(diff.added, vec![key2]);
+        assert!(diff.removed.is_empty());
+        assert_eq!(diff.modified, vec![key1]);
+        assert!(matches!(
+            index.diff_versions(from, to + 10),
+            Err(StorageError::VersionNotFound)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_storage_index_versions_between() -> Result<(), StorageError> {
+        let start = SystemTime::now() - Duration::from_secs(1);
+        let mut index = StorageIndex::new(CacheConfig::default(), None)?;
+        index.insert(b"test data".to_vec(), None)?;
+        let latest = index.create_new_version()?;
+        let end = SystemTime::now() + Duration::from_secs(1);
+
+        let versions = index.versions_between(start, end);
+        assert_eq!(versions.last().map(|info| info.version), Some(latest));
+        assert_eq!(versions.last().map(|info| info.size), Some(1));
+        assert!(index
+            .versions_between(
+                end + Duration::from_secs(60),
+                end + Duration::from_secs(120)
+            )
+            .is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_storage_index_last_modified_version() -> Result<(), StorageError> {
+        let mut index = StorageIndex::new(CacheConfig::default(), None)?;
+        let key = Sha256::new(b"tracked");
+        assert_eq!(index.last_modified_version(&key)?, None);
+
+        index.insert(b"first".to_vec(), Some(key))?;
+        let inserted = index.current_version().unwrap();
+        assert_eq!(index.last_modified_version(&key)?, Some(inserted));
+
+        index.create_new_version()?;
+        index.insert(b"unrelated".to_vec(), None)?;
+        index.insert(b"second".to_vec(), Some(key))?;
+        let modified = index.current_version().unwrap();
+        index.create_new_version()?;
+
+        assert_eq!(index.last_modified_version(&key)?, Some(modified));
+        Ok(())
+    }
+
     #[test]
     fn test_storage_index_save_and_load() -> Result<(), StorageError> {
         let dir = tempdir()?;
@@ -244,6 +1257,121 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_storage_index_tags() -> Result<(), StorageError> {
+        let dir = tempdir()?;
+        let path = dir.path().join("tagged");
+        let path = path.to_str().unwrap();
+
+        let mut index = StorageIndex::new(CacheConfig::default(), None)?;
+        let value = b"known good".to_vec();
+        let key = Sha256::new(&value);
+        index.insert(value.clone(), Some(key))?;
+        let good = index.create_new_version()?;
+        index.tag_version(good, "known-good".to_string())?;
+
+        index.remove(&key)?;
+        let later = index.create_new_version()?;
+        index.tag_version(later, "later".to_string())?;
+        assert!(matches!(
+            index.tag_version(later + 10, "bogus".to_string()),
+            Err(StorageError::VersionNotFound)
+        ));
+
+        index.save_to_disk(path, None)?;
+        let mut loaded = StorageIndex::load_from_disk(path, CacheConfig::default())?;
+        loaded.rollback_to_tag("known-good")?;
+        assert_eq!(loaded.get(&key)?, value);
+        assert!(matches!(
+            loaded.rollback_to_tag("later"),
+            Err(StorageError::TagNotFound(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_storage_index_recover_from_wal() -> Result<(), StorageError> {
+        let dir = tempdir()?;
+        let path = dir.path().join("wal_backup");
+        let path = path.to_str().unwrap();
+        let saved = b"saved".to_vec();
+        let buffered = b"buffered".to_vec();
+        let batched = b"batched".to_vec();
+
+        let mut index = StorageIndex::new(CacheConfig::default(), None)?;
+        index.enable_wal(path)?;
+        index.insert(saved.clone(), None)?;
+        index.save_to_disk(path, None)?;
+        assert!(WriteAheadLog::read_records(path)?.is_empty());
+
+        index.insert(buffered.clone(), None)?;
+        index.remove(&Sha256::new(&saved))?;
+        index.transaction(|txn| {
+            txn.insert(batched.clone(), None)?;
+            Ok(())
+        })?;
+        let expected_version = index.current_version();
+        drop(index);
+
+        let mut recovered = StorageIndex::recover(path, CacheConfig::default())?;
+        assert_eq!(recovered.get(&Sha256::new(&buffered))?, buffered);
+        assert_eq!(recovered.get(&Sha256::new(&batched))?, batched);
+        assert!(matches!(
+            recovered.get(&Sha256::new(&saved)),
+            Err(StorageError::KeyNotFound)
+        ));
+        assert_eq!(recovered.current_version(), expected_version);
+        Ok(())
+    }
+
+    #[test]
+    fn test_recover_after_torn_wal_write() -> Result<(), StorageError> {
+        let dir = tempdir()?;
+        let path = dir.path().join("wal_backup");
+        let path = path.to_str().unwrap();
+        let first = b"first".to_vec();
+        let second = b"second".to_vec();
+
+        let mut index = StorageIndex::new(CacheConfig::default(), None)?;
+        index.save_to_disk(path, None)?;
+        index.enable_wal(path)?;
+        index.insert(first.clone(), None)?;
+        drop(index);
+        std::fs::OpenOptions::new()
+            .append(true)
+            .open(dir.path().join("wal_backup").join("wal.log"))?
+            .write_all(&[0, 0, 0, 42, 1, 2])?;
+
+        let mut recovered = StorageIndex::recover(path, CacheConfig::default())?;
+        assert_eq!(recovered.get(&Sha256::new(&first))?, first);
+        recovered.insert(second.clone(), None)?;
+        drop(recovered);
+
+        let mut recovered = StorageIndex::recover(path, CacheConfig::default())?;
+        assert_eq!(recovered.get(&Sha256::new(&first))?, first);
+        assert_eq!(recovered.get(&Sha256::new(&second))?, second);
+        Ok(())
+    }
+
+    #[test]
+    fn test_save_to_disk_truncates_wal_through_equivalent_path() -> Result<(), StorageError> {
+        let dir = tempdir()?;
+        let path = dir.path().join("wal_backup");
+        let path = path.to_str().unwrap();
+        let mut index = StorageIndex::new(CacheConfig::default(), None)?;
+        index.enable_wal(path)?;
+        index.insert(b"value".to_vec(), None)?;
+        assert_eq!(WriteAheadLog::read_records(path)?.len(), 1);
+
+        index.save_to_disk(&format!("{path}/./"), None)?;
+        assert!(WriteAheadLog::read_records(path)?.is_empty());
+
+        index.insert(b"other".to_vec(), None)?;
+        index.save_to_disk(dir.path().join("elsewhere").to_str().unwrap(), None)?;
+        assert_eq!(WriteAheadLog::read_records(path)?.len(), 1);
+        Ok(())
+    }
+
     #[test]
     fn test_storage_index_metadata_and_version_history() -> Result<(), StorageError> {
         let mut index = StorageIndex::new(CacheConfig::default(), None)?;
@@ -284,6 +1412,40 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_storage_index_ttl_expiry() -> Result<(), StorageError> {
+        let mut index = StorageIndex::new(CacheConfig::default(), None)?;
+        let session = b"session token".to_vec();
+        let durable = b"durable data".to_vec();
+        let session_key = Sha256::new(&session);
+        let durable_key = Sha256::new(&durable);
+
+        index.insert_with_ttl(
+            session.clone(),
+            Some(session_key),
+            Duration::from_millis(50),
+        )?;
+        index.insert(durable.clone(), Some(durable_key))?;
+        assert_eq!(index.get(&session_key)?, session);
+
+        std::thread::sleep(Duration::from_millis(100));
+        assert!(matches!(
+            index.get(&session_key),
+            Err(StorageError::KeyNotFound)
+        ));
+        let latest = index.backup.get_latest_version().unwrap();
+        assert_eq!(latest.keys().collect::<Vec<_>>(), vec![&durable_key]);
+
+        let versions_before = index.get_version_history().len();
+        assert_eq!(index.purge_expired()?, 1);
+        assert_eq!(index.get_version_history().len(), versions_before + 1);
+        assert_eq!(index.get_metadata()?.size, 1);
+        assert_eq!(index.purge_expired()?, 0);
+        assert_eq!(index.get(&durable_key)?, durable);
+
+        Ok(())
+    }
+
     #[test]
     fn test_storage_index_max_versions() -> Result<(), StorageError> {
         let mut index = StorageIndex::new(CacheConfig::default(), None)?;
@@ -304,6 +1466,29 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_storage_index_rollback_trimmed_version() -> Result<(), StorageError> {
+        let mut index = StorageIndex::new(CacheConfig::default(), Some(3))?;
+        let mut created = Vec::new();
+        for i in 0..5 {
+            let value = format!("test data {}", i).into_bytes();
+            index.insert(value, None)?;
+            created.push(index.create_new_version()?);
+        }
+
+        assert!(matches!(
+            index.rollback(created[0]),
+            Err(StorageError::VersionTrimmed(v)) if v == created[0]
+        ));
+        assert!(matches!(
+            index.rollback(created[4] + 100),
+            Err(StorageError::VersionNotFound)
+        ));
+        assert_eq!(index.current_version(), Some(created[4]));
+        index.rollback(created[4])?;
+        Ok(())
+    }
+
     #[test]
     fn test_storage_index_clear_history() -> Result<(), StorageError> {
         let mut index = StorageIndex::new(CacheConfig::default(), None)?;
@@ -322,4 +1507,309 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_repair_restores_consistency() -> Result<(), StorageError> {
+        let mut index = StorageIndex::new(CacheConfig::default(), None)?;
+        index.insert(b"first".to_vec(), None)?;
+        let stale = index.latest_fragment()?;
+        index.insert(b"second".to_vec(), None)?;
+
+        let mut drifted = serde_json::to_value(index.latest_fragment()?).unwrap();
+        drifted["hash"] = serde_json::to_value(Sha256::new(b"drifted")).unwrap();
+        let drifted: Fragment = serde_json::from_value(drifted).unwrap();
+        index.backup.add_version(drifted)?;
+        index.cache.clear();
+        index.cache.insert(stale).unwrap();
+
+        let report = index.repair()?;
+        assert!(!report.is_empty());
+        assert_eq!(
+            report.repaired_versions,
+            vec![index.current_version().unwrap()]
+        );
+        assert_eq!(report.dropped_cache_entries, 1);
+        assert!(report.cache_rebuilt);
+
+        let latest = index.latest_fragment()?;
+        assert_eq!(latest.get_hash(), &latest.compute_hash());
+        assert!(index.cache.contains_key(&Sha256::new(&latest.to_bytes()?)));
+        assert!(index.repair()?.is_empty());
+        assert_eq!(index.get(&Sha256::new(b"second"))?, b"second".to_vec());
+        Ok(())
+    }
+
+    // Caches a copy of the latest fragment that still carries its hash but reads `key`'s
+    // value with the first letter capitalised, so reads show whether they hit the cache.
+    fn cache_corrupted_copy(index: &mut StorageIndex, key: &Sha256) -> Sha256 {
+        let latest = index.latest_fragment().unwrap();
+        let mut corrupted = serde_json::to_value(&latest).unwrap();
+        let stored = &mut corrupted["table"]["table"][key.encode_hex::<String>()];
+        stored[1] = (b'P').into();
+        let corrupted: Fragment = serde_json::from_value(corrupted).unwrap();
+        let corrupted_key = index.cache.insert(corrupted).unwrap();
+        index.latest_cache_key = Some(corrupted_key);
+        corrupted_key
+    }
+
+    #[test]
+    fn test_read_repair() -> Result<(), StorageError> {
+        let mut index = StorageIndex::new(CacheConfig::default(), None)?;
+        let key = Sha256::new(b"payload");
+        index.insert(b"payload".to_vec(), Some(key))?;
+
+        let latest = index.latest_fragment()?;
+        let corrupted_key = cache_corrupted_copy(&mut index, &key);
+        assert_eq!(index.get(&key)?, b"Payload".to_vec());
+
+        index.set_read_repair(true);
+        assert_eq!(index.get(&key)?, b"payload".to_vec());
+        let latest_key = Sha256::new(&latest.to_bytes()?);
+        assert!(!index.cache.contains_key(&corrupted_key));
+        assert!(index.cache.contains_key(&latest_key));
+        assert_eq!(index.latest_cache_key, Some(latest_key));
+        assert_eq!(index.get(&key)?, b"payload".to_vec());
+        Ok(())
+    }
+
+    #[test]
+    fn test_recompress_with_trained_dict() -> Result<(), StorageError> {
+        let mut index = StorageIndex::new(CacheConfig::default(), None)?;
+        let blobs: Vec<Vec<u8>> = (0..200)
+            .map(|i| {
+                format!(
+                    r#"{{"id":{},"kind":"sensor_reading","unit":"celsius","location":"warehouse-{}","value":{}.{}}}"#,
+                    i,
+                    i % 7,
+                    20 + i % 13,
+                    i % 10
+                )
+                .into_bytes()
+            })
+            .collect();
+        for blob in &blobs {
+            index.insert(blob.clone(), None)?;
+        }
+        let stored_size = |index: &StorageIndex| -> usize {
+            index
+                .latest_fragment()
+                .unwrap()
+                .iter_mut()
+                .map(|(_, stored)| stored.len())
+                .sum()
+        };
+        let before = stored_size(&index);
+        let version = index.current_version();
+
+        let dict = train_dictionary(&blobs, 4096).unwrap();
+        index.recompress_with_dict(dict.clone())?;
+
+        assert_eq!(index.current_version(), version.map(|v| v + 1));
+        assert_eq!(index.get_metadata()?.compression_dict, Some(dict));
+        assert!(stored_size(&index) * 2 < before);
+        for blob in &blobs {
+            assert_eq!(&index.get(&Sha256::new(blob))?, blob);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_benchmark() -> Result<(), StorageError> {
+        let result = StorageIndex::benchmark(256, 50)?;
+        assert!(result.insert_ops_per_sec > 0.0);
+        assert!(result.insert_bytes_per_sec > result.insert_ops_per_sec);
+        assert!(result.get_ops_per_sec > 0.0);
+        assert!(result.get_bytes_per_sec > result.get_ops_per_sec);
+        Ok(())
+    }
+
+    #[test]
+    fn test_amplification_factor() -> Result<(), StorageError> {
+        let mut index = StorageIndex::new(CacheConfig::default(), None)?;
+        assert_eq!(index.amplification_factor()?, 1.0);
+
+        index.insert(b"only version".to_vec(), None)?;
+        index.clear_history();
+        assert_eq!(index.amplification_factor()?, 1.0);
+
+        for i in 0..5 {
+            index.insert(
+                format!("growing data {}", i).repeat(i + 1).into_bytes(),
+                None,
+            )?;
+        }
+        assert!(index.amplification_factor()? > 1.0);
+
+        let keys: Vec<Sha256> = index.latest_fragment()?.keys().copied().collect();
+        for key in &keys {
+            index.remove(key)?;
+        }
+        assert!(matches!(
+            index.amplification_factor(),
+            Err(StorageError::EmptyLatestVersion)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_compact() -> Result<(), StorageError> {
+        let mut index = StorageIndex::new(CacheConfig::default(), None)?;
+        for i in 0..6 {
+            index.insert(format!("value {}", i).into_bytes(), None)?;
+        }
+        let latest = index.latest_fragment()?.to_bytes()?;
+        let newest = index.current_version().unwrap();
+        index.tag_version(newest - 1, "old".to_string())?;
+        index.tag_version(newest - 3, "older".to_string())?;
+
+        index.compact_keep_last(3)?;
+        assert_eq!(index.get_version_history().len(), 3);
+        assert_eq!(index.current_version(), Some(newest));
+        assert!(matches!(
+            index.rollback(newest - 3),
+            Err(StorageError::VersionTrimmed(_))
+        ));
+        assert!(index.rollback_to_tag("older").is_err());
+        assert_eq!(index.latest_fragment()?.to_bytes()?, latest);
+
+        index.tag_version(newest, "head".to_string())?;
+        index.compact()?;
+        assert_eq!(index.get_version_history().len(), 1);
+        assert_eq!(index.current_version(), Some(1));
+        assert_eq!(index.latest_fragment()?.to_bytes()?, latest);
+        assert!(index.rollback_to_tag("old").is_err());
+        assert_eq!(index.rollback_to_tag("head")?.to_bytes()?, latest);
+        assert!(index.backup.get_version_control().verify_chain().is_ok());
+        assert_eq!(index.get(&Sha256::new(b"value 0"))?, b"value 0".to_vec());
+
+        index.insert(b"after".to_vec(), None)?;
+        assert_eq!(index.current_version(), Some(2));
+        Ok(())
+    }
+
+    #[test]
+    fn test_salted_content_keys() -> Result<(), StorageError> {
+        let value = b"shared payload".to_vec();
+        let mut first = StorageIndex::new_with_salt(CacheConfig::default(), None, b"a".to_vec())?;
+        let mut second = StorageIndex::new_with_salt(CacheConfig::default(), None, b"b".to_vec())?;
+        let unsalted = StorageIndex::new(CacheConfig::default(), None)?;
+
+        assert_ne!(first.content_key(&value), second.content_key(&value));
+        assert_eq!(unsalted.content_key(&value), Sha256::new(&value));
+
+        first.insert(value.clone(), None)?;
+        second.transaction(|txn| txn.insert(value.clone(), None).map(|_| ()))?;
+        assert_eq!(first.get(&first.content_key(&value))?, value);
+        assert_eq!(second.get(&second.content_key(&value))?, value);
+        assert!(matches!(
+            first.get(&Sha256::new(&value)),
+            Err(StorageError::KeyNotFound)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_hash_algorithm_survives_reload() -> Result<(), StorageError> {
+        let dir = tempdir()?;
+        let path = dir.path().to_str().unwrap();
+        let value = b"hashed with blake3".to_vec();
+        let mut index = StorageIndex::new_with_hash_algorithm(
+            CacheConfig::default(),
+            None,
+            HashAlgorithm::Blake3,
+        )?;
+        index.insert(value.clone(), None)?;
+        let key = index.content_key(&value);
+        assert_eq!(key, HashAlgorithm::Blake3.digest(&value));
+        assert_ne!(key, Sha256::new(&value));
+        index.save_to_disk(path, None)?;
+
+        let mut loaded = StorageIndex::load_from_disk(path, CacheConfig::default())?;
+        assert_eq!(loaded.content_key(&value), key);
+        assert_eq!(loaded.get(&key)?, value);
+        assert!(loaded.repair()?.repaired_versions.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_many() -> Result<(), StorageError> {
+        let mut index = StorageIndex::new(CacheConfig::default(), None)?;
+        let present: Vec<Vec<u8>> = (0..3)
+            .map(|i| format!("record {}", i).into_bytes())
+            .collect();
+        for value in &present {
+            index.insert(value.clone(), None)?;
+        }
+        let keys = [
+            Sha256::new(&present[0]),
+            Sha256::new(b"absent"),
+            Sha256::new(&present[2]),
+            Sha256::new(&present[1]),
+        ];
+
+        assert_eq!(
+            index.get_many(&keys)?,
+            vec![
+                Some(present[0].clone()),
+                None,
+                Some(present[2].clone()),
+                Some(present[1].clone()),
+            ]
+        );
+        assert!(index.get_many(&[])?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_many_reads_cached_fragment() -> Result<(), StorageError> {
+        let mut index = StorageIndex::new(CacheConfig::default(), None)?;
+        let key = Sha256::new(b"payload");
+        let absent = Sha256::new(b"absent");
+        index.insert(b"payload".to_vec(), Some(key))?;
+        index.set_access_counting(true);
+        let corrupted_key = cache_corrupted_copy(&mut index, &key);
+
+        assert_eq!(
+            index.get_many(&[key, absent])?,
+            vec![Some(b"Payload".to_vec()), None]
+        );
+        index.set_read_repair(true);
+        assert_eq!(index.get_many(&[key])?, vec![Some(b"payload".to_vec())]);
+        assert!(!index.cache.contains_key(&corrupted_key));
+        assert_eq!(index.hot_keys(2), vec![(key, 2)]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_storage_index_seal() -> Result<(), StorageError> {
+        let mut index = StorageIndex::new(CacheConfig::default(), None)?;
+        let value = b"frozen".to_vec();
+        let key = Sha256::new(&value);
+        index.insert(value.clone(), None)?;
+        let version = index.current_version().unwrap();
+        assert!(!index.is_sealed());
+
+        index.seal();
+        assert!(index.is_sealed());
+        assert!(matches!(
+            index.insert(b"more".to_vec(), None),
+            Err(StorageError::ReadOnly)
+        ));
+        assert!(matches!(index.remove(&key), Err(StorageError::ReadOnly)));
+        assert!(matches!(index.rollback(1), Err(StorageError::ReadOnly)));
+        assert!(matches!(
+            index.create_new_version(),
+            Err(StorageError::ReadOnly)
+        ));
+        assert!(matches!(
+            index.transaction(|_| Ok(())),
+            Err(StorageError::ReadOnly)
+        ));
+        assert!(matches!(index.repair(), Err(StorageError::ReadOnly)));
+
+        assert_eq!(index.get(&key)?, value);
+        assert_eq!(index.get_shared(&key)?, value);
+        assert_eq!(index.current_version(), Some(version));
+        Ok(())
+    }
 }
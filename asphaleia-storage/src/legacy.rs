@@ -0,0 +1,76 @@
+use super::{
+    fragment::Fragment,
+    versioning::{Version, VersionControl},
+};
+use asphaleia_crypto::hash::Sha256;
+use serde::Deserialize;
+use std::{
+    collections::{BTreeMap, HashMap},
+    time::SystemTime,
+};
+
+// `versions.bin` as the first release wrote it (payload format 0). Bincode keeps no field
+// names or defaults, so these mirror that layout field for field; fields the current
+// types recompute are read and dropped.
+#[derive(Deserialize)]
+pub(crate) struct LegacyVersionControl {
+    versions: Vec<LegacyVersion>,
+    max_versions: Option<usize>,
+}
+
+#[derive(Deserialize)]
+struct LegacyVersion {
+    creation_date: u64,
+    version: u64,
+    fragment: LegacyFragment,
+}
+
+#[derive(Deserialize)]
+struct LegacyFragment {
+    table: LegacyTable,
+    _hash: Sha256,
+    metadata: LegacyMetadata,
+}
+
+#[derive(Deserialize)]
+struct LegacyTable {
+    table: BTreeMap<Sha256, Vec<u8>>,
+}
+
+// `compression` was a free-form label; values were always zstd.
+#[derive(Deserialize)]
+struct LegacyMetadata {
+    creation_date: SystemTime,
+    last_modified: SystemTime,
+    _compression: String,
+    compression_level: i32,
+    compression_dict: Option<Vec<u8>>,
+    _size: usize,
+}
+
+impl LegacyVersionControl {
+    // The old versions had no `prev_hash`, so the chain is built fresh over them.
+    pub(crate) fn migrate(self) -> VersionControl {
+        let mut versions: Vec<Version> = Vec::with_capacity(self.versions.len());
+        for legacy in self.versions {
+            let metadata = legacy.fragment.metadata;
+            let fragment = Fragment::from_untagged(
+                metadata.compression_level,
+                metadata.compression_dict,
+                metadata.creation_date,
+                metadata.last_modified,
+                legacy.fragment.table.table,
+            );
+            let prev_hash = versions
+                .last()
+                .map_or(Sha256([0u8; 32]), |previous| previous.digest());
+            versions.push(Version {
+                creation_date: legacy.creation_date,
+                version: legacy.version,
+                prev_hash,
+                fragment,
+            });
+        }
+        VersionControl::from_parts(versions, self.max_versions, HashMap::new())
+    }
+}
@@ -1,15 +1,24 @@
 mod backup;
 mod cache;
 mod compression;
+mod digest;
 mod fragment;
 mod index;
+mod legacy;
+mod store;
 mod table;
+mod transaction;
 mod versioning;
+mod wal;
 
 pub use backup::*;
 pub use cache::*;
 pub use compression::*;
+pub use digest::*;
 pub use fragment::*;
 pub use index::*;
+pub use store::*;
 pub use table::*;
+pub use transaction::*;
 pub use versioning::*;
+pub use wal::*;
@@ -0,0 +1,106 @@
+use std::{
+    fs::{self, create_dir_all, File},
+    io::{self, BufReader, BufWriter, Cursor, Read, Write},
+    path::PathBuf,
+};
+
+// Where a backup's files live. Implementors only need `write`, `read`, `list` and
+// `remove`; the streaming hooks default to buffering whole files through them.
+pub trait BackupStore {
+    fn write(&mut self, name: &str, bytes: &[u8]) -> io::Result<()>;
+    fn read(&self, name: &str) -> io::Result<Vec<u8>>;
+    fn list(&self) -> io::Result<Vec<String>>;
+    fn remove(&mut self, name: &str) -> io::Result<()>;
+
+    fn exists(&self, name: &str) -> io::Result<bool> {
+        Ok(self.list()?.iter().any(|listed| listed == name))
+    }
+
+    fn reader<'a>(&'a self, name: &str) -> io::Result<Box<dyn Read + 'a>> {
+        Ok(Box::new(Cursor::new(self.read(name)?)))
+    }
+
+    // The default writer hands its buffer to `write` on every `flush`.
+    fn writer<'a>(&'a mut self, name: &str) -> io::Result<Box<dyn Write + 'a>> {
+        Ok(Box::new(BufferedStoreWriter {
+            store: self,
+            name: name.to_string(),
+            buffer: Vec::new(),
+        }))
+    }
+}
+
+struct BufferedStoreWriter<'a, S: BackupStore + ?Sized> {
+    store: &'a mut S,
+    name: String,
+    buffer: Vec<u8>,
+}
+
+impl<S: BackupStore + ?Sized> Write for BufferedStoreWriter<'_, S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.store.write(&self.name, &self.buffer)
+    }
+}
+
+// One file per name under `root`, which is created on the first write.
+pub struct FileSystemStore {
+    root: PathBuf,
+}
+
+impl FileSystemStore {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+}
+
+impl BackupStore for FileSystemStore {
+    fn write(&mut self, name: &str, bytes: &[u8]) -> io::Result<()> {
+        create_dir_all(&self.root)?;
+        fs::write(self.root.join(name), bytes)
+    }
+
+    fn read(&self, name: &str) -> io::Result<Vec<u8>> {
+        fs::read(self.root.join(name))
+    }
+
+    fn list(&self) -> io::Result<Vec<String>> {
+        let entries = match fs::read_dir(&self.root) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        let mut names = Vec::new();
+        for entry in entries {
+            let entry = entry?;
+            if entry.file_type()?.is_file() {
+                names.extend(entry.file_name().into_string().ok());
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    fn remove(&mut self, name: &str) -> io::Result<()> {
+        fs::remove_file(self.root.join(name))
+    }
+
+    fn exists(&self, name: &str) -> io::Result<bool> {
+        Ok(self.root.join(name).is_file())
+    }
+
+    fn reader<'a>(&'a self, name: &str) -> io::Result<Box<dyn Read + 'a>> {
+        Ok(Box::new(BufReader::new(File::open(self.root.join(name))?)))
+    }
+
+    fn writer<'a>(&'a mut self, name: &str) -> io::Result<Box<dyn Write + 'a>> {
+        create_dir_all(&self.root)?;
+        Ok(Box::new(BufWriter::new(File::create(
+            self.root.join(name),
+        )?)))
+    }
+}
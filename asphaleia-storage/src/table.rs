@@ -2,31 +2,52 @@ use asphaleia_crypto::hash::Sha256;
 use serde::{Deserialize, Serialize};
 use std::collections::BTreeMap;
 
+// Keys default to `Sha256`, which is all `Fragment` uses; any `Ord` key works for
+// callers building their own layer on top.
 #[derive(Debug, Serialize, Deserialize, Clone)]
-pub struct Table {
-    table: BTreeMap<Sha256, Vec<u8>>,
+pub struct Table<K: Ord = Sha256> {
+    table: BTreeMap<K, Vec<u8>>,
+    #[serde(default = "BTreeMap::new")]
+    checksums: BTreeMap<K, u32>,
 }
 
-impl Table {
+impl<K: Ord> Default for Table<K> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: Ord> Table<K> {
     pub fn new() -> Self {
         Self {
             table: BTreeMap::new(),
+            checksums: BTreeMap::new(),
         }
     }
 
-    pub fn insert(&mut self, value: Vec<u8>, key: Sha256) -> Option<Vec<u8>> {
+    pub fn insert(&mut self, value: Vec<u8>, key: K) -> Option<Vec<u8>> {
+        self.checksums.remove(&key);
         self.table.insert(key, value)
     }
 
-    pub fn get(&self, key: &Sha256) -> Option<&Vec<u8>> {
+    pub fn set_checksum(&mut self, key: K, checksum: u32) {
+        self.checksums.insert(key, checksum);
+    }
+
+    pub fn get_checksum(&self, key: &K) -> Option<u32> {
+        self.checksums.get(key).copied()
+    }
+
+    pub fn get(&self, key: &K) -> Option<&Vec<u8>> {
         self.table.get(key)
     }
 
-    pub fn remove(&mut self, key: &Sha256) -> Option<Vec<u8>> {
+    pub fn remove(&mut self, key: &K) -> Option<Vec<u8>> {
+        self.checksums.remove(key);
         self.table.remove(key)
     }
 
-    pub fn contains_key(&self, key: &Sha256) -> bool {
+    pub fn contains_key(&self, key: &K) -> bool {
         self.table.contains_key(key)
     }
 
@@ -39,18 +60,19 @@ impl Table {
     }
 
     pub fn clear(&mut self) {
-        self.table.clear()
+        self.table.clear();
+        self.checksums.clear();
     }
 
-    pub fn iter(&self) -> impl Iterator<Item = (&Sha256, &Vec<u8>)> {
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = (&K, &Vec<u8>)> {
         self.table.iter()
     }
 
-    pub fn iter_mut(&mut self) -> impl Iterator<Item = (&Sha256, &mut Vec<u8>)> {
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (&K, &mut Vec<u8>)> {
         self.table.iter_mut()
     }
 
-    pub fn keys(&self) -> impl Iterator<Item = &Sha256> {
+    pub fn keys(&self) -> impl Iterator<Item = &K> {
         self.table.keys()
     }
 
@@ -62,66 +84,117 @@ impl Table {
         self.table.values_mut()
     }
 
-    pub fn entry(
-        &mut self,
-        key: Sha256,
-    ) -> std::collections::btree_map::Entry<'_, Sha256, Vec<u8>> {
+    pub fn entry(&mut self, key: K) -> std::collections::btree_map::Entry<'_, K, Vec<u8>> {
         self.table.entry(key)
     }
 
-    pub fn append(&mut self, other: &mut Table) {
+    pub fn append(&mut self, other: &mut Table<K>) {
+        for key in other.table.keys() {
+            self.checksums.remove(key);
+        }
+        self.checksums.append(&mut other.checksums);
         self.table.append(&mut other.table)
     }
 
-    pub fn range<R>(&self, range: R) -> impl Iterator<Item = (&Sha256, &Vec<u8>)>
+    pub fn range<R>(&self, range: R) -> impl Iterator<Item = (&K, &Vec<u8>)>
     where
-        R: std::ops::RangeBounds<Sha256>,
+        R: std::ops::RangeBounds<K>,
     {
         self.table.range(range)
     }
 
-    pub fn range_mut<R>(&mut self, range: R) -> impl Iterator<Item = (&Sha256, &mut Vec<u8>)>
+    pub fn range_mut<R>(&mut self, range: R) -> impl Iterator<Item = (&K, &mut Vec<u8>)>
     where
-        R: std::ops::RangeBounds<Sha256>,
+        R: std::ops::RangeBounds<K>,
     {
         self.table.range_mut(range)
     }
 
-    pub fn first_key_value(&self) -> Option<(&Sha256, &Vec<u8>)> {
+    pub fn first_key_value(&self) -> Option<(&K, &Vec<u8>)> {
         self.table.first_key_value()
     }
 
-    pub fn last_key_value(&self) -> Option<(&Sha256, &Vec<u8>)> {
+    pub fn last_key_value(&self) -> Option<(&K, &Vec<u8>)> {
         self.table.last_key_value()
     }
 
     pub fn first_entry(
         &mut self,
-    ) -> Option<std::collections::btree_map::OccupiedEntry<'_, Sha256, Vec<u8>>> {
+    ) -> Option<std::collections::btree_map::OccupiedEntry<'_, K, Vec<u8>>> {
         self.table.first_entry()
     }
 
     pub fn last_entry(
         &mut self,
-    ) -> Option<std::collections::btree_map::OccupiedEntry<'_, Sha256, Vec<u8>>> {
+    ) -> Option<std::collections::btree_map::OccupiedEntry<'_, K, Vec<u8>>> {
         self.table.last_entry()
     }
 
-    pub fn pop_first(&mut self) -> Option<(Sha256, Vec<u8>)> {
-        self.table.pop_first()
+    pub fn pop_first(&mut self) -> Option<(K, Vec<u8>)> {
+        let entry = self.table.pop_first();
+        if let Some((key, _)) = &entry {
+            self.checksums.remove(key);
+        }
+        entry
     }
 
-    pub fn pop_last(&mut self) -> Option<(Sha256, Vec<u8>)> {
-        self.table.pop_last()
+    pub fn pop_last(&mut self) -> Option<(K, Vec<u8>)> {
+        let entry = self.table.pop_last();
+        if let Some((key, _)) = &entry {
+            self.checksums.remove(key);
+        }
+        entry
     }
+}
 
+impl<K: Ord + Serialize> Table<K> {
+    // For `Sha256` keys the bincode encoding is exactly the 32 raw bytes.
     pub fn to_bytes(&self) -> Vec<u8> {
         let mut bytes = Vec::new();
+        self.feed_bytes(|chunk| bytes.extend_from_slice(chunk));
+        bytes
+    }
+
+    // Hands `to_bytes` to `sink` piece by piece, so hashing needn't build the buffer.
+    pub fn feed_bytes(&self, mut sink: impl FnMut(&[u8])) {
+        let mut encoded_key = Vec::new();
         for (key, value) in self.table.iter() {
-            bytes.extend_from_slice(key.as_bytes());
-            bytes.extend_from_slice(&(value.len() as u32).to_be_bytes());
-            bytes.extend_from_slice(value);
+            encoded_key.clear();
+            bincode::serialize_into(&mut encoded_key, key).expect("Failed to serialize table key");
+            sink(&encoded_key);
+            sink(&(value.len() as u32).to_be_bytes());
+            sink(value);
         }
-        bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_native_keys() {
+        let mut by_id: Table<u64> = Table::new();
+        for id in [30u64, 10, 20] {
+            by_id.insert(id.to_be_bytes().to_vec(), id);
+        }
+        assert_eq!(by_id.keys().copied().collect::<Vec<_>>(), vec![10, 20, 30]);
+        assert_eq!(by_id.range(15..).count(), 2);
+
+        let mut by_name: Table<String> = Table::new();
+        by_name.insert(b"v".to_vec(), "beta".to_string());
+        by_name.insert(b"v".to_vec(), "alpha".to_string());
+        assert_eq!(by_name.first_key_value().unwrap().0, "alpha");
+
+        let mut hashed = Table::new();
+        let key = Sha256::new(b"key");
+        hashed.insert(b"value".to_vec(), key);
+        let expected = [&key.0[..], &5u32.to_be_bytes(), b"value"].concat();
+        assert_eq!(hashed.to_bytes(), expected);
+
+        let mut fed = Vec::new();
+        hashed.feed_bytes(|chunk| fed.push(chunk.to_vec()));
+        assert_eq!(fed.len(), 3);
+        assert_eq!(fed.concat(), expected);
     }
 }
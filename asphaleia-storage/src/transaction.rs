@@ -0,0 +1,168 @@
+use super::{
+    fragment::Fragment,
+    index::{content_key, StorageError, StorageIndex},
+    wal::{WalError, WalRecord},
+};
+use asphaleia_crypto::hash::Sha256;
+
+pub struct FragmentTxn {
+    fragment: Fragment,
+    journal: Option<Vec<WalRecord>>,
+    key_salt: Option<Vec<u8>>,
+}
+
+impl FragmentTxn {
+    pub(crate) fn new(fragment: Fragment, journaled: bool, key_salt: Option<Vec<u8>>) -> Self {
+        Self {
+            fragment,
+            journal: journaled.then(Vec::new),
+            key_salt,
+        }
+    }
+
+    pub fn insert(
+        &mut self,
+        value: Vec<u8>,
+        key: Option<Sha256>,
+    ) -> Result<Option<Vec<u8>>, StorageError> {
+        let key = key.unwrap_or_else(|| {
+            let algorithm = self.fragment.get_metadata().hash_algorithm;
+            content_key(algorithm, self.key_salt.as_deref(), &value)
+        });
+        if let Some(journal) = self.journal.as_mut() {
+            journal.push(WalRecord::Insert {
+                key,
+                value: value.clone(),
+                expires_at: None,
+            });
+        }
+        Ok(self.fragment.insert(value, key)?)
+    }
+
+    pub fn get(&self, key: &Sha256) -> Result<Vec<u8>, StorageError> {
+        self.fragment.get(key)?.ok_or(StorageError::KeyNotFound)
+    }
+
+    pub fn remove(&mut self, key: &Sha256) -> Result<Vec<u8>, StorageError> {
+        let value = self.fragment.remove(key).ok_or(StorageError::KeyNotFound)?;
+        if let Some(journal) = self.journal.as_mut() {
+            journal.push(WalRecord::Remove { key: *key });
+        }
+        Ok(value)
+    }
+
+    pub fn contains_key(&self, key: &Sha256) -> bool {
+        self.fragment.contains_key(key)
+    }
+
+    pub(crate) fn replay(&mut self, record: WalRecord) -> Result<(), StorageError> {
+        match record {
+            WalRecord::Insert { key, value, .. } => {
+                self.insert(value, Some(key))?;
+            }
+            WalRecord::Remove { key } => {
+                self.remove(&key)?;
+            }
+            _ => return Err(WalError::InvalidRecord.into()),
+        }
+        Ok(())
+    }
+
+    pub(crate) fn into_parts(self) -> (Fragment, Vec<WalRecord>) {
+        (self.fragment, self.journal.unwrap_or_default())
+    }
+}
+
+// A transaction that ran to completion against one index but has not been committed.
+pub struct PreparedTxn {
+    txn: FragmentTxn,
+    base_version: Option<u64>,
+    base_hash: Sha256,
+}
+
+impl PreparedTxn {
+    pub(crate) fn new(txn: FragmentTxn, base_version: Option<u64>, base_hash: Sha256) -> Self {
+        Self {
+            txn,
+            base_version,
+            base_hash,
+        }
+    }
+
+    pub fn base_version(&self) -> Option<u64> {
+        self.base_version
+    }
+
+    pub(crate) fn base_hash(&self) -> Sha256 {
+        self.base_hash
+    }
+
+    pub(crate) fn into_txn(self) -> FragmentTxn {
+        self.txn
+    }
+}
+
+type TxnBody<'a> = Box<dyn FnOnce(&mut FragmentTxn) -> Result<(), StorageError> + 'a>;
+
+// Applies one transaction per shard, all or none. Every shard is prepared before any is
+// committed, so a failing body commits nothing; if a commit itself fails, shards that
+// already committed are rolled back to their prepared base version. A shard with no
+// version to return to is refused while preparing. Rolling back can still fail, e.g.
+// when `max_versions` trimmed the base; those shards are named in `PartialCommit`.
+#[derive(Default)]
+pub struct TwoPhaseCommit<'a> {
+    participants: Vec<(&'a mut StorageIndex, TxnBody<'a>)>,
+}
+
+impl<'a> TwoPhaseCommit<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn participant<F>(mut self, index: &'a mut StorageIndex, f: F) -> Self
+    where
+        F: FnOnce(&mut FragmentTxn) -> Result<(), StorageError> + 'a,
+    {
+        self.participants.push((index, Box::new(f)));
+        self
+    }
+
+    // Errors name the shard by its position in `participant` order.
+    pub fn commit(self) -> Result<(), StorageError> {
+        let aborted = |shard, source| StorageError::ShardAborted {
+            shard,
+            source: Box::new(source),
+        };
+        let mut prepared = Vec::with_capacity(self.participants.len());
+        for (shard, (index, f)) in self.participants.into_iter().enumerate() {
+            let txn = index.prepare(f).map_err(|e| aborted(shard, e))?;
+            let base_version = txn
+                .base_version()
+                .ok_or_else(|| aborted(shard, StorageError::VersionNotFound))?;
+            prepared.push((index, txn, base_version));
+        }
+
+        let mut committed: Vec<(usize, &mut StorageIndex, u64)> =
+            Vec::with_capacity(prepared.len());
+        for (shard, (index, txn, base_version)) in prepared.into_iter().enumerate() {
+            if let Err(e) = index.commit_prepared(txn) {
+                let inconsistent: Vec<(usize, StorageError)> = committed
+                    .into_iter()
+                    .filter_map(|(done, index, version)| {
+                        index.rollback(version).err().map(|e| (done, e))
+                    })
+                    .collect();
+                if inconsistent.is_empty() {
+                    return Err(aborted(shard, e));
+                }
+                return Err(StorageError::PartialCommit {
+                    shard,
+                    source: Box::new(e),
+                    inconsistent,
+                });
+            }
+            committed.push((shard, index, base_version));
+        }
+        Ok(())
+    }
+}
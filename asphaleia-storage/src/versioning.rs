@@ -1,11 +1,28 @@
-use super::fragment::Fragment;
+use super::{
+    compression::CompressionAlgorithm,
+    fragment::{Fragment, FragmentError},
+};
+use asphaleia_crypto::hash::Sha256;
 use serde::{Deserialize, Serialize};
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::{
+    collections::HashMap,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum VersionError {
+    #[error("Hash chain broken at version {version}")]
+    ChainBroken { version: u64 },
+    #[error("Version {0} not found")]
+    NotFound(u64),
+}
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Version {
     pub creation_date: u64,
     pub version: u64,
+    pub prev_hash: Sha256,
     pub fragment: Fragment,
 }
 
@@ -17,10 +34,20 @@ impl Version {
                 .expect("Time went backwards")
                 .as_secs(),
             version: 1,
+            prev_hash: Sha256([0u8; 32]),
             fragment,
         }
     }
 
+    pub fn digest(&self) -> Sha256 {
+        let mut bytes = Vec::with_capacity(80);
+        bytes.extend_from_slice(&self.version.to_be_bytes());
+        bytes.extend_from_slice(&self.creation_date.to_be_bytes());
+        bytes.extend_from_slice(self.prev_hash.as_bytes());
+        bytes.extend_from_slice(self.fragment.compute_hash().as_bytes());
+        Sha256::new(&bytes)
+    }
+
     pub fn increment(&mut self) {
         self.version += 1;
         self.creation_date = SystemTime::now()
@@ -30,20 +57,66 @@ impl Version {
     }
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VersionInfo {
+    pub version: u64,
+    pub creation_date: SystemTime,
+    pub size: usize,
+    pub hash: Sha256,
+}
+
+impl From<&Version> for VersionInfo {
+    fn from(version: &Version) -> Self {
+        Self {
+            version: version.version,
+            creation_date: UNIX_EPOCH + Duration::from_secs(version.creation_date),
+            size: version.fragment.len(),
+            hash: *version.fragment.get_hash(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct VersionDiff {
+    pub added: Vec<Sha256>,
+    pub removed: Vec<Sha256>,
+    pub modified: Vec<Sha256>,
+}
+
+impl VersionDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.modified.is_empty()
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
 pub struct VersionControl {
     versions: Vec<Version>,
     max_versions: Option<usize>,
+    #[serde(default)]
+    tags: HashMap<String, u64>,
 }
 
 impl VersionControl {
     pub fn new(max_versions: Option<usize>) -> Self {
-        let mut versions: Vec<Version> = Vec::new();
-        versions.push(Self::genesis_version());
+        let versions: Vec<Version> = vec![Self::genesis_version()];
+
+        Self {
+            versions,
+            max_versions,
+            tags: HashMap::new(),
+        }
+    }
 
+    pub(crate) fn from_parts(
+        versions: Vec<Version>,
+        max_versions: Option<usize>,
+        tags: HashMap<String, u64>,
+    ) -> Self {
         Self {
             versions,
             max_versions,
+            tags,
         }
     }
 
@@ -51,6 +124,7 @@ impl VersionControl {
         let new_version = if let Some(last_version) = self.versions.last() {
             let mut version = last_version.clone();
             version.increment();
+            version.prev_hash = last_version.digest();
             version.fragment = fragment;
             version
         } else {
@@ -69,6 +143,71 @@ impl VersionControl {
         self.versions.iter().find(|v| v.version == version)
     }
 
+    pub fn diff(&self, from: u64, to: u64) -> Result<Option<VersionDiff>, FragmentError> {
+        let (Some(from), Some(to)) = (self.get_version(from), self.get_version(to)) else {
+            return Ok(None);
+        };
+        let (old, new) = (&from.fragment, &to.fragment);
+        let mut diff = VersionDiff::default();
+        for key in old.keys() {
+            if !new.contains_key(key) {
+                diff.removed.push(*key);
+            } else if old.get(key)? != new.get(key)? {
+                diff.modified.push(*key);
+            }
+        }
+        diff.added = new
+            .keys()
+            .filter(|key| !old.contains_key(key))
+            .copied()
+            .collect();
+        Ok(Some(diff))
+    }
+
+    // Only looks at key sets, so it skips the value comparisons `diff` pays for.
+    pub fn keys_only_in(&self, a: u64, b: u64) -> Result<Vec<Sha256>, VersionError> {
+        let a = self.get_version(a).ok_or(VersionError::NotFound(a))?;
+        let b = self.get_version(b).ok_or(VersionError::NotFound(b))?;
+        Ok(a.fragment
+            .keys()
+            .filter(|key| !b.fragment.contains_key(key))
+            .copied()
+            .collect())
+    }
+
+    pub fn versions_between(&self, start: SystemTime, end: SystemTime) -> Vec<VersionInfo> {
+        self.versions
+            .iter()
+            .map(VersionInfo::from)
+            .filter(|info| info.creation_date >= start && info.creation_date <= end)
+            .collect()
+    }
+
+    pub fn last_modified_version(&self, key: &Sha256) -> Result<Option<u64>, FragmentError> {
+        for pair in self.versions.windows(2).rev() {
+            if pair[1].fragment.get(key)? != pair[0].fragment.get(key)? {
+                return Ok(Some(pair[1].version));
+            }
+        }
+        Ok(self
+            .versions
+            .first()
+            .filter(|v| v.fragment.contains_key(key))
+            .map(|v| v.version))
+    }
+
+    // The oldest retained version anchors the chain; trimmed predecessors can't be checked.
+    pub fn verify_chain(&self) -> Result<(), VersionError> {
+        for pair in self.versions.windows(2) {
+            if pair[1].prev_hash != pair[0].digest() {
+                return Err(VersionError::ChainBroken {
+                    version: pair[1].version,
+                });
+            }
+        }
+        Ok(())
+    }
+
     fn genesis_version() -> Version {
         Version {
             creation_date: SystemTime::now()
@@ -76,24 +215,76 @@ impl VersionControl {
                 .expect("Time went backwards")
                 .as_secs(),
             version: 0,
-            fragment: Fragment::new("zstd  ".to_string(), 3, None),
+            prev_hash: Sha256([0u8; 32]),
+            fragment: Fragment::new(CompressionAlgorithm::Zstd, 3, None),
+        }
+    }
+
+    pub fn repair_hashes(&mut self) -> Vec<u64> {
+        self.versions
+            .iter_mut()
+            .filter_map(|v| v.fragment.repair_hash().then_some(v.version))
+            .collect()
+    }
+
+    pub fn versions_after(&self, version: u64) -> &[Version] {
+        let start = self.versions.partition_point(|v| v.version <= version);
+        &self.versions[start..]
+    }
+
+    pub fn extend_versions(&mut self, versions: Vec<Version>) {
+        self.versions.extend(versions);
+        if let Some(max) = self.max_versions {
+            while self.versions.len() > max {
+                self.versions.remove(0);
+            }
         }
     }
 
+    pub fn set_tags(&mut self, tags: HashMap<String, u64>) {
+        self.tags = tags;
+    }
+
     pub fn get_latest_version(&self) -> Option<&Version> {
         self.versions.last()
     }
 
+    pub fn get_oldest_version_number(&self) -> Option<u64> {
+        self.versions.first().map(|v| v.version)
+    }
+
+    pub fn is_trimmed(&self, version: u64) -> bool {
+        self.get_oldest_version_number()
+            .is_some_and(|oldest| version < oldest)
+    }
+
     pub fn rollback(&mut self, version: u64) -> Option<Fragment> {
         if let Some(index) = self.versions.iter().position(|v| v.version == version) {
             let rollback_version = self.versions[index].clone();
             self.versions.truncate(index + 1);
+            self.tags.retain(|_, tagged| *tagged <= version);
             Some(rollback_version.fragment)
         } else {
             None
         }
     }
 
+    pub fn tag_version(&mut self, version: u64, tag: String) -> bool {
+        if self.get_version(version).is_none() {
+            return false;
+        }
+        self.tags.insert(tag, version);
+        true
+    }
+
+    pub fn get_tag(&self, tag: &str) -> Option<u64> {
+        self.tags.get(tag).copied()
+    }
+
+    pub fn get_tags(&self) -> &HashMap<String, u64> {
+        &self.tags
+    }
+
     pub fn get_history(&self) -> Vec<&Version> {
         self.versions.iter().collect()
     }
@@ -109,6 +300,27 @@ impl VersionControl {
         }
     }
 
+    // Replaces the whole history with the latest fragment renumbered as version 1;
+    // only tags on the latest version survive.
+    pub fn compact(&mut self) {
+        if let Some(latest) = self.versions.pop() {
+            self.tags.retain(|_, tagged| *tagged == latest.version);
+            let baseline = Version::new(latest.fragment);
+            for tagged in self.tags.values_mut() {
+                *tagged = baseline.version;
+            }
+            self.versions = vec![baseline];
+        }
+    }
+
+    pub fn compact_keep_last(&mut self, n: usize) {
+        let excess = self.versions.len().saturating_sub(n.max(1));
+        self.versions.drain(..excess);
+        if let Some(oldest) = self.get_oldest_version_number() {
+            self.tags.retain(|_, tagged| *tagged >= oldest);
+        }
+    }
+
     pub fn set_max_versions(&mut self, max_versions: Option<usize>) {
         self.max_versions = max_versions;
         if let Some(max) = max_versions {
@@ -122,3 +334,109 @@ impl VersionControl {
         self.max_versions
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verify_chain() {
+        let mut version_control = VersionControl::new(None);
+        for i in 0..4u8 {
+            let mut fragment = Fragment::new(CompressionAlgorithm::Zstd, 3, None);
+            fragment.insert(vec![i; 16], Sha256::new(&[i])).unwrap();
+            version_control.add_version(fragment);
+        }
+        assert!(version_control.verify_chain().is_ok());
+
+        version_control.versions[1]
+            .fragment
+            .insert(b"forged".to_vec(), Sha256::new(b"forged"))
+            .unwrap();
+        assert!(matches!(
+            version_control.verify_chain(),
+            Err(VersionError::ChainBroken { version: 2 })
+        ));
+    }
+
+    #[test]
+    fn test_versions_between() {
+        let mut version_control = VersionControl::new(None);
+        for _ in 0..4 {
+            version_control.add_version(Fragment::new(CompressionAlgorithm::Zstd, 3, None));
+        }
+        for (version, secs) in version_control
+            .versions
+            .iter_mut()
+            .zip([100, 200, 300, 400, 500])
+        {
+            version.creation_date = secs;
+        }
+
+        let at = |secs| UNIX_EPOCH + Duration::from_secs(secs);
+        let found: Vec<u64> = version_control
+            .versions_between(at(200), at(400))
+            .iter()
+            .map(|info| info.version)
+            .collect();
+        assert_eq!(found, vec![1, 2, 3]);
+        assert!(version_control.versions_between(at(10), at(50)).is_empty());
+        assert_eq!(
+            version_control.versions_between(at(450), at(900))[0].creation_date,
+            at(500)
+        );
+    }
+
+    #[test]
+    fn test_diff() {
+        let kept = Sha256::new(b"kept");
+        let changed = Sha256::new(b"changed");
+        let dropped = Sha256::new(b"dropped");
+        let added = Sha256::new(b"added");
+
+        let mut version_control = VersionControl::new(None);
+        let mut fragment = Fragment::new(CompressionAlgorithm::Zstd, 3, None);
+        fragment.insert(b"same".to_vec(), kept).unwrap();
+        fragment.insert(b"before".to_vec(), changed).unwrap();
+        fragment.insert(b"gone".to_vec(), dropped).unwrap();
+        version_control.add_version(fragment.clone());
+
+        fragment.insert(b"after".to_vec(), changed).unwrap();
+        fragment.remove(&dropped);
+        fragment.insert(b"new".to_vec(), added).unwrap();
+        version_control.add_version(fragment);
+
+        let diff = version_control.diff(1, 2).unwrap().unwrap();
+        assert_eq!(diff.added, vec![added]);
+        assert_eq!(diff.removed, vec![dropped]);
+        assert_eq!(diff.modified, vec![changed]);
+        assert!(version_control.diff(2, 2).unwrap().unwrap().is_empty());
+        assert!(version_control.diff(1, 42).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_keys_only_in() {
+        let shared = Sha256::new(b"shared");
+        let dropped = Sha256::new(b"dropped");
+        let added = Sha256::new(b"added");
+
+        let mut version_control = VersionControl::new(None);
+        let mut fragment = Fragment::new(CompressionAlgorithm::Zstd, 3, None);
+        fragment.insert(b"shared".to_vec(), shared).unwrap();
+        fragment.insert(b"dropped".to_vec(), dropped).unwrap();
+        version_control.add_version(fragment.clone());
+
+        fragment.remove(&dropped);
+        fragment.insert(b"changed".to_vec(), shared).unwrap();
+        fragment.insert(b"added".to_vec(), added).unwrap();
+        version_control.add_version(fragment);
+
+        assert_eq!(version_control.keys_only_in(1, 2).unwrap(), vec![dropped]);
+        assert_eq!(version_control.keys_only_in(2, 1).unwrap(), vec![added]);
+        assert!(version_control.keys_only_in(2, 2).unwrap().is_empty());
+        assert!(matches!(
+            version_control.keys_only_in(1, 42),
+            Err(VersionError::NotFound(42))
+        ));
+    }
+}
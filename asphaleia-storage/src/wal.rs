@@ -0,0 +1,166 @@
+use asphaleia_crypto::hash::Sha256;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{create_dir_all, File, OpenOptions},
+    io::{ErrorKind, Read, Write},
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+use thiserror::Error;
+
+const WAL_FILE_NAME: &str = "wal.log";
+
+#[derive(Error, Debug)]
+pub enum WalError {
+    #[error("IO error: {0}")]
+    IoError(#[from] std::io::Error),
+    #[error("Serialization error: {0}")]
+    SerializationError(#[from] bincode::Error),
+    #[error("Invalid record inside a transaction")]
+    InvalidRecord,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum WalRecord {
+    Insert {
+        key: Sha256,
+        value: Vec<u8>,
+        expires_at: Option<SystemTime>,
+    },
+    Remove {
+        key: Sha256,
+    },
+    Transaction {
+        ops: Vec<WalRecord>,
+    },
+    PurgeExpired,
+    CreateVersion,
+    Rollback {
+        version: u64,
+    },
+    Tag {
+        version: u64,
+        tag: String,
+    },
+    Recompress {
+        dict: Vec<u8>,
+    },
+    Compact {
+        keep_last: Option<usize>,
+    },
+}
+
+pub struct WriteAheadLog {
+    dir: PathBuf,
+    file: File,
+}
+
+impl WriteAheadLog {
+    // A torn tail left by a crash is cut off first, so new frames follow the last complete one.
+    pub fn open(dir: &str) -> Result<Self, WalError> {
+        let dir = PathBuf::from(dir);
+        create_dir_all(&dir)?;
+        let mut file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(dir.join(WAL_FILE_NAME))?;
+        if file.metadata()?.is_file() {
+            let mut data = Vec::new();
+            file.read_to_end(&mut data)?;
+            let complete = frames(&data).map(|(end, _)| end).last().unwrap_or(0);
+            if complete < data.len() {
+                file.set_len(complete as u64)?;
+                file.sync_data()?;
+            }
+        }
+        Ok(Self { dir, file })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn append(&mut self, record: &WalRecord) -> Result<(), WalError> {
+        let payload = bincode::serialize(record)?;
+        let mut frame = Vec::with_capacity(payload.len() + 4);
+        frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        frame.extend_from_slice(&payload);
+        self.file.write_all(&frame)?;
+        self.file.sync_data()?;
+        Ok(())
+    }
+
+    pub fn truncate(&mut self) -> Result<(), WalError> {
+        self.file.set_len(0)?;
+        self.file.sync_data()?;
+        Ok(())
+    }
+
+    // A frame cut short by a crash mid-append is dropped rather than treated as corruption.
+    pub fn read_records(dir: &str) -> Result<Vec<WalRecord>, WalError> {
+        let mut data = Vec::new();
+        match File::open(Path::new(dir).join(WAL_FILE_NAME)) {
+            Ok(mut file) => {
+                file.read_to_end(&mut data)?;
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        }
+
+        frames(&data)
+            .map(|(_, payload)| Ok(bincode::deserialize(payload)?))
+            .collect()
+    }
+}
+
+// Yields each complete frame's payload with the offset just past it.
+fn frames(data: &[u8]) -> impl Iterator<Item = (usize, &[u8])> {
+    let mut offset = 0;
+    std::iter::from_fn(move || {
+        let len = u32::from_be_bytes(data.get(offset..offset + 4)?.try_into().unwrap()) as usize;
+        let payload = data.get(offset + 4..offset + 4 + len)?;
+        offset += 4 + len;
+        Some((offset, payload))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_wal_roundtrip_and_torn_tail() -> Result<(), WalError> {
+        let dir = tempdir()?;
+        let path = dir.path().to_str().unwrap();
+        let records = vec![
+            WalRecord::Insert {
+                key: Sha256::new(b"k"),
+                value: b"v".to_vec(),
+                expires_at: None,
+            },
+            WalRecord::Remove {
+                key: Sha256::new(b"k"),
+            },
+        ];
+
+        let mut wal = WriteAheadLog::open(path)?;
+        for record in &records {
+            wal.append(record)?;
+        }
+        wal.file.write_all(&[0, 0, 0, 42, 1, 2])?;
+        assert_eq!(WriteAheadLog::read_records(path)?, records);
+
+        drop(wal);
+        let mut wal = WriteAheadLog::open(path)?;
+        wal.append(&WalRecord::PurgeExpired)?;
+        let mut expected = records.clone();
+        expected.push(WalRecord::PurgeExpired);
+        assert_eq!(WriteAheadLog::read_records(path)?, expected);
+
+        wal.truncate()?;
+        assert!(WriteAheadLog::read_records(path)?.is_empty());
+        Ok(())
+    }
+}